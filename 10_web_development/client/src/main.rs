@@ -1,14 +1,58 @@
 use anyhow::{Context, Result};
-use shared::{deserialize_message, serialize_message, MessageType};
+use chrono::{DateTime, Utc};
+use futures::{SinkExt, StreamExt};
+use shared::{
+    parse_history_time, received_image_name, sha256_hex, Capability, Compression, Envelope,
+    EnvelopeCodec, Format, Heartbeat, MessageType, Password, PresenceEvent, Status,
+    MAX_HISTORY_PAGE, PROTOCOL_VERSION,
+};
 use std::env;
 use std::sync::Arc;
+use tls::{Connector, ReadStream, WriteStream};
 use tokio::fs::File;
-use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, BufReader};
 use tokio::sync::{mpsc, Mutex};
 use tokio::task;
-use tracing::{error, info};
-use tracing_subscriber;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{error, info, warn};
+use transfer::{upload_file, Downloads, PendingOffsets};
+use uuid::Uuid;
+
+mod tls;
+mod transfer;
+
+/// The read half of the connection to the server, decoded into envelopes.
+type ServerReader = FramedRead<ReadStream, EnvelopeCodec>;
+
+/// The write half of the connection to the server, encoding envelopes.
+type ServerSink = FramedWrite<WriteStream, EnvelopeCodec>;
+
+/// The write half of the connection to the server, shared by the tasks that
+/// send messages. It is replaced when the client reconnects.
+type ServerWriter = Arc<Mutex<ServerSink>>;
+
+/// The session token issued by the server after login, if any.
+type Session = Arc<Mutex<Option<String>>>;
+
+/// The time of the oldest message history shown, which `.history` pages
+/// back from.
+type HistoryCursor = Arc<Mutex<Option<DateTime<Utc>>>>;
+
+/// Number of messages `.history` fetches when no number is given.
+const DEFAULT_HISTORY_PAGE: u32 = 20;
+
+/// Delay before the first attempt to reconnect to the server.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound of the delay between attempts to reconnect.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Attempts to reconnect before the client gives up.
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+
+/// Time a reconnect attempt, including the handshake, may take.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Main function    
 ///
@@ -47,54 +91,149 @@ async fn main() {
 ///
 /// * `addresess` - The server address to connect to.
 async fn start_client(address: &str) -> Result<()> {
-    let stream = TcpStream::connect(address)
-        .await
-        .context("Failed to connect to server")?;
-    info!("Connected to server at {}", address);
+    let connector = Connector::from_env(address)?;
+    let (reader, writer, capabilities) = connect(&connector).await?;
+    let writer = Arc::new(Mutex::new(writer));
+    let heartbeat = if capabilities.contains(&Capability::Heartbeats) {
+        let heartbeat = heartbeat_settings()?;
+        task::spawn(send_heartbeats(Arc::clone(&writer), heartbeat.interval));
+        Some(heartbeat)
+    } else {
+        None
+    };
     info!(
         "For login use: \n 
-    .login <user> \n 
+    .login <user> <password> \n 
     For registration use: \n 
-    .register <user> \n
+    .register <user> <password> \n
+    To chat in a room use: \n
+    .join <room>, .leave [room] and .rooms \n
+    To message a single user use: \n
+    .msg <user> <text> \n
+    To see who is online and set your status use: \n
+    .who and .status <online|away|dnd> \n
+    To page back through earlier messages use: \n
+    .history [n | since <time>] \n
     To exit the client use: \n
     .quit"
     );
 
-    let (reader, writer) = stream.into_split();
-    let reader = Arc::new(Mutex::new(reader));
-    let writer = Arc::new(Mutex::new(writer));
-
-    let (tx, mut rx) = mpsc::channel::<MessageType>(100);
+    let (tx, mut rx) = mpsc::channel::<Envelope>(100);
     let (quit_tx, mut quit_rx) = mpsc::channel::<()>(1);
+    let pending_offsets = PendingOffsets::default();
+    let history_cursor = HistoryCursor::default();
 
     // Task for handling server responses
-    let reader_clone = Arc::clone(&reader);
+    let writer_clone = Arc::clone(&writer);
     let tx_clone = tx.clone();
     let quit_tx_clone = quit_tx.clone();
     let server_response_handle = task::spawn(async move {
-        if let Err(e) = handle_server_response(reader_clone, tx_clone, quit_tx_clone).await {
+        let session = Session::default();
+        if let Err(e) = handle_server_response(
+            &connector,
+            reader,
+            writer_clone,
+            tx_clone,
+            quit_tx_clone,
+            session,
+            heartbeat,
+        )
+        .await
+        {
             error!("Error handling server response: {}", e);
         }
     });
 
     // Task for handling user input
     let writer_clone = Arc::clone(&writer);
+    let pending_offsets_clone = Arc::clone(&pending_offsets);
+    let history_cursor_clone = Arc::clone(&history_cursor);
     let user_input_handle = task::spawn(async move {
-        if let Err(e) = handle_user_input(writer_clone, tx).await {
+        if let Err(e) = handle_user_input(
+            writer_clone,
+            tx,
+            capabilities,
+            pending_offsets_clone,
+            history_cursor_clone,
+        )
+        .await
+        {
             error!("Error handling user input: {}", e);
         }
     });
 
     // Main task to handle incoming messages
     let incoming_handle = task::spawn(async move {
-        while let Some(message) = rx.recv().await {
-            match message {
-                MessageType::Error(err) => {
-                    error!("Error from server: {}", err);
+        let mut downloads = Downloads::new();
+        while let Some(envelope) = rx.recv().await {
+            // Messages stamped with a sender were relayed from other users
+            if let Some(sender) = envelope.sender.clone() {
+                if let Err(e) = handle_broadcast(envelope, &mut downloads).await {
+                    error!("Failed to handle message from {}: {}", sender, e);
                 }
+                continue;
+            }
+            match envelope.message {
+                MessageType::Error(err) => match envelope.correlation_id {
+                    Some(id) => error!("Error from server (in reply to message {}): {}", id, err),
+                    None => error!("Error from server: {}", err),
+                },
                 MessageType::Text(text) => {
                     info!("Server response: {}", text);
                 }
+                MessageType::Rooms(rooms) if rooms.is_empty() => {
+                    info!("There are no rooms yet, create one with .join <room>");
+                }
+                MessageType::Rooms(rooms) => {
+                    info!("Rooms: {}", rooms.join(", "));
+                }
+                MessageType::Users(users) => {
+                    let users: Vec<String> = users
+                        .into_iter()
+                        .map(|user| match user.status {
+                            Status::Online => user.username,
+                            status => format!("{} ({})", user.username, status),
+                        })
+                        .collect();
+                    info!("Online: {}", users.join(", "));
+                }
+                MessageType::Presence { username, event } => match event {
+                    PresenceEvent::LoggedIn => info!("{} is online", username),
+                    PresenceEvent::LoggedOut => info!("{} went offline", username),
+                    PresenceEvent::TimedOut => info!("{} lost their connection", username),
+                    PresenceEvent::StatusChanged(status) => {
+                        info!("{} is now {}", username, status)
+                    }
+                },
+                MessageType::History(messages) if messages.is_empty() => {
+                    info!("No earlier messages");
+                }
+                MessageType::History(messages) => {
+                    if let Some(oldest) = messages.first().and_then(|message| message.timestamp) {
+                        let mut cursor = history_cursor.lock().await;
+                        if cursor.is_none_or(|cursor| oldest < cursor) {
+                            *cursor = Some(oldest);
+                        }
+                    }
+                    info!("--- {} earlier messages ---", messages.len());
+                    for envelope in messages {
+                        let result = match envelope.message {
+                            MessageType::Image(..) => show_thumbnail(envelope).await,
+                            _ => handle_broadcast(envelope, &mut downloads).await,
+                        };
+                        if let Err(e) = result {
+                            error!("Failed to show message from history: {}", e);
+                        }
+                    }
+                }
+                MessageType::FileOffset {
+                    transfer_id,
+                    offset,
+                } => {
+                    if let Some(offset_tx) = pending_offsets.lock().await.remove(&transfer_id) {
+                        let _ = offset_tx.send(offset);
+                    }
+                }
                 MessageType::Quit => {
                     info!("Server has closed the connection.");
                     break;
@@ -119,27 +258,270 @@ async fn start_client(address: &str) -> Result<()> {
     Ok(())
 }
 
+/// Connects to the server and performs the handshake
+///
+/// # Arguments
+///
+/// * `connector` - Opens the connection to the server.
+///
+/// # Returns
+///
+/// Both halves of the connection and the capabilities negotiated for it.
+async fn connect(connector: &Connector) -> Result<(ServerReader, ServerSink, Vec<Capability>)> {
+    let (reader, writer) = connector.connect().await?;
+    let mut reader = FramedRead::new(reader, EnvelopeCodec::default());
+    let mut writer = FramedWrite::new(writer, EnvelopeCodec::default());
+    let capabilities = handshake(&mut reader, &mut writer).await?;
+    Ok((reader, writer, capabilities))
+}
+
+/// Reconnects to the server after the connection dropped
+///
+/// Attempts are spaced with exponential backoff, starting at
+/// `INITIAL_RECONNECT_DELAY` and doubling up to `MAX_RECONNECT_DELAY`. The
+/// new write half replaces the old one in `writer`, and if the client holds
+/// a session token, it resumes its session with `MessageType::Resume`.
+///
+/// # Arguments
+///
+/// * `connector` - Opens the connection to the server.
+/// * `writer` - The write half of the dropped connection, locked so that no
+///   message is sent until the client has reconnected.
+/// * `token` - The session token issued by the server, if any.
+///
+/// # Returns
+///
+/// The read half of the new connection and the ID of the resume message, if
+/// one was sent.
+async fn reconnect(
+    connector: &Connector,
+    writer: &mut ServerSink,
+    token: Option<String>,
+) -> Result<(ServerReader, Option<Uuid>)> {
+    let mut delay = INITIAL_RECONNECT_DELAY;
+    for attempt in 1..=MAX_RECONNECT_ATTEMPTS {
+        info!(
+            "Reconnecting in {} seconds (attempt {} of {})",
+            delay.as_secs(),
+            attempt,
+            MAX_RECONNECT_ATTEMPTS
+        );
+        sleep(delay).await;
+        let result = timeout(RECONNECT_TIMEOUT, connect(connector))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Server did not answer in time")));
+        match result {
+            Ok((reader, new_writer, _)) => {
+                *writer = new_writer;
+                let resume_id = match token {
+                    Some(token) => {
+                        let envelope = Envelope::new(MessageType::Resume(token));
+                        let id = envelope.id;
+                        writer
+                            .send(&envelope)
+                            .await
+                            .context("Failed to resume session")?;
+                        Some(id)
+                    }
+                    None => {
+                        info!("Reconnected, please log in again");
+                        None
+                    }
+                };
+                return Ok((reader, resume_id));
+            }
+            Err(e) => warn!("Failed to reconnect: {}", e),
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+    Err(anyhow::anyhow!(
+        "Could not reconnect to the server after {} attempts",
+        MAX_RECONNECT_ATTEMPTS
+    ))
+}
+
+/// Performs the protocol handshake with the server
+///
+/// This function announces the protocol version, capabilities, wire formats
+/// and compression algorithms of the client and waits for the server to
+/// acknowledge them. Both halves of the connection then switch to the wire
+/// format and compression the server chose.
+///
+/// # Arguments
+///
+/// * `reader` - The reader half of the TcpStream.
+/// * `writer` - The writer half of the TcpStream.
+///
+/// # Returns
+///
+/// The capabilities negotiated for the connection.
+async fn handshake(reader: &mut ServerReader, writer: &mut ServerSink) -> Result<Vec<Capability>> {
+    let hello = MessageType::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_name: format!("client {}", env!("CARGO_PKG_VERSION")),
+        capabilities: Capability::ALL.to_vec(),
+        formats: preferred_formats()?,
+        compression: preferred_compression()?,
+    };
+    writer
+        .send(&Envelope::new(hello))
+        .await
+        .context("Failed to send Hello")?;
+
+    match read_message(reader).await?.message {
+        MessageType::HelloAck {
+            protocol_version,
+            capabilities,
+            format,
+            compression,
+        } => {
+            info!(
+                "Server speaks protocol version {} with capabilities {:?} in format {} with compression {:?}",
+                protocol_version, capabilities, format, compression
+            );
+            reader.decoder_mut().set_format(format);
+            reader.decoder_mut().set_compression(compression);
+            writer.encoder_mut().set_format(format);
+            writer.encoder_mut().set_compression(compression);
+            Ok(capabilities)
+        }
+        MessageType::Error(err) => Err(anyhow::anyhow!("Server rejected handshake: {}", err)),
+        _ => Err(anyhow::anyhow!("Unexpected handshake response from server")),
+    }
+}
+
+/// Returns the wire formats to offer the server, in order of preference
+///
+/// The format named by the `WIRE_FORMAT` environment variable (`cbor`,
+/// `msgpack`, `json` or `bincode`) is offered first, so e.g. `json` can be
+/// requested to inspect the traffic by hand.
+fn preferred_formats() -> Result<Vec<Format>> {
+    let mut formats = Format::ALL.to_vec();
+    if let Ok(name) = env::var("WIRE_FORMAT") {
+        let preferred: Format = name.parse().map_err(|e: String| anyhow::anyhow!(e))?;
+        formats.retain(|format| *format != preferred);
+        formats.insert(0, preferred);
+    }
+    Ok(formats)
+}
+
+/// Returns the compression algorithms to offer the server, in order of
+/// preference
+///
+/// The `COMPRESSION` environment variable selects a single algorithm
+/// (`zstd` or `deflate`), or turns compression off with `none`.
+fn preferred_compression() -> Result<Vec<Compression>> {
+    match env::var("COMPRESSION") {
+        Ok(name) if name.eq_ignore_ascii_case("none") => Ok(Vec::new()),
+        Ok(name) => {
+            let preferred: Compression = name.parse().map_err(|e: String| anyhow::anyhow!(e))?;
+            Ok(vec![preferred])
+        }
+        Err(_) => Ok(Compression::ALL.to_vec()),
+    }
+}
+
+/// Returns the heartbeat settings of the client
+///
+/// `HEARTBEAT_INTERVAL` sets the seconds between two pings to the server
+/// and `HEARTBEAT_MISSES` how many heartbeats the server may miss before
+/// the client considers the connection dead and reconnects.
+fn heartbeat_settings() -> Result<Heartbeat> {
+    let mut heartbeat = Heartbeat::default();
+    if let Ok(interval) = env::var("HEARTBEAT_INTERVAL") {
+        let interval: u64 = interval
+            .parse()
+            .context("HEARTBEAT_INTERVAL must be a number of seconds")?;
+        heartbeat.interval = Duration::from_secs(interval);
+    }
+    if let Ok(max_missed) = env::var("HEARTBEAT_MISSES") {
+        heartbeat.max_missed = max_missed
+            .parse()
+            .context("HEARTBEAT_MISSES must be a number of heartbeats")?;
+    }
+    if heartbeat.interval.is_zero() || heartbeat.max_missed == 0 {
+        return Err(anyhow::anyhow!(
+            "HEARTBEAT_INTERVAL and HEARTBEAT_MISSES must be at least 1"
+        ));
+    }
+    Ok(heartbeat)
+}
+
+/// Pings the server at the heartbeat interval
+///
+/// The pings make the server answer even when nothing else happens, so a
+/// silent connection means the server is gone. They keep going across
+/// reconnects.
+///
+/// # Arguments
+///
+/// * `writer` - A shared reference to the writer half of the TcpStream.
+/// * `interval` - The time between two pings.
+async fn send_heartbeats(writer: ServerWriter, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        // Not `send_message`, which would log every ping
+        let ping = Envelope::new(MessageType::Ping);
+        if let Err(e) = writer.lock().await.send(&ping).await {
+            warn!("Failed to ping server: {}", e);
+        }
+    }
+}
+
 /// Handles user input
 ///
 /// This function reads user input from command line, process commands
 /// for sending text, files, images, and quit messages to the server.
+/// After `.join`, text is posted to the joined room until the client
+/// leaves it.
 ///
 /// # Arguments
 ///
 /// * `writer` - A shared reference to the writer half of the TcpStream.
+/// * `tx` - A channel sender for sending messages to the main task.
+/// * `capabilities` - The capabilities negotiated with the server.
+/// * `pending_offsets` - The chunked uploads waiting for their offset.
+/// * `history_cursor` - The time `.history` pages back from.
 async fn handle_user_input(
-    writer: Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
-    tx: mpsc::Sender<MessageType>,
+    writer: ServerWriter,
+    tx: mpsc::Sender<Envelope>,
+    capabilities: Vec<Capability>,
+    pending_offsets: PendingOffsets,
+    history_cursor: HistoryCursor,
 ) -> Result<()> {
     let stdin = io::stdin();
     let mut stdin_reader = BufReader::new(stdin).lines();
 
     // List of valid commands
-    let valid_commands = [".file", ".image", ".quit", ".login", ".register"];
+    let valid_commands = [
+        ".file",
+        ".image",
+        ".quit",
+        ".login",
+        ".register",
+        ".join",
+        ".leave",
+        ".rooms",
+        ".msg",
+        ".who",
+        ".status",
+        ".history",
+    ];
+
+    // The room text messages are posted to, `None` for everybody
+    let mut current_room: Option<String> = None;
 
     while let Some(line) = stdin_reader.next_line().await? {
         let input = line.trim().to_string();
-        info!("Read input: {}", input);
+        if input.starts_with(".login") || input.starts_with(".register") {
+            // Never log the password
+            info!(
+                "Read input: {}",
+                input.split_whitespace().next().unwrap_or("")
+            );
+        } else {
+            info!("Read input: {}", input);
+        }
 
         // Check if the input is command
         if input.starts_with('.') {
@@ -147,17 +529,33 @@ async fn handle_user_input(
 
             // Check if command is valid
             if !valid_commands.contains(&command) {
-                eprintln!("Invalid command. Valid commands are: .file <path>, .image <path>, .quit, .login <username>, .register <username>");
+                eprintln!("Invalid command. Valid commands are: .file <path>, .image <path>, .quit, .login <username> <password>, .register <username> <password>, .join <room>, .leave [room], .rooms, .msg <user> <text>, .who, .status <online|away|dnd>, .history [n | since <time>]");
                 continue;
             }
 
             match command {
                 ".file" => {
+                    if !capabilities.contains(&Capability::Files) {
+                        eprintln!("Error: the server does not accept files.");
+                        continue;
+                    }
                     if input.len() <= 6 {
                         eprintln!("Error: .file command requires a file path.");
                         continue;
                     }
                     let path = &input[6..].trim();
+                    if capabilities.contains(&Capability::ChunkedFiles) {
+                        // Stream the file in the background so chatting can go on
+                        let writer = Arc::clone(&writer);
+                        let pending_offsets = Arc::clone(&pending_offsets);
+                        let path = std::path::PathBuf::from(path);
+                        task::spawn(async move {
+                            if let Err(e) = upload_file(writer, pending_offsets, path).await {
+                                error!("Failed to send file: {}", e);
+                            }
+                        });
+                        continue;
+                    }
                     match File::open(&path).await {
                         Ok(mut file) => {
                             let mut buffer = Vec::new();
@@ -170,7 +568,8 @@ async fn handle_user_input(
                                 .to_str()
                                 .unwrap()
                                 .to_string();
-                            let message = MessageType::File(filename.clone(), buffer);
+                            let sha256 = sha256_hex(&buffer);
+                            let message = MessageType::File(filename.clone(), buffer, sha256);
                             send_message(&writer, &message).await?;
                             info!("Sent file: {}", filename);
                         }
@@ -180,6 +579,10 @@ async fn handle_user_input(
                     }
                 }
                 ".image" => {
+                    if !capabilities.contains(&Capability::Images) {
+                        eprintln!("Error: the server does not accept images.");
+                        continue;
+                    }
                     if input.len() <= 7 {
                         eprintln!("Error: .image command requires a file path.");
                         continue;
@@ -191,7 +594,8 @@ async fn handle_user_input(
                             file.read_to_end(&mut buffer)
                                 .await
                                 .context("Failed to read image")?;
-                            let message = MessageType::Image(buffer);
+                            let sha256 = sha256_hex(&buffer);
+                            let message = MessageType::Image(buffer, sha256);
                             send_message(&writer, &message).await?;
                             info!("Sent image from path: {}", path);
                         }
@@ -204,33 +608,148 @@ async fn handle_user_input(
                     let message = MessageType::Quit;
                     send_message(&writer, &message).await?;
                     info!("Sent quit message");
-                    if let Err(e) = tx.send(message).await {
+                    if let Err(e) = tx.send(Envelope::new(message)).await {
                         error!("Failed to send quit message to main loop: {}", e)
                     }
                     break;
                 }
                 ".login" => {
-                    if input.len() <= 7 {
-                        eprintln!("Error: .login command requires a username.");
+                    let Some((username, password)) = parse_credentials(&input[6..]) else {
+                        eprintln!("Error: .login command requires a username and a password.");
                         continue;
-                    }
-                    let username = &input[7..].trim().to_string();
-                    let message = MessageType::Login(username.clone());
+                    };
+                    let message = MessageType::Login {
+                        username: username.clone(),
+                        password,
+                    };
                     send_message(&writer, &message).await?;
                     info!("Sent login message for username: {}", username);
                 }
                 ".register" => {
-                    if input.len() <= 10 {
-                        eprintln!("Error: .register command requires a username.");
+                    let Some((username, password)) = parse_credentials(&input[9..]) else {
+                        eprintln!("Error: .register command requires a username and a password.");
                         continue;
-                    }
-                    let username = &input[10..].trim().to_string();
-                    let message = MessageType::Register(username.clone());
+                    };
+                    let message = MessageType::Register {
+                        username: username.clone(),
+                        password,
+                    };
                     send_message(&writer, &message).await?;
                     info!("Sent register message for username: {}", username);
                 }
+                ".join" | ".leave" | ".rooms" if !capabilities.contains(&Capability::Rooms) => {
+                    eprintln!("Error: the server does not support rooms.");
+                }
+                ".join" => {
+                    if input.len() <= 6 {
+                        eprintln!("Error: .join command requires a room name.");
+                        continue;
+                    }
+                    let room = input[6..].trim().to_string();
+                    send_message(&writer, &MessageType::Join(room.clone())).await?;
+                    info!("Sent join message for room: {}", room);
+                    current_room = Some(room);
+                }
+                ".leave" => {
+                    let room = match input[6..].trim() {
+                        "" => match current_room.clone() {
+                            Some(room) => room,
+                            None => {
+                                eprintln!("Error: .leave command requires a room name.");
+                                continue;
+                            }
+                        },
+                        room => room.to_string(),
+                    };
+                    send_message(&writer, &MessageType::Leave(room.clone())).await?;
+                    info!("Sent leave message for room: {}", room);
+                    if current_room.as_ref() == Some(&room) {
+                        current_room = None;
+                    }
+                }
+                ".rooms" => {
+                    send_message(&writer, &MessageType::ListRooms).await?;
+                }
+                ".msg" => {
+                    if !capabilities.contains(&Capability::DirectMessages) {
+                        eprintln!("Error: the server does not support direct messages.");
+                        continue;
+                    }
+                    let mut parts = input[4..].trim().splitn(2, char::is_whitespace);
+                    let (to, body) = match (parts.next(), parts.next()) {
+                        (Some(to), Some(body)) if !to.is_empty() => (to, body.trim()),
+                        _ => {
+                            eprintln!("Error: .msg command requires a username and a text.");
+                            continue;
+                        }
+                    };
+                    let message = MessageType::Direct {
+                        to: to.to_string(),
+                        body: body.to_string(),
+                    };
+                    send_message(&writer, &message).await?;
+                    info!("Sent direct message to {}: {}", to, body);
+                }
+                ".who" | ".status" if !capabilities.contains(&Capability::Presence) => {
+                    eprintln!("Error: the server does not support presence.");
+                }
+                ".who" => {
+                    send_message(&writer, &MessageType::Who).await?;
+                }
+                ".status" => {
+                    let status: Status = match input[7..].trim().parse() {
+                        Ok(status) => status,
+                        Err(e) => {
+                            eprintln!("Error: {}", e);
+                            continue;
+                        }
+                    };
+                    send_message(&writer, &MessageType::SetStatus(status)).await?;
+                }
+                ".history" => {
+                    if !capabilities.contains(&Capability::History) {
+                        eprintln!("Error: the server does not support message history.");
+                        continue;
+                    }
+                    let args = input[8..].trim();
+                    let message = if let Some(time) = args.strip_prefix("since ") {
+                        let Some(since) = parse_history_time(time) else {
+                            eprintln!("Error: use a time like 2024-05-17 14:30 (UTC).");
+                            continue;
+                        };
+                        MessageType::FetchHistory {
+                            before: None,
+                            since: Some(since),
+                            limit: MAX_HISTORY_PAGE,
+                        }
+                    } else {
+                        let limit = match args {
+                            "" => DEFAULT_HISTORY_PAGE,
+                            n => match n.parse::<u32>() {
+                                Ok(n) if n > 0 => n.min(MAX_HISTORY_PAGE),
+                                _ => {
+                                    eprintln!("Error: .history takes a number of messages or since <time>.");
+                                    continue;
+                                }
+                            },
+                        };
+                        MessageType::FetchHistory {
+                            before: *history_cursor.lock().await,
+                            since: None,
+                            limit,
+                        }
+                    };
+                    send_message(&writer, &message).await?;
+                }
                 _ => {}
             }
+        } else if let Some(room) = &current_room {
+            let message = MessageType::RoomMessage {
+                room: room.clone(),
+                body: input.to_string(),
+            };
+            send_message(&writer, &message).await?;
+            info!("Sent text message to room {}: {}", room, input);
         } else {
             let message = MessageType::Text(input.to_string());
             send_message(&writer, &message).await?;
@@ -241,49 +760,108 @@ async fn handle_user_input(
     Ok(())
 }
 
+/// Splits the arguments of `.login` and `.register` into a username and a
+/// password
+///
+/// The password is everything after the username, so it may contain
+/// spaces.
+///
+/// # Arguments
+///
+/// * `args` - The command line after the command name.
+fn parse_credentials(args: &str) -> Option<(String, Password)> {
+    let (username, password) = args.trim().split_once(char::is_whitespace)?;
+    let password = password.trim();
+    if password.is_empty() {
+        return None;
+    }
+    Some((username.to_string(), Password::new(password)))
+}
+
 /// Handles server responses
 /// This function reads responses from the server, deserializes the messages,
 /// and sends them to the main task through the provided channel.
 ///
+/// Session tokens are kept in `session` rather than passed on, and pings
+/// are answered right away. When the connection drops, or the server sends
+/// nothing for the heartbeat timeout, the client reconnects (see
+/// `reconnect`) and resumes its session, and the server replays the
+/// messages it missed. If the
+/// server refuses to resume the session, the token is dropped and the user
+/// has to log in again. Chunked transfers in progress are not resumed.
+///
 /// # Arguments
 ///
-/// * `reader` - A shared reference to the reader half of the TcpStream
+/// * `connector` - Opens the connection to the server when reconnecting.
+/// * `reader` - The reader half of the TcpStream
+/// * `writer` - A shared reference to the writer half of the TcpStream.
 /// * `tx` - A channel sender for sending messages to the main task.
+/// * `quit_tx` - A channel sender for signalling the other tasks to quit.
+/// * `session` - The session token issued by the server.
+/// * `heartbeat` - The heartbeat settings, if the server supports
+///   heartbeats.
 async fn handle_server_response(
-    reader: Arc<Mutex<tokio::net::tcp::OwnedReadHalf>>,
-    tx: mpsc::Sender<MessageType>,
+    connector: &Connector,
+    mut reader: ServerReader,
+    writer: ServerWriter,
+    tx: mpsc::Sender<Envelope>,
     quit_tx: mpsc::Sender<()>,
+    session: Session,
+    heartbeat: Option<Heartbeat>,
 ) -> Result<()> {
+    let mut resume_id = None;
     loop {
-        let mut len_bytes = [0u8; 4];
-        {
-            let mut reader = reader.lock().await;
-            if let Err(e) = reader.read_exact(&mut len_bytes).await {
-                error!("Failed to read response length: {}", e);
-                return Err(e.into());
+        let result = match heartbeat {
+            Some(heartbeat) => timeout(heartbeat.timeout(), read_message(&mut reader))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(anyhow::anyhow!(
+                        "No message from server for {} seconds",
+                        heartbeat.timeout().as_secs()
+                    ))
+                }),
+            None => read_message(&mut reader).await,
+        };
+        let envelope = match result {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("Lost connection to server: {}", e);
+                let token = session.lock().await.clone();
+                let mut writer = writer.lock().await;
+                (reader, resume_id) = reconnect(connector, &mut writer, token).await?;
+                continue;
             }
-        }
-        let len = u32::from_be_bytes(len_bytes) as usize;
-
-        let mut buffer = vec![0u8; len];
-        {
-            let mut reader = reader.lock().await;
-            if let Err(e) = reader.read_exact(&mut buffer).await {
-                error!("Failed to read response: {}", e);
-                return Err(e.into());
+        };
+        //  info!("Received message from server: {:?}",envelope);
+        match &envelope.message {
+            MessageType::SessionToken(token) => {
+                *session.lock().await = Some(token.clone());
+                continue;
             }
+            MessageType::Ping => {
+                let pong = Envelope::reply_to(envelope.id, MessageType::Pong);
+                if let Err(e) = writer.lock().await.send(&pong).await {
+                    warn!("Failed to answer ping from server: {}", e);
+                }
+                continue;
+            }
+            MessageType::Pong => continue,
+            MessageType::Error(_)
+                if resume_id.is_some() && envelope.correlation_id == resume_id =>
+            {
+                *session.lock().await = None;
+                resume_id = None;
+            }
+            _ => {}
         }
-
-        let message = deserialize_message(&buffer).map_err(|e| anyhow::anyhow!(e))?;
-        //  info!("Received message from server: {:?}",message);
-        if let MessageType::Quit = message {
-            if let Err(e) = tx.send(message).await {
+        if let MessageType::Quit = envelope.message {
+            if let Err(e) = tx.send(envelope).await {
                 error!("Failed to send quit message to main loop: {}", e);
             }
             let _ = quit_tx.send(()).await;
             break;
         }
-        if let Err(e) = tx.send(message).await {
+        if let Err(e) = tx.send(envelope).await {
             error!("Failed to send message to main loop: {}", e);
             return Err(e.into());
         }
@@ -292,35 +870,191 @@ async fn handle_server_response(
     Ok(())
 }
 
+/// Reads a message from the server
+///
+/// This function reads a length-prefixed message from the server and
+/// deserializes it together with its envelope. Frames larger than the
+/// default `FrameLimits` are rejected, which ends the connection.
+///
+/// # Arguments
+///
+/// * `reader` - The reader half of the TcpStream.
+async fn read_message(reader: &mut ServerReader) -> Result<Envelope> {
+    match reader.next().await {
+        Some(Ok(envelope)) => Ok(envelope),
+        Some(Err(e)) => {
+            error!("Failed to read response: {}", e);
+            Err(e.into())
+        }
+        None => Err(anyhow::anyhow!("Server closed the connection")),
+    }
+}
+
+/// Saves the thumbnail an image is shown with in history
+///
+/// # Arguments
+///
+/// * `envelope` - The history entry carrying the thumbnail.
+async fn show_thumbnail(envelope: Envelope) -> Result<()> {
+    let sender = envelope.sender.as_deref().unwrap_or("unknown");
+    let time = envelope
+        .timestamp
+        .map(|timestamp| timestamp.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default();
+    let MessageType::Image(data, sha256) = envelope.message else {
+        return Ok(());
+    };
+    if sha256_hex(&data) != sha256 {
+        return Err(anyhow::anyhow!(
+            "Received thumbnail is corrupted, discarding it"
+        ));
+    }
+    let path = format!(
+        "received/thumbnails/{}",
+        received_image_name(sender, envelope.id, &data)
+    );
+    save_received(&path, &data).await?;
+    info!(
+        "[{}] {}: [image, thumbnail saved to {}]",
+        time, sender, path
+    );
+    Ok(())
+}
+
+/// Handles a message broadcast by another user
+///
+/// This function prints text messages and saves received images and files
+/// into the `received` directory.
+///
+/// # Arguments
+///
+/// * `envelope` - The broadcast message with the sender and timestamp
+///   stamped by the server.
+/// * `downloads` - The chunked file transfers being received.
+async fn handle_broadcast(envelope: Envelope, downloads: &mut Downloads) -> Result<()> {
+    let sender = envelope.sender.as_deref().unwrap_or("unknown");
+    let time = envelope
+        .timestamp
+        .map(|timestamp| timestamp.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default();
+    match envelope.message {
+        MessageType::Text(text) => {
+            info!("[{}] {}: {}", time, sender, text);
+        }
+        MessageType::RoomMessage { room, body } => {
+            info!("[{}] #{} {}: {}", time, room, sender, body);
+        }
+        MessageType::Direct { body, .. } => {
+            info!("[{}] {} (private): {}", time, sender, body);
+        }
+        MessageType::Image(data, sha256) => {
+            if sha256_hex(&data) != sha256 {
+                return Err(anyhow::anyhow!(
+                    "Received image is corrupted, discarding it"
+                ));
+            }
+            let path = format!(
+                "received/images/{}",
+                received_image_name(sender, envelope.id, &data)
+            );
+            save_received(&path, &data).await?;
+            info!("{} sent an image, saved to {}", sender, path);
+        }
+        MessageType::File(name, data, sha256) => {
+            // Never trust the directory part of a name chosen by another user
+            let name = std::path::Path::new(&name)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .context("Received file has an invalid name")?;
+            if sha256_hex(&data) != sha256 {
+                return Err(anyhow::anyhow!(
+                    "Received file '{}' is corrupted, discarding it",
+                    name
+                ));
+            }
+            let path = format!("received/files/{}", name);
+            save_received(&path, &data).await?;
+            info!("{} sent file '{}', saved to {}", sender, name, path);
+        }
+        MessageType::FileStart {
+            transfer_id,
+            name,
+            size,
+            sha256,
+        } => {
+            info!("{} is sending file '{}' ({} bytes)...", sender, name, size);
+            downloads.start(transfer_id, name, size, sha256).await?;
+        }
+        MessageType::FileChunk {
+            transfer_id,
+            offset,
+            data,
+        } => {
+            downloads.write_chunk(transfer_id, offset, &data).await?;
+        }
+        MessageType::FileFinish { transfer_id } => {
+            let path = downloads.finish(transfer_id).await?;
+            info!("{} sent a file, saved to {}", sender, path.display());
+        }
+        _ => {
+            info!("Received unexpected message from {}", sender);
+        }
+    }
+    Ok(())
+}
+
+/// Saves received data to the given path, creating parent directories.
+///
+/// # Arguments
+///
+/// * `path` - The path to save the data to.
+/// * `data` - The received data.
+async fn save_received(path: &str, data: &[u8]) -> Result<()> {
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .context("Failed to create directory for received data")?;
+    }
+    tokio::fs::write(path, data)
+        .await
+        .context("Failed to save received data")?;
+    Ok(())
+}
+
 /// Sends a message to the server
 ///
-/// This function serializes a message and sends it to the server through
-/// the provided writer.
+/// This function wraps a message in a new envelope, serializes it and sends
+/// it to the server through the provided writer.
 /// # Arguments
 ///
 /// * `writer` - A shared reference to the writer half of the TcpStream.
 /// * `message` - The message to be sent.
+///
+/// # Returns
+///
+/// The ID of the sent message, which the server refers to in its replies.
 async fn send_message(
-    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    writer: &ServerWriter,
     message: &MessageType,
-) -> Result<()> {
-    let serialized = serialize_message(message).map_err(|e| anyhow::anyhow!(e))?;
-
-    // Send the length of the serialized message (as 4-byte value).
-    let len = serialized.len() as u32;
-    {
-        let mut writer = writer.lock().await;
-        writer
-            .write_all(&len.to_be_bytes())
-            .await
-            .context("Failed to send message length")?;
-        // Send the serialized message.
-        writer
-            .write_all(&serialized)
-            .await
-            .context("Failed to send message")?;
-    }
+) -> Result<Uuid> {
+    let envelope = Envelope::new(message.clone());
+    writer
+        .lock()
+        .await
+        .send(&envelope)
+        .await
+        .context("Failed to send message")?;
 
-    info!("Sent message: {:?}", message); // T
-    Ok(())
+    match message {
+        // Do not flood the log with file contents
+        MessageType::FileChunk { offset, data, .. } => {
+            info!(
+                "Sent file chunk at offset {} ({} bytes)",
+                offset,
+                data.len()
+            )
+        }
+        _ => info!("Sent {} message {}", message.kind(), envelope.id),
+    }
+    Ok(envelope.id)
 }
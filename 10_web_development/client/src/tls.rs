@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use shared::sha256_hex;
+use std::env;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, WebPkiSupportedAlgorithms};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
+};
+use tokio_rustls::TlsConnector;
+use tracing::info;
+
+/// The read half of the connection to the server, plain TCP or TLS.
+pub type ReadStream = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The write half of the connection to the server, plain TCP or TLS.
+pub type WriteStream = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Opens connections to the server, over TLS if it is configured
+pub struct Connector {
+    address: String,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+}
+
+impl Connector {
+    /// Configures the connection to a server from the environment
+    ///
+    /// TLS is used when one of these variables is set:
+    ///
+    /// * `TLS_CA_FILE` - A PEM bundle of the certificate authorities to
+    ///   trust, e.g. the `cert.pem` written by `server gen-cert`.
+    /// * `TLS_FINGERPRINT` - The SHA-256 fingerprint of the server
+    ///   certificate in hex (colons allowed). Only that certificate is
+    ///   accepted, whoever issued it.
+    ///
+    /// `TLS_SERVER_NAME` overrides the name the certificate is checked
+    /// against, which defaults to the host part of `address`.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The server address to connect to.
+    pub fn from_env(address: &str) -> Result<Self> {
+        let config = match (env::var("TLS_CA_FILE"), env::var("TLS_FINGERPRINT")) {
+            (Ok(_), Ok(_)) => {
+                anyhow::bail!("Set either TLS_CA_FILE or TLS_FINGERPRINT, not both")
+            }
+            (Ok(path), Err(_)) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(&path)
+                    .with_context(|| format!("Failed to read certificates from {}", path))?
+                {
+                    roots
+                        .add(cert.with_context(|| format!("Invalid certificate in {}", path))?)
+                        .with_context(|| format!("Invalid certificate in {}", path))?;
+                }
+                Some(
+                    ClientConfig::builder()
+                        .with_root_certificates(roots)
+                        .with_no_client_auth(),
+                )
+            }
+            (Err(_), Ok(fingerprint)) => Some(
+                ClientConfig::builder()
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(PinnedCertificate::new(
+                        &fingerprint,
+                    )))
+                    .with_no_client_auth(),
+            ),
+            (Err(_), Err(_)) => None,
+        };
+
+        let tls = match config {
+            Some(config) => {
+                let server_name = match env::var("TLS_SERVER_NAME") {
+                    Ok(name) => name,
+                    Err(_) => host(address).to_string(),
+                };
+                let server_name =
+                    ServerName::try_from(server_name).context("Invalid TLS server name")?;
+                Some((TlsConnector::from(Arc::new(config)), server_name))
+            }
+            None => None,
+        };
+        Ok(Connector {
+            address: address.to_string(),
+            tls,
+        })
+    }
+
+    /// Connects to the server and splits the connection into its halves,
+    /// after performing the TLS handshake if TLS is configured.
+    pub async fn connect(&self) -> Result<(ReadStream, WriteStream)> {
+        let stream = TcpStream::connect(&self.address)
+            .await
+            .context("Failed to connect to server")?;
+        match &self.tls {
+            Some((connector, server_name)) => {
+                let stream = connector
+                    .connect(server_name.clone(), stream)
+                    .await
+                    .context("TLS handshake with server failed")?;
+                info!("Connected to server at {} with TLS", self.address);
+                let (reader, writer) = tokio::io::split(stream);
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            None => {
+                info!("Connected to server at {}", self.address);
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+        }
+    }
+}
+
+/// Returns the host part of a `host:port` address.
+fn host(address: &str) -> &str {
+    let host = address
+        .rsplit_once(':')
+        .map_or(address, |(host, _port)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Accepts exactly the server certificate with a pinned fingerprint
+///
+/// The certificate chain and name are not checked, which is what makes
+/// pinning work with self-signed certificates. Handshake signatures are
+/// still verified, so the server must hold the certificate's private key.
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: String,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedCertificate {
+    fn new(fingerprint: &str) -> Self {
+        PinnedCertificate {
+            fingerprint: fingerprint.replace(':', "").to_ascii_lowercase(),
+            algorithms: crypto::ring::default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if sha256_hex(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(Error::General(
+                "Server certificate does not match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
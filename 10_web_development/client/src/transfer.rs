@@ -0,0 +1,219 @@
+use anyhow::{Context, Result};
+use shared::{sha256_file, MessageType, FILE_CHUNK_SIZE};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{oneshot, Mutex};
+use tokio::task;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{send_message, ServerWriter};
+
+/// Uploads waiting for the server to tell them where to continue, keyed by
+/// transfer ID.
+pub type PendingOffsets = Arc<Mutex<HashMap<Uuid, oneshot::Sender<u64>>>>;
+
+/// Uploads a file to the server as a chunked transfer
+///
+/// The file is announced with its size and SHA-256 digest. The server
+/// answers with the offset to continue from, which is non-zero when an
+/// earlier upload of the same file was interrupted, and the file is then
+/// streamed from that offset without reading it into memory.
+///
+/// # Arguments
+///
+/// * `writer` - A shared reference to the writer half of the TcpStream.
+/// * `pending` - The uploads waiting for their offset.
+/// * `path` - The path of the file to upload.
+pub async fn upload_file(
+    writer: ServerWriter,
+    pending: PendingOffsets,
+    path: PathBuf,
+) -> Result<()> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("File has an invalid name")?
+        .to_string();
+    let size = fs::metadata(&path)
+        .await
+        .context("Failed to read file metadata")?
+        .len();
+    let hash_path = path.clone();
+    let sha256 = task::spawn_blocking(move || sha256_file(&hash_path)).await??;
+
+    let transfer_id = Uuid::new_v4();
+    let (offset_tx, offset_rx) = oneshot::channel();
+    pending.lock().await.insert(transfer_id, offset_tx);
+    let start = MessageType::FileStart {
+        transfer_id,
+        name: name.clone(),
+        size,
+        sha256,
+    };
+    send_message(&writer, &start).await?;
+
+    let mut offset = offset_rx
+        .await
+        .context("Connection closed before the server accepted the file")?;
+    if offset > 0 {
+        info!("Resuming upload of '{}' at offset {}", name, offset);
+    }
+
+    let mut file = File::open(&path).await.context("Failed to open file")?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut buffer = vec![0u8; FILE_CHUNK_SIZE];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .await
+            .context("Failed to read file")?;
+        if read == 0 {
+            break;
+        }
+        let chunk = MessageType::FileChunk {
+            transfer_id,
+            offset,
+            data: buffer[..read].to_vec(),
+        };
+        send_message(&writer, &chunk).await?;
+        offset += read as u64;
+    }
+
+    send_message(&writer, &MessageType::FileFinish { transfer_id }).await?;
+    info!("Sent file: {}", name);
+    Ok(())
+}
+
+/// A file being received from the server
+struct Download {
+    name: String,
+    size: u64,
+    sha256: String,
+    path: PathBuf,
+    file: File,
+    received: u64,
+}
+
+/// Files relayed by the server that are still being received
+///
+/// Chunks are written straight to a partial file in `received/files`,
+/// which is moved into place once its digest has been verified.
+pub struct Downloads {
+    downloads: HashMap<Uuid, Download>,
+}
+
+impl Downloads {
+    pub fn new() -> Self {
+        Downloads {
+            downloads: HashMap::new(),
+        }
+    }
+
+    /// Starts receiving a file
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - The ID of the transfer.
+    /// * `name` - The name of the file, as chosen by its sender.
+    /// * `size` - The size of the file in bytes.
+    /// * `sha256` - The hex-encoded SHA-256 digest of the file.
+    pub async fn start(
+        &mut self,
+        transfer_id: Uuid,
+        name: String,
+        size: u64,
+        sha256: String,
+    ) -> Result<()> {
+        // Never trust the directory part of a name chosen by another user
+        let name = Path::new(&name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("Received file has an invalid name")?
+            .to_string();
+        fs::create_dir_all("received/files/.partial")
+            .await
+            .context("Failed to create directory for received files")?;
+        let path = PathBuf::from(format!("received/files/.partial/{}", transfer_id));
+        let file = File::create(&path)
+            .await
+            .context("Failed to create partial file")?;
+        self.downloads.insert(
+            transfer_id,
+            Download {
+                name,
+                size,
+                sha256,
+                path,
+                file,
+                received: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Writes a received chunk
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - The ID of the transfer.
+    /// * `offset` - The offset of the chunk within the file.
+    /// * `data` - The content of the chunk.
+    pub async fn write_chunk(&mut self, transfer_id: Uuid, offset: u64, data: &[u8]) -> Result<()> {
+        let download = self
+            .downloads
+            .get_mut(&transfer_id)
+            .context("Chunk of unknown file transfer")?;
+        if offset != download.received || offset + data.len() as u64 > download.size {
+            return Err(anyhow::anyhow!(
+                "Unexpected chunk of file '{}'",
+                download.name
+            ));
+        }
+        download
+            .file
+            .write_all(data)
+            .await
+            .context("Failed to write received chunk")?;
+        download.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// Finishes receiving a file, verifying it before moving it into place
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - The ID of the transfer.
+    ///
+    /// # Returns
+    ///
+    /// The path the file was saved to.
+    pub async fn finish(&mut self, transfer_id: Uuid) -> Result<PathBuf> {
+        let mut download = self
+            .downloads
+            .remove(&transfer_id)
+            .context("End of unknown file transfer")?;
+        download.file.flush().await?;
+        drop(download.file);
+
+        let partial_path = download.path.clone();
+        let sha256 = task::spawn_blocking(move || sha256_file(&partial_path)).await??;
+        if download.received != download.size || sha256 != download.sha256 {
+            fs::remove_file(&download.path).await?;
+            return Err(anyhow::anyhow!(
+                "Received file '{}' is corrupted, discarding it",
+                download.name
+            ));
+        }
+
+        let path = PathBuf::from(format!("received/files/{}", download.name));
+        fs::rename(&download.path, &path)
+            .await
+            .context("Failed to save received file")?;
+        Ok(path)
+    }
+}
@@ -0,0 +1,67 @@
+-- The tables the SQLite backend created before migrations were added.
+-- UUIDs are stored as 16-byte blobs and timestamps as text.
+
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY,
+    username TEXT UNIQUE NOT NULL,
+    password_hash TEXT
+);
+
+CREATE TABLE IF NOT EXISTS rooms (
+    id INTEGER PRIMARY KEY,
+    name TEXT UNIQUE NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY,
+    message_id BLOB UNIQUE,
+    user_id INTEGER REFERENCES users(id),
+    room_id INTEGER REFERENCES rooms(id),
+    content TEXT NOT NULL,
+    timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS messages_room_id_timestamp_idx ON messages (room_id, timestamp);
+
+CREATE TABLE IF NOT EXISTS attachments (
+    id BLOB PRIMARY KEY,
+    message_id INTEGER NOT NULL REFERENCES messages(id),
+    uploader_id INTEGER NOT NULL REFERENCES users(id),
+    original_name TEXT,
+    mime_type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    sha256 TEXT NOT NULL,
+    storage_path TEXT NOT NULL,
+    width INTEGER,
+    height INTEGER,
+    phash INTEGER,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS attachments_message_id_idx ON attachments (message_id);
+CREATE INDEX IF NOT EXISTS attachments_image_size_idx ON attachments (width, height)
+    WHERE phash IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS thumbnails (
+    attachment_id BLOB NOT NULL REFERENCES attachments(id) ON DELETE CASCADE,
+    max_size INTEGER NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    storage_path TEXT NOT NULL,
+    PRIMARY KEY (attachment_id, max_size)
+);
+
+CREATE TABLE IF NOT EXISTS direct_messages (
+    id INTEGER PRIMARY KEY,
+    message_id BLOB UNIQUE NOT NULL,
+    sender_id INTEGER NOT NULL REFERENCES users(id),
+    recipient_id INTEGER NOT NULL REFERENCES users(id),
+    content TEXT NOT NULL,
+    timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    delivered BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX IF NOT EXISTS direct_messages_undelivered_idx ON direct_messages (recipient_id)
+    WHERE NOT delivered;
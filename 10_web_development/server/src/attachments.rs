@@ -0,0 +1,113 @@
+use anyhow::Result;
+use shared::sha256_hex;
+use uuid::Uuid;
+
+use crate::images::ProcessedImage;
+use crate::storage::{file_key, image_key};
+
+/// Longest file name kept for an upload, the limit of most file systems.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// An image or file a user uploaded, kept in the storage
+pub struct Attachment {
+    pub id: Uuid,
+    /// The name the file was uploaded with. Images sent with
+    /// `MessageType::Image` have none.
+    pub original_name: Option<String>,
+    pub mime_type: String,
+    pub size: u64,
+    /// The hex-encoded SHA-256 digest of the stored content.
+    pub sha256: String,
+    /// The key the content is stored under, see `Storage`.
+    pub storage_path: String,
+    /// The width and height of an image in pixels. Files have none.
+    pub dimensions: Option<(u32, u32)>,
+    /// The perceptual hash of an image, see `images::dhash`. Files have
+    /// none.
+    pub phash: Option<u64>,
+    /// Smaller copies of an image, smallest first. Files have none.
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+/// A smaller copy of an image attachment, in the format of the image
+pub struct Thumbnail {
+    /// The longest side the thumbnail was made for.
+    pub max_size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub size: u64,
+    /// The key the thumbnail is stored under, the key of the image itself
+    /// if it already fit the size.
+    pub storage_path: String,
+}
+
+impl Attachment {
+    /// Describes an uploaded image and its thumbnails as they came out of
+    /// the image pipeline
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the attachment.
+    /// * `image` - The processed image.
+    pub fn image(id: Uuid, image: &ProcessedImage) -> Result<Self> {
+        let extension = image.format.extensions_str()[0];
+        let sha256 = sha256_hex(&image.data);
+        let thumbnails = image
+            .thumbnails
+            .iter()
+            .map(|thumbnail| {
+                Ok(Thumbnail {
+                    max_size: thumbnail.max_size,
+                    width: thumbnail.width,
+                    height: thumbnail.height,
+                    size: thumbnail.data.len() as u64,
+                    storage_path: image_key(&sha256_hex(&thumbnail.data), extension)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Attachment {
+            id,
+            original_name: None,
+            mime_type: image.format.to_mime_type().to_string(),
+            size: image.data.len() as u64,
+            storage_path: image_key(&sha256, extension)?,
+            sha256,
+            dimensions: Some((image.width, image.height)),
+            phash: Some(image.phash),
+            thumbnails,
+        })
+    }
+
+    /// Describes an uploaded file, guessing its MIME type from its name
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the attachment.
+    /// * `name` - The name the file was uploaded with.
+    /// * `size` - The size of the file in bytes.
+    /// * `sha256` - The hex-encoded SHA-256 digest of the file.
+    pub fn file(id: Uuid, name: String, size: u64, sha256: String) -> Result<Self> {
+        Ok(Attachment {
+            id,
+            mime_type: mime_guess::from_path(&name)
+                .first_or_octet_stream()
+                .to_string(),
+            original_name: Some(name),
+            size,
+            storage_path: file_key(&sha256)?,
+            sha256,
+            dimensions: None,
+            phash: None,
+            thumbnails: Vec::new(),
+        })
+    }
+
+    /// The text the attachment's message shows in history and in the web
+    /// listing.
+    pub fn summary(&self) -> String {
+        match &self.original_name {
+            Some(name) => format!("[file '{}', {} bytes]", name, self.size),
+            None => format!("[image, {} bytes]", self.size),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::{Context, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use shared::Password;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::task;
+
+use crate::repository::ChatRepository;
+
+/// Minimum length of a new password in bytes.
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Failed logins from one address after which the user is locked out.
+const MAX_FAILURES: u32 = 5;
+
+/// How long a user stays locked out, and how long failures are remembered.
+const LOCKOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Error, Debug)]
+pub enum LoginError {
+    #[error("Invalid username or password")]
+    InvalidCredentials,
+    #[error("Too many failed logins, try again in {} seconds", .0.as_secs().max(1))]
+    LockedOut(Duration),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Failed logins of one user from one address
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Tracks failed logins per client address and username
+///
+/// After `MAX_FAILURES` failed logins from the same address, further
+/// attempts to log in as that user from there are refused for `LOCKOUT`,
+/// whether the password is right or not.
+#[derive(Default)]
+pub struct LoginGuard {
+    failures: Mutex<HashMap<(IpAddr, String), Failures>>,
+}
+
+impl LoginGuard {
+    /// Fails if the user is locked out for the address at `now`.
+    fn check(&self, ip: IpAddr, username: &str, now: Instant) -> Result<(), LoginError> {
+        let failures = self.failures.lock().unwrap();
+        let locked_until = failures
+            .get(&(ip, username.to_string()))
+            .and_then(|failures| failures.locked_until);
+        match locked_until {
+            Some(until) if until > now => Err(LoginError::LockedOut(until - now)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a failed login at `now`, locking the user out for the
+    /// address once there were too many.
+    fn record_failure(&self, ip: IpAddr, username: &str, now: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+        // Forget failures that are too old to matter, so addresses trying
+        // many usernames do not pile up
+        failures.retain(|_, failures| now.duration_since(failures.last) < LOCKOUT);
+
+        let failures = failures
+            .entry((ip, username.to_string()))
+            .or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+        failures.count += 1;
+        failures.last = now;
+        if failures.count >= MAX_FAILURES {
+            failures.count = 0;
+            failures.locked_until = Some(now + LOCKOUT);
+        }
+    }
+
+    /// Forgets the failed logins of the user from the address.
+    fn record_success(&self, ip: IpAddr, username: &str) {
+        self.failures
+            .lock()
+            .unwrap()
+            .remove(&(ip, username.to_string()));
+    }
+}
+
+/// Checks a user's password
+///
+/// Unknown users and wrong passwords are reported alike, and an unknown
+/// user still costs a full Argon2 verification, so neither the answer nor
+/// its timing tells whether a username exists.
+///
+/// # Arguments
+///
+/// * `repository` - Where users and messages are stored.
+/// * `guard` - The failed logins tracked by the server.
+/// * `ip` - The address of the client logging in.
+/// * `username` - The username to log in as.
+/// * `password` - The password the client sent.
+pub async fn authenticate(
+    repository: &dyn ChatRepository,
+    guard: &LoginGuard,
+    ip: IpAddr,
+    username: &str,
+    password: &Password,
+) -> Result<(), LoginError> {
+    guard.check(ip, username, Instant::now())?;
+
+    let password_hash = repository.password_hash(username).await?;
+
+    let password = password.clone();
+    let verified = task::spawn_blocking(move || verify_password(&password, password_hash))
+        .await
+        .context("Password verification failed")??;
+
+    if verified {
+        guard.record_success(ip, username);
+        Ok(())
+    } else {
+        guard.record_failure(ip, username, Instant::now());
+        Err(LoginError::InvalidCredentials)
+    }
+}
+
+/// Hashes a new password with Argon2id and a random salt
+///
+/// Returns the hash in PHC string format, which includes the salt and
+/// parameters.
+///
+/// # Arguments
+///
+/// * `password` - The password to hash.
+pub async fn hash_password(password: &Password) -> Result<String> {
+    let password = password.clone();
+    task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.expose().as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))
+    })
+    .await?
+}
+
+/// Verifies a password against a stored hash in constant time, using a
+/// dummy hash when there is none.
+fn verify_password(password: &Password, password_hash: Option<String>) -> Result<bool> {
+    let exists = password_hash.is_some();
+    let password_hash = password_hash.unwrap_or_else(|| dummy_hash().to_string());
+    let parsed = PasswordHash::new(&password_hash)
+        .map_err(|e| anyhow::anyhow!("Stored password hash is invalid: {}", e))?;
+    let matches = Argon2::default()
+        .verify_password(password.expose().as_bytes(), &parsed)
+        .is_ok();
+    Ok(exists && matches)
+}
+
+/// A valid hash of a random password, verified for unknown users.
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(salt.as_str().as_bytes(), &salt)
+            .expect("Failed to hash dummy password")
+            .to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn test_hash_and_verify_password() {
+        let password = Password::new("correct horse");
+        let hash = hash_password(&password).await.unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password(&password, Some(hash.clone())).unwrap());
+        assert!(!verify_password(&Password::new("battery staple"), Some(hash)).unwrap());
+    }
+
+    #[test]
+    fn test_verify_unknown_user_uses_dummy_hash() {
+        assert!(PasswordHash::new(dummy_hash()).is_ok());
+        assert!(!verify_password(&Password::new("correct horse"), None).unwrap());
+        // Even the password the dummy hash was made from is refused
+        let salt = PasswordHash::new(dummy_hash()).unwrap().salt.unwrap();
+        let password = Password::new(salt.as_str());
+        assert!(!verify_password(&password, None).unwrap());
+    }
+
+    #[test]
+    fn test_stored_hash_must_be_valid() {
+        let password = Password::new("correct horse");
+        assert!(verify_password(&password, Some("not a hash".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_guard_locks_out_after_max_failures() {
+        let guard = LoginGuard::default();
+        let start = Instant::now();
+        for _ in 1..MAX_FAILURES {
+            guard.record_failure(IP, "alice", start);
+            assert!(guard.check(IP, "alice", start).is_ok());
+        }
+        guard.record_failure(IP, "alice", start);
+        assert!(matches!(
+            guard.check(IP, "alice", start),
+            Err(LoginError::LockedOut(remaining)) if remaining == LOCKOUT
+        ));
+        // Other users and addresses are not affected
+        assert!(guard.check(IP, "bob", start).is_ok());
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        assert!(guard.check(other, "alice", start).is_ok());
+    }
+
+    #[test]
+    fn test_guard_releases_after_lockout() {
+        let guard = LoginGuard::default();
+        let start = Instant::now();
+        for _ in 0..MAX_FAILURES {
+            guard.record_failure(IP, "alice", start);
+        }
+        assert!(guard.check(IP, "alice", start + LOCKOUT / 2).is_err());
+        assert!(guard.check(IP, "alice", start + LOCKOUT).is_ok());
+    }
+
+    #[test]
+    fn test_guard_forgets_failures_after_success() {
+        let guard = LoginGuard::default();
+        let start = Instant::now();
+        for _ in 1..MAX_FAILURES {
+            guard.record_failure(IP, "alice", start);
+        }
+        guard.record_success(IP, "alice");
+        guard.record_failure(IP, "alice", start);
+        assert!(guard.check(IP, "alice", start).is_ok());
+    }
+}
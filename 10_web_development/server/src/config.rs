@@ -0,0 +1,341 @@
+use anyhow::{Context, Result};
+use shared::{FrameLimits, Heartbeat, MAX_HISTORY_PAGE};
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Number of public messages sent to a client after login by default.
+const DEFAULT_HISTORY_LENGTH: u32 = 20;
+
+/// Seconds a presigned download URL stays valid by default.
+const DEFAULT_S3_URL_EXPIRY: u64 = 900;
+
+/// Largest width and height of an uploaded image by default.
+const DEFAULT_IMAGE_MAX_DIMENSION: u32 = 8192;
+
+/// Most memory an uploaded image may take once decoded by default, 256 MiB.
+const DEFAULT_IMAGE_MAX_DECODED_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The sizes of the thumbnails made of every image by default.
+const DEFAULT_THUMBNAIL_SIZES: &[u32] = &[128, 512];
+
+/// Largest file accepted in a chunked upload by default, 1 GiB.
+const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Seconds an unfinished upload is kept after its last chunk by default.
+const DEFAULT_PARTIAL_UPLOAD_EXPIRY: u64 = 24 * 60 * 60;
+
+/// Bits in which the perceptual hashes of two images may differ for them
+/// to count as duplicates by default.
+const DEFAULT_DUPLICATE_DISTANCE: u32 = 4;
+
+/// Server settings read from the environment (or the `.env` file)
+pub struct Config {
+    /// The limits enforced on frames received from clients.
+    pub limits: FrameLimits,
+    /// The certificate and key to serve TLS with, or `None` for plain TCP.
+    pub tls: Option<TlsFiles>,
+    /// How often clients are pinged and how many pings they may miss.
+    pub heartbeat: Heartbeat,
+    /// How many public messages clients are sent after logging in.
+    pub history_length: u32,
+    /// Where uploaded images and files are kept.
+    pub storage: StorageConfig,
+    /// How uploaded images are checked and converted.
+    pub images: ImageConfig,
+    /// How large chunked uploads may be and where unfinished ones are kept.
+    pub uploads: UploadConfig,
+    /// Whether pending schema migrations are applied on startup.
+    pub auto_migrate: bool,
+}
+
+/// The PEM files the server's TLS certificate is read from
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// How large chunked uploads may be and how long unfinished ones are kept
+pub struct UploadConfig {
+    /// The directory unfinished uploads are kept in.
+    pub partial_dir: PathBuf,
+    /// The largest file accepted, in bytes.
+    pub max_file_size: u64,
+    /// How long an unfinished upload is kept after its last chunk.
+    pub partial_expiry: Duration,
+}
+
+/// How uploaded images are checked, converted and shrunk
+pub struct ImageConfig {
+    /// The widest image accepted, in pixels.
+    pub max_width: u32,
+    /// The tallest image accepted, in pixels.
+    pub max_height: u32,
+    /// The most memory an image may take once decoded, in bytes.
+    pub max_decoded_size: u64,
+    /// The longest side of each thumbnail made of an image, smallest first.
+    pub thumbnail_sizes: Vec<u32>,
+    /// The format images are stored and relayed in.
+    pub format: ImageOutput,
+    /// Bits in which the perceptual hashes of two images of the same size
+    /// may differ for them to count as duplicates, `None` to store every
+    /// image.
+    pub duplicate_distance: Option<u32>,
+}
+
+/// The format uploaded images are stored in
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ImageOutput {
+    /// PNG, whatever the image was sent as.
+    Png,
+    /// The format the image was sent in if it is PNG, JPEG, GIF or WebP,
+    /// PNG otherwise.
+    Original,
+    /// Lossless WebP.
+    WebP,
+}
+
+/// The backend uploaded images and files are kept in
+pub enum StorageConfig {
+    /// A directory of the local file system.
+    Local(PathBuf),
+    /// A bucket of an S3-compatible object store.
+    S3(S3Config),
+}
+
+/// The bucket of an S3-compatible object store to keep uploads in
+pub struct S3Config {
+    /// The URL of the object store, e.g. `http://localhost:9000`.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    /// Prepended to the key of every stored object.
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Whether the bucket is addressed in the path of URLs, as MinIO
+    /// expects, rather than in the host name.
+    pub path_style: bool,
+    /// How long presigned download URLs stay valid.
+    pub url_expiry: Duration,
+}
+
+impl Config {
+    /// Reads the configuration from the environment
+    ///
+    /// Unset variables fall back to their defaults:
+    ///
+    /// * `MAX_FRAME_SIZE` - Maximum size of a message in bytes.
+    /// * `MAX_FILENAME_LENGTH` - Maximum length of a file name in bytes.
+    /// * `MAX_TEXT_LENGTH` - Maximum length of a text message in bytes.
+    /// * `TLS_CERT_FILE` and `TLS_KEY_FILE` - The certificate chain and
+    ///   private key to serve TLS with. Without them the server speaks
+    ///   plain TCP.
+    /// * `HEARTBEAT_INTERVAL` - Seconds between two pings to a client.
+    /// * `HEARTBEAT_MISSES` - Heartbeats a client may miss before it is
+    ///   disconnected.
+    /// * `HISTORY_LENGTH` - Public messages sent to a client after login,
+    ///   0 to send none.
+    /// * `STORAGE_BACKEND` - Where uploaded images and files are kept,
+    ///   `local` (the default) or `s3`.
+    /// * `STORAGE_DIR` - The directory the `local` backend keeps uploads
+    ///   in, the working directory by default. Unfinished chunked uploads
+    ///   are kept in its `files/.partial` with either backend.
+    /// * `MAX_FILE_SIZE` - The largest file accepted in a chunked upload,
+    ///   in bytes.
+    /// * `PARTIAL_UPLOAD_EXPIRY` - Seconds an unfinished upload is kept
+    ///   after its last chunk.
+    /// * `S3_ENDPOINT`, `S3_BUCKET`, `S3_ACCESS_KEY_ID` and
+    ///   `S3_SECRET_ACCESS_KEY` - The object store and bucket the `s3`
+    ///   backend keeps uploads in, and the credentials to access it with.
+    /// * `S3_REGION` - The region of the bucket, `us-east-1` by default.
+    /// * `S3_PREFIX` - Prepended to the key of every object, none by
+    ///   default.
+    /// * `S3_URL_STYLE` - `path` (the default) to address the bucket in the
+    ///   path of URLs, or `virtual-host` to address it in the host name.
+    /// * `S3_URL_EXPIRY` - Seconds a presigned download URL stays valid.
+    /// * `IMAGE_MAX_WIDTH` and `IMAGE_MAX_HEIGHT` - The largest image
+    ///   accepted, in pixels.
+    /// * `IMAGE_MAX_DECODED_SIZE` - The most memory an image may take once
+    ///   decoded, in bytes.
+    /// * `IMAGE_THUMBNAIL_SIZES` - Comma-separated longest sides of the
+    ///   thumbnails made of every image, empty to make none.
+    /// * `IMAGE_FORMAT` - `png` (the default), `original` or `webp`.
+    /// * `IMAGE_DUPLICATE_DISTANCE` - Bits in which the perceptual hashes
+    ///   of two images may differ for the later one to reuse the stored
+    ///   earlier one, `off` to store every image.
+    /// * `AUTO_MIGRATE` - `on` (the default) to apply pending schema
+    ///   migrations on startup, `off` to refuse to start until they are
+    ///   applied with `server migrate`.
+    pub fn from_env() -> Result<Self> {
+        let defaults = FrameLimits::default();
+        let limits = FrameLimits {
+            max_frame_size: var_or("MAX_FRAME_SIZE", defaults.max_frame_size, "bytes")?,
+            max_filename_len: var_or("MAX_FILENAME_LENGTH", defaults.max_filename_len, "bytes")?,
+            max_text_len: var_or("MAX_TEXT_LENGTH", defaults.max_text_len, "bytes")?,
+        };
+        let tls = match (env::var("TLS_CERT_FILE"), env::var("TLS_KEY_FILE")) {
+            (Ok(cert), Ok(key)) => Some(TlsFiles {
+                cert: cert.into(),
+                key: key.into(),
+            }),
+            (Err(_), Err(_)) => None,
+            _ => anyhow::bail!("TLS_CERT_FILE and TLS_KEY_FILE must be set together"),
+        };
+        let defaults = Heartbeat::default();
+        let heartbeat = Heartbeat {
+            interval: Duration::from_secs(var_or(
+                "HEARTBEAT_INTERVAL",
+                defaults.interval.as_secs(),
+                "seconds",
+            )?),
+            max_missed: var_or("HEARTBEAT_MISSES", defaults.max_missed, "heartbeats")?,
+        };
+        if heartbeat.interval.is_zero() || heartbeat.max_missed == 0 {
+            anyhow::bail!("HEARTBEAT_INTERVAL and HEARTBEAT_MISSES must be at least 1");
+        }
+        let history_length = var_or("HISTORY_LENGTH", DEFAULT_HISTORY_LENGTH, "messages")?;
+        if history_length > MAX_HISTORY_PAGE {
+            anyhow::bail!("HISTORY_LENGTH must be at most {}", MAX_HISTORY_PAGE);
+        }
+        let auto_migrate = match env::var("AUTO_MIGRATE").as_deref() {
+            Ok("on") | Err(_) => true,
+            Ok("off") => false,
+            Ok(_) => anyhow::bail!("AUTO_MIGRATE must be on or off"),
+        };
+        Ok(Config {
+            limits,
+            tls,
+            heartbeat,
+            history_length,
+            storage: storage_from_env()?,
+            images: images_from_env()?,
+            uploads: uploads_from_env()?,
+            auto_migrate,
+        })
+    }
+}
+
+/// Reads the image pipeline settings from the environment.
+fn images_from_env() -> Result<ImageConfig> {
+    let max_width = var_or("IMAGE_MAX_WIDTH", DEFAULT_IMAGE_MAX_DIMENSION, "pixels")?;
+    let max_height = var_or("IMAGE_MAX_HEIGHT", DEFAULT_IMAGE_MAX_DIMENSION, "pixels")?;
+    if max_width == 0 || max_height == 0 {
+        anyhow::bail!("IMAGE_MAX_WIDTH and IMAGE_MAX_HEIGHT must be at least 1");
+    }
+    let max_decoded_size = var_or(
+        "IMAGE_MAX_DECODED_SIZE",
+        DEFAULT_IMAGE_MAX_DECODED_SIZE,
+        "bytes",
+    )?;
+    let mut thumbnail_sizes = match env::var("IMAGE_THUMBNAIL_SIZES") {
+        Ok(sizes) => sizes
+            .split(',')
+            .map(str::trim)
+            .filter(|size| !size.is_empty())
+            .map(|size| size.parse().ok().filter(|&size| size > 0))
+            .collect::<Option<Vec<u32>>>()
+            .context("IMAGE_THUMBNAIL_SIZES must be a list of numbers of pixels")?,
+        Err(_) => DEFAULT_THUMBNAIL_SIZES.to_vec(),
+    };
+    thumbnail_sizes.sort_unstable();
+    thumbnail_sizes.dedup();
+    let format = match env::var("IMAGE_FORMAT").as_deref() {
+        Ok("png") | Err(_) => ImageOutput::Png,
+        Ok("original") => ImageOutput::Original,
+        Ok("webp") => ImageOutput::WebP,
+        Ok(_) => anyhow::bail!("IMAGE_FORMAT must be png, original or webp"),
+    };
+    let duplicate_distance = match env::var("IMAGE_DUPLICATE_DISTANCE").as_deref() {
+        Ok("off") => None,
+        _ => Some(var_or(
+            "IMAGE_DUPLICATE_DISTANCE",
+            DEFAULT_DUPLICATE_DISTANCE,
+            "bits",
+        )?),
+    };
+    if duplicate_distance.is_some_and(|distance| distance > 64) {
+        anyhow::bail!("IMAGE_DUPLICATE_DISTANCE must be at most 64 bits");
+    }
+    Ok(ImageConfig {
+        max_width,
+        max_height,
+        max_decoded_size,
+        thumbnail_sizes,
+        format,
+        duplicate_distance,
+    })
+}
+
+/// Reads the limits of chunked uploads from the environment.
+fn uploads_from_env() -> Result<UploadConfig> {
+    let storage_dir = PathBuf::from(env::var("STORAGE_DIR").unwrap_or_else(|_| ".".to_string()));
+    Ok(UploadConfig {
+        partial_dir: storage_dir.join("files").join(".partial"),
+        max_file_size: var_or("MAX_FILE_SIZE", DEFAULT_MAX_FILE_SIZE, "bytes")?,
+        partial_expiry: Duration::from_secs(var_or(
+            "PARTIAL_UPLOAD_EXPIRY",
+            DEFAULT_PARTIAL_UPLOAD_EXPIRY,
+            "seconds",
+        )?),
+    })
+}
+
+/// Reads the storage backend and its settings from the environment.
+fn storage_from_env() -> Result<StorageConfig> {
+    let backend = env::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".to_string());
+    match backend.as_str() {
+        "local" => Ok(StorageConfig::Local(
+            env::var("STORAGE_DIR")
+                .unwrap_or_else(|_| ".".to_string())
+                .into(),
+        )),
+        "s3" => {
+            let path_style = match env::var("S3_URL_STYLE").as_deref() {
+                Ok("path") | Err(_) => true,
+                Ok("virtual-host") => false,
+                Ok(_) => anyhow::bail!("S3_URL_STYLE must be path or virtual-host"),
+            };
+            let mut prefix = env::var("S3_PREFIX").unwrap_or_default();
+            if !prefix.is_empty() && !prefix.ends_with('/') {
+                prefix.push('/');
+            }
+            Ok(StorageConfig::S3(S3Config {
+                endpoint: required_var("S3_ENDPOINT")?,
+                bucket: required_var("S3_BUCKET")?,
+                region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+                prefix,
+                access_key_id: required_var("S3_ACCESS_KEY_ID")?,
+                secret_access_key: required_var("S3_SECRET_ACCESS_KEY")?,
+                path_style,
+                url_expiry: Duration::from_secs(var_or(
+                    "S3_URL_EXPIRY",
+                    DEFAULT_S3_URL_EXPIRY,
+                    "seconds",
+                )?),
+            }))
+        }
+        _ => anyhow::bail!("STORAGE_BACKEND must be local or s3"),
+    }
+}
+
+/// Reads an environment variable that has no default.
+fn required_var(name: &str) -> Result<String> {
+    env::var(name).with_context(|| format!("{} must be set", name))
+}
+
+/// Parses an environment variable holding a number of `unit`s, returning
+/// `default` when it is not set.
+fn var_or<T>(name: &str, default: T, unit: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("{} must be a number of {}", name, unit)),
+        Err(_) => Ok(default),
+    }
+}
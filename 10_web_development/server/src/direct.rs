@@ -0,0 +1,85 @@
+use anyhow::Result;
+use shared::{Capability, Envelope, MessageType};
+use tracing::info;
+
+use crate::repository::ChatRepository;
+use crate::ClientHandle;
+
+/// Stores a direct message
+///
+/// Direct messages live in their own table, so they never show up among
+/// the public messages.
+///
+/// # Arguments
+///
+/// * `repository` - Where users and messages are stored.
+/// * `envelope` - The stamped envelope of the message.
+/// * `to` - The username of the recipient.
+/// * `body` - The text of the message.
+/// * `delivered` - Whether the message was relayed to a session of the
+///   recipient, or still has to be delivered when they log in.
+pub async fn save_direct_message(
+    repository: &dyn ChatRepository,
+    envelope: &Envelope,
+    to: &str,
+    body: &str,
+    delivered: bool,
+) -> Result<()> {
+    if !repository
+        .save_direct_message(envelope, to, body, delivered)
+        .await?
+    {
+        return Err(anyhow::anyhow!("User '{}' does not exist", to));
+    }
+    Ok(())
+}
+
+/// Sends a user the direct messages they received while offline
+///
+/// The messages are sent oldest first with their original ID, sender and
+/// timestamp, and are marked as delivered afterwards. Sessions that did not
+/// negotiate direct messages are skipped and the messages stay pending.
+///
+/// # Arguments
+///
+/// * `repository` - Where users and messages are stored.
+/// * `handle` - The handle of the session the user logged in on.
+/// * `username` - The username of the user.
+pub async fn deliver_pending(
+    repository: &dyn ChatRepository,
+    handle: &ClientHandle,
+    username: &str,
+) -> Result<()> {
+    if !handle.capabilities.contains(&Capability::DirectMessages) {
+        return Ok(());
+    }
+
+    let rows = repository.pending_direct_messages(username).await?;
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    let mut delivered = Vec::with_capacity(rows.len());
+    for row in rows {
+        let envelope = Envelope {
+            id: row.message_id,
+            timestamp: Some(row.timestamp.and_utc()),
+            sender: Some(row.username),
+            correlation_id: None,
+            message: MessageType::Direct {
+                to: username.to_string(),
+                body: row.content,
+            },
+        };
+        handle.send_envelope(envelope).await?;
+        delivered.push(row.id);
+    }
+
+    repository.mark_delivered(&delivered).await?;
+    info!(
+        "Delivered {} pending direct messages to {}",
+        delivered.len(),
+        username
+    );
+    Ok(())
+}
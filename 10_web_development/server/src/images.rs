@@ -0,0 +1,207 @@
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use std::borrow::Cow;
+use std::io::Cursor;
+
+use crate::config::{ImageConfig, ImageOutput};
+
+/// Quality JPEG images are encoded with when they keep their format.
+const JPEG_QUALITY: u8 = 90;
+
+/// An uploaded image, decoded and checked against the limits
+pub struct DecodedImage {
+    /// The image, turned upright.
+    pub image: DynamicImage,
+    /// The format the image was sent in.
+    pub format: ImageFormat,
+    /// The perceptual hash of the image, see `dhash`.
+    pub phash: u64,
+}
+
+/// An uploaded image after it went through the pipeline
+pub struct ProcessedImage {
+    /// The format the image is stored in.
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    /// The perceptual hash of the image, see `dhash`.
+    pub phash: u64,
+    /// The encoded image, without any of the metadata it was sent with.
+    pub data: Vec<u8>,
+    /// Smaller copies of the image, one for each configured size.
+    pub thumbnails: Vec<ProcessedThumbnail>,
+}
+
+/// A thumbnail of an uploaded image, in the format of the image
+pub struct ProcessedThumbnail {
+    /// The longest side the thumbnail was made for.
+    pub max_size: u32,
+    pub width: u32,
+    pub height: u32,
+    /// The encoded thumbnail. Images that already fit the size are their
+    /// own thumbnail.
+    pub data: Vec<u8>,
+}
+
+/// Checks and decodes an uploaded image
+///
+/// Images larger than the configured dimensions or decoded size are
+/// refused before they are decoded, so a small file cannot make the server
+/// allocate huge buffers. The image is turned upright according to its
+/// EXIF orientation. Runs for a while, call it from a blocking task.
+///
+/// # Arguments
+///
+/// * `data` - The image as sent by the client.
+/// * `config` - The limits to check the image against.
+pub fn decode_image(data: &[u8], config: &ImageConfig) -> Result<DecodedImage> {
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .context("Failed to read image")?;
+    let sent_format = reader.format().context("Unsupported image format")?;
+    // The dimensions are checked below, with a clearer message
+    let mut limits = Limits::no_limits();
+    limits.max_alloc = Some(config.max_decoded_size);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder().context("Failed to load image")?;
+    let (width, height) = decoder.dimensions();
+    if width > config.max_width || height > config.max_height {
+        anyhow::bail!(
+            "Image is {}x{} pixels, at most {}x{} are allowed",
+            width,
+            height,
+            config.max_width,
+            config.max_height
+        );
+    }
+    if decoder.total_bytes() > config.max_decoded_size {
+        anyhow::bail!(
+            "Image takes {} bytes once decoded, at most {} are allowed",
+            decoder.total_bytes(),
+            config.max_decoded_size
+        );
+    }
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder).context("Failed to load image")?;
+    image.apply_orientation(orientation);
+    Ok(DecodedImage {
+        phash: dhash(&image),
+        image,
+        format: sent_format,
+    })
+}
+
+/// Converts and shrinks a decoded image
+///
+/// The image is encoded again, which drops EXIF, GPS and any other metadata
+/// it was sent with, and thumbnails are made at the configured sizes. Runs
+/// for a while, call it from a blocking task.
+///
+/// # Arguments
+///
+/// * `decoded` - The decoded image.
+/// * `config` - The thumbnail sizes and output format.
+pub fn encode_image(decoded: DecodedImage, config: &ImageConfig) -> Result<ProcessedImage> {
+    let DecodedImage {
+        image,
+        format: sent_format,
+        phash,
+    } = decoded;
+    let format = match config.format {
+        ImageOutput::Png => ImageFormat::Png,
+        ImageOutput::WebP => ImageFormat::WebP,
+        ImageOutput::Original => match sent_format {
+            ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP => {
+                sent_format
+            }
+            _ => ImageFormat::Png,
+        },
+    };
+    let data = encode(&image, format)?;
+
+    let mut thumbnails = Vec::with_capacity(config.thumbnail_sizes.len());
+    for &max_size in &config.thumbnail_sizes {
+        let thumbnail = if image.width() <= max_size && image.height() <= max_size {
+            ProcessedThumbnail {
+                max_size,
+                width: image.width(),
+                height: image.height(),
+                data: data.clone(),
+            }
+        } else {
+            let resized = image.thumbnail(max_size, max_size);
+            ProcessedThumbnail {
+                max_size,
+                width: resized.width(),
+                height: resized.height(),
+                data: encode(&resized, format)?,
+            }
+        };
+        thumbnails.push(thumbnail);
+    }
+
+    Ok(ProcessedImage {
+        format,
+        width: image.width(),
+        height: image.height(),
+        phash,
+        data,
+        thumbnails,
+    })
+}
+
+/// Computes the difference hash (dHash) of an image
+///
+/// The image is shrunk to 9x8 grey pixels, and each bit of the hash tells
+/// whether a pixel is brighter than its right neighbour. Copies of an image
+/// that were scaled, re-encoded or slightly edited get hashes that differ
+/// in only a few bits. Flat images have no differences to go by and all
+/// hash to 0.
+///
+/// # Arguments
+///
+/// * `image` - The image to hash.
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.thumbnail_exact(9, 8).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Encodes an image, converting its pixels to what the format supports
+///
+/// # Arguments
+///
+/// * `image` - The image to encode.
+/// * `format` - The format to encode it in.
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>> {
+    let pixels = match format {
+        ImageFormat::Jpeg => Cow::Owned(DynamicImage::ImageRgb8(image.to_rgb8())),
+        ImageFormat::Gif => Cow::Owned(DynamicImage::ImageRgba8(image.to_rgba8())),
+        ImageFormat::WebP if image.color().has_alpha() => {
+            Cow::Owned(DynamicImage::ImageRgba8(image.to_rgba8()))
+        }
+        ImageFormat::WebP => Cow::Owned(DynamicImage::ImageRgb8(image.to_rgb8())),
+        _ => Cow::Borrowed(image),
+    };
+
+    let mut data = Vec::new();
+    let result = match format {
+        ImageFormat::Jpeg => {
+            pixels.write_with_encoder(JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY))
+        }
+        _ => pixels.write_to(&mut Cursor::new(&mut data), format),
+    };
+    result.with_context(|| format!("Failed to encode image as {:?}", format))?;
+    Ok(data)
+}
@@ -1,81 +1,454 @@
 use anyhow::{Context, Result};
-use chrono::{NaiveDateTime, Utc};
+use attachments::Attachment;
+use auth::{LoginError, LoginGuard};
+use chrono::{DateTime, Utc};
+use config::{Config, ImageConfig, StorageConfig, UploadConfig};
 use dotenv::dotenv;
-use image::ImageFormat;
-use shared::{deserialize_message, serialize_message, MessageType};
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-use std::collections::HashMap;
+use futures::{SinkExt, StreamExt};
+use images::DecodedImage;
+use repository::ChatRepository;
+use s3::S3Storage;
+use session::Sessions;
+use shared::{
+    choose_compression, choose_format, is_compatible, is_valid_room_name, is_valid_username,
+    negotiate, sanitize_file_name, sha256_hex, Capability, Compression, Envelope, EnvelopeCodec,
+    Format, FrameError, FrameLimits, Heartbeat, MessageType, Password, PresenceEvent, Status,
+    MAX_HISTORY_PAGE, MAX_USERNAME_LEN, PROTOCOL_VERSION,
+};
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::path::Path;
 use std::sync::Arc;
-use tokio::fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use storage::{LocalStorage, Storage};
+use tls::{ReadStream, WriteStream};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Mutex, Notify};
 use tokio::task;
-use tracing::{error, info};
-use tracing_subscriber;
-use actix_rt;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{error, info, warn};
+use transfer::{expire_partial_uploads, relay_file, CompletedUpload, Uploads};
+use uuid::Uuid;
 
+mod attachments;
+mod auth;
+mod config;
+mod direct;
+mod images;
+mod migrations;
+mod postgres;
+mod presence;
+mod repository;
+mod rooms;
+mod s3;
+mod session;
+mod sqlite;
+mod storage;
+mod tls;
+mod transfer;
 mod web_server; 
 
-type Clients = Arc<Mutex<HashMap<std::net::SocketAddr, (Arc<Mutex<TcpStream>>, String)>>>;
+/// Capacity of each client's outbound queue.
+///
+/// When a client does not read fast enough and its queue fills up, the
+/// server disconnects it instead of letting it hold up other clients.
+const OUTBOUND_QUEUE_CAPACITY: usize = 64;
+
+/// Capacity of each client's queue for relayed file transfers.
+///
+/// Files are queued with backpressure and written only when the outbound
+/// queue is empty, so a large transfer never takes slots chat messages,
+/// presence events and pings need.
+const BULK_QUEUE_CAPACITY: usize = 4;
+
+/// A handle to a connected client
+///
+/// Messages for the client are pushed into its outbound queue and written
+/// to the socket by the client's own writer task, so senders never wait on
+/// the client's reader.
+#[derive(Clone)]
+struct ClientHandle {
+    /// The sending side of the client's outbound queue.
+    sender: mpsc::Sender<Arc<Envelope>>,
+    /// The sending side of the client's queue for relayed files.
+    bulk: mpsc::Sender<Arc<Envelope>>,
+    /// The username of the client, empty until the client logs in.
+    username: String,
+    /// The capabilities negotiated with the client during the handshake.
+    capabilities: Vec<Capability>,
+    /// The rooms the client has joined.
+    rooms: HashSet<String>,
+    /// Notified when the server decides to drop the client.
+    disconnect: Arc<Notify>,
+    /// The session the client logged in with, if it negotiated sessions.
+    session: Option<Uuid>,
+    /// The heartbeat settings, `None` once the handshake showed the client
+    /// does not support heartbeats.
+    heartbeat: Option<Heartbeat>,
+    /// The status the user is shown with.
+    status: Status,
+    /// How many public messages the client is sent after logging in.
+    history_length: u32,
+    /// Where the client's images and files are kept.
+    storage: Arc<dyn Storage>,
+    /// How the client's images are checked and converted.
+    images: Arc<ImageConfig>,
+    /// How large the client's chunked uploads may be and where unfinished
+    /// ones are kept.
+    uploads: Arc<UploadConfig>,
+}
+
+impl ClientHandle {
+    /// Queues a message from the server for the client, waiting for space
+    /// in the queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to be sent.
+    async fn send(&self, message: MessageType) -> Result<()> {
+        self.send_envelope(Envelope::new(message).stamp(None)).await
+    }
+
+    /// Queues a message from the server answering one of the client's
+    /// messages.
+    ///
+    /// # Arguments
+    ///
+    /// * `correlation_id` - The ID of the client's message being answered.
+    /// * `message` - The message to be sent.
+    async fn reply(&self, correlation_id: Uuid, message: MessageType) -> Result<()> {
+        self.send_envelope(Envelope::reply_to(correlation_id, message).stamp(None))
+            .await
+    }
+
+    /// Queues an already stamped envelope for the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `envelope` - The envelope to be sent.
+    async fn send_envelope(&self, envelope: Envelope) -> Result<()> {
+        self.sender
+            .send(Arc::new(envelope))
+            .await
+            .map_err(|_| anyhow::anyhow!("Client connection is closed"))
+    }
+
+    /// Queues part of a relayed file for the client, waiting for space in
+    /// the bulk queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `envelope` - The envelope to be sent.
+    async fn send_bulk(&self, envelope: Envelope) -> Result<()> {
+        self.bulk
+            .send(Arc::new(envelope))
+            .await
+            .map_err(|_| anyhow::anyhow!("Client connection is closed"))
+    }
+
+    /// Returns the capability the message needs but the client did not
+    /// negotiate, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to check.
+    fn missing_capability(&self, message: &MessageType) -> Option<Capability> {
+        message
+            .required_capability()
+            .filter(|capability| !self.capabilities.contains(capability))
+    }
+
+    /// Checks whether a broadcast message is meant for the client: room
+    /// messages only go to the members of the room, and direct messages to
+    /// the recipient.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to check.
+    fn receives(&self, message: &MessageType) -> bool {
+        match message {
+            MessageType::RoomMessage { room, .. } => self.rooms.contains(room),
+            MessageType::Direct { to, .. } => self.username == *to,
+            _ => true,
+        }
+    }
+}
+
+type Clients = Arc<Mutex<HashMap<std::net::SocketAddr, ClientHandle>>>;
+
+/// The read half of a client's connection, decoded into envelopes.
+type ClientReader = FramedRead<ReadStream, EnvelopeCodec>;
+
+/// The write half of a client's connection, encoding envelopes.
+type ClientWriter = FramedWrite<WriteStream, EnvelopeCodec>;
 
 /// Handles client connections and interactions
 ///
 /// This functions manages the client's connection lifecycle, including login,
-/// registration, and message handling.
+/// registration, and message handling. Reading stops when the server
+/// disconnects the client (see `broadcast_message`) or when the client sends
+/// a frame the server cannot accept, in which case the client is told why
+/// before the connection is closed.
 ///
 /// # Arguments
 ///
-/// * `stream` - The client's TCP stream wrapped in an Arc and Mutex.
-/// * `addr` - A shared reference to the clients hashmap.
-/// * `db_pool` - The PostgreSQL connection pool.
+/// * `reader` - The read half of the client's TCP stream.
+/// * `handle` - The handle used to send messages to the client.
+/// * `addr` - The client's socket address.
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `repository` - Where users and messages are stored.
+/// * `guard` - The failed logins tracked by the server.
+/// * `sessions` - The sessions of the server.
 async fn handle_client(
-    stream: Arc<Mutex<TcpStream>>,
+    mut reader: ClientReader,
+    handle: ClientHandle,
     addr: std::net::SocketAddr,
     clients: Clients,
-    db_pool: Arc<Pool<Postgres>>,
+    repository: Arc<dyn ChatRepository>,
+    guard: Arc<LoginGuard>,
+    sessions: Arc<Sessions>,
 ) -> Result<()> {
+    let result = tokio::select! {
+        result = client_session(&mut reader, &handle, addr, &clients, &repository, &guard, &sessions) => result,
+        _ = send_heartbeats(&handle, addr, &clients) => Ok(()),
+        _ = handle.disconnect.notified() => {
+            info!("Client {} was disconnected by the server", addr);
+            Ok(())
+        }
+    };
+
+    if let Err(e) = &result {
+        match e.downcast_ref::<FrameError>() {
+            Some(FrameError::Io(_)) | None => {}
+            Some(err) => {
+                let _ = report_error(&handle, None, &err.to_string()).await;
+            }
+        }
+    }
+    let timed_out = matches!(&result, Err(e) if is_timeout(e));
+    // Removed first, announcing locks the clients again
+    let removed = clients.lock().await.remove(&addr);
+    if let Some(handle) = removed {
+        if !handle.username.is_empty() {
+            let event = if timed_out {
+                PresenceEvent::TimedOut
+            } else {
+                PresenceEvent::LoggedOut
+            };
+            presence::announce(&clients, addr, &handle.username, event).await;
+        }
+        // Keep the session around so the client can resume it after
+        // reconnecting
+        if let Some(id) = handle.session {
+            sessions.suspend(id, addr, handle.rooms);
+        }
+    }
+    if timed_out {
+        // Already logged when the timeout was noticed
+        return Ok(());
+    }
+    result
+}
+
+/// Performs the protocol handshake with a new client
+///
+/// The client must open the connection with `MessageType::Hello`. A client
+/// speaking an incompatible protocol version is sent an error and rejected.
+/// Otherwise it is answered with `MessageType::HelloAck`, and the returned
+/// handle carries the negotiated capabilities. The reader switches to the
+/// negotiated wire format and compression right away, and the writer once
+/// it has sent the `HelloAck` (see `write_loop`).
+///
+/// # Arguments
+///
+/// * `reader` - The read half of the client's TCP stream.
+/// * `handle` - The handle used to send messages to the client.
+/// * `addr` - The client's socket address.
+/// * `clients` - A shared reference to the clients hashmap.
+async fn handshake(
+    reader: &mut ClientReader,
+    handle: &ClientHandle,
+    addr: std::net::SocketAddr,
+    clients: &Clients,
+) -> Result<ClientHandle> {
+    let envelope = next_message(reader, handle).await?;
+    if !within_limits(handle, &envelope, reader.decoder().limits()).await? {
+        return Err(anyhow::anyhow!("Client {} sent an oversized Hello", addr));
+    }
+    let (protocol_version, client_name, offered, formats, offered_compression) =
+        match envelope.message {
+            MessageType::Hello {
+                protocol_version,
+                client_name,
+                capabilities,
+                formats,
+                compression,
+            } => (
+                protocol_version,
+                client_name,
+                capabilities,
+                formats,
+                compression,
+            ),
+            _ => {
+                report_error(
+                    handle,
+                    Some(envelope.id),
+                    "Expected Hello as the first message",
+                )
+                .await?;
+                return Err(anyhow::anyhow!("Client {} did not send Hello", addr));
+            }
+        };
+
+    if !is_compatible(protocol_version) {
+        let error_message = format!(
+            "Unsupported protocol version {}, the server speaks version {}",
+            protocol_version, PROTOCOL_VERSION
+        );
+        report_error(handle, Some(envelope.id), &error_message).await?;
+        return Err(anyhow::anyhow!(
+            "Client {} ({}) speaks incompatible protocol version {}",
+            addr,
+            client_name,
+            protocol_version
+        ));
+    }
+
+    let capabilities = negotiate(&offered, Capability::ALL);
+    let format = choose_format(&formats, Format::ALL);
+    let compression = choose_compression(&offered_compression, Compression::ALL);
+    info!(
+        "Client {} ({}) speaks protocol version {} with capabilities {:?} in format {} with compression {:?}",
+        addr, client_name, protocol_version, capabilities, format, compression
+    );
+    reader.decoder_mut().set_format(format);
+    reader.decoder_mut().set_compression(compression);
+    let handle = ClientHandle {
+        heartbeat: handle
+            .heartbeat
+            .filter(|_| capabilities.contains(&Capability::Heartbeats)),
+        capabilities: capabilities.clone(),
+        ..handle.clone()
+    };
+    clients.lock().await.insert(addr, handle.clone());
+    let hello_ack = MessageType::HelloAck {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities,
+        format,
+        compression,
+    };
+    handle.reply(envelope.id, hello_ack).await?;
+    Ok(handle)
+}
+
+/// Runs the login and message loops of a client session
+///
+/// # Arguments
+///
+/// * `reader` - The read half of the client's TCP stream.
+/// * `handle` - The handle used to send messages to the client.
+/// * `addr` - The client's socket address.
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `repository` - Where users and messages are stored.
+/// * `guard` - The failed logins tracked by the server.
+/// * `sessions` - The sessions of the server.
+async fn client_session(
+    reader: &mut ClientReader,
+    handle: &ClientHandle,
+    addr: std::net::SocketAddr,
+    clients: &Clients,
+    repository: &Arc<dyn ChatRepository>,
+    guard: &LoginGuard,
+    sessions: &Sessions,
+) -> Result<()> {
+    let handle = &handshake(reader, handle, addr, clients).await?;
+    let limits = *reader.decoder().limits();
+    let mut uploads = Uploads::new(Arc::clone(&handle.uploads));
+
     // Ask for login or registration
     loop {
-        let message = read_message(stream.clone()).await?;
-        match message {
-            MessageType::Login(username) => {
-                if user_exists(&db_pool, &username).await? {
-                    clients
-                        .lock()
-                        .await
-                        .insert(addr, (stream.clone(), username.clone()));
-                    info!("User {} logged in from {}", username, addr);
-                    let welcome_message = MessageType::Text(format!("Welcome, {}!", username));
-                    send_message(stream.clone(), &welcome_message).await?;
-                    break;
-                } else {
-                    let error_message = MessageType::Error(
-                        "User does not exist. You can create new user by \n 
-               .register <username> 
-               \n or provide correct username and login by 
-               \n .login <username>"
-                            .to_string(),
-                    );
-                    send_message(stream.clone(), &error_message).await?;
+        let envelope = next_message(reader, handle).await?;
+        if !within_limits(handle, &envelope, &limits).await? {
+            continue;
+        }
+        match envelope.message {
+            MessageType::Login { username, password } => {
+                if !check_password(
+                    repository.as_ref(),
+                    guard,
+                    addr,
+                    handle,
+                    envelope.id,
+                    &username,
+                    &password,
+                )
+                .await?
+                {
+                    continue;
                 }
+                log_in(clients, addr, handle, &username).await;
+                info!("User {} logged in from {}", username, addr);
+                let welcome_message = MessageType::Text(format!("Welcome, {}!", username));
+                handle.reply(envelope.id, welcome_message).await?;
+                session::start_session(sessions, clients, addr, handle, &username).await?;
+                send_recent_history(repository.as_ref(), handle).await?;
+                direct::deliver_pending(repository.as_ref(), handle, &username).await?;
+                break;
             }
-            MessageType::Register(username) => {
-                if register_user(&db_pool, &username).await.is_ok() {
-                    clients
-                        .lock()
-                        .await
-                        .insert(addr, (stream.clone(), username.clone()));
+            MessageType::Resume(token) => match sessions.resume(&token, addr) {
+                Ok(resumed) => {
+                    resume_session(
+                        clients,
+                        addr,
+                        handle,
+                        repository.as_ref(),
+                        envelope.id,
+                        resumed,
+                    )
+                    .await?;
+                    break;
+                }
+                Err(e) => {
+                    warn!("Refused to resume a session from {}: {}", addr, e);
+                    report_error(handle, Some(envelope.id), &e.to_string()).await?;
+                }
+            },
+            MessageType::Register { username, password } => {
+                if !is_valid_username(&username) {
+                    let error_message = MessageType::Error(format!(
+                        "Usernames must be 1 to {} characters long and consist of letters, digits, - and _",
+                        MAX_USERNAME_LEN
+                    ));
+                    handle.reply(envelope.id, error_message).await?;
+                    continue;
+                }
+                if password.expose().len() < auth::MIN_PASSWORD_LEN {
+                    let error_message = MessageType::Error(format!(
+                        "Password must be at least {} characters long",
+                        auth::MIN_PASSWORD_LEN
+                    ));
+                    handle.reply(envelope.id, error_message).await?;
+                    continue;
+                }
+                let password_hash = auth::hash_password(&password).await?;
+                if register_user(repository.as_ref(), &username, &password_hash)
+                    .await
+                    .is_ok()
+                {
+                    log_in(clients, addr, handle, &username).await;
                     info!("User {} registered and logged in from {}", username, addr);
                     let welcome_message =
                         MessageType::Text(format!("User {} registered successfully", username));
-                    send_message(stream.clone(), &welcome_message).await?;
+                    handle.reply(envelope.id, welcome_message).await?;
+                    session::start_session(sessions, clients, addr, handle, &username).await?;
+                    send_recent_history(repository.as_ref(), handle).await?;
                     break;
                 } else {
                     let error_message = MessageType::Error("Failed to register user.".to_string());
-                    send_message(stream.clone(), &error_message).await?;
+                    handle.reply(envelope.id, error_message).await?;
                 }
             }
             MessageType::Quit => {
@@ -84,213 +457,770 @@ async fn handle_client(
             }
             _ => {
                 let error_message = MessageType::Error(
-                    "Please login or register. \n .login <username> \n or \n .register <username>"
+                    "Please login or register. \n .login <username> <password> \n or \n .register <username> <password>"
                         .to_string(),
                 );
-                send_message(stream.clone(), &error_message).await?;
+                handle.reply(envelope.id, error_message).await?;
             }
         }
     }
 
     loop {
-        match read_message(stream.clone()).await {
-            Ok(message) => {
-                match message {
-                    MessageType::Login(username) => {
-                        clients
-                            .lock()
-                            .await
-                            .insert(addr, (stream.clone(), username.clone()));
-                        info!("User {} connected from {}", username, addr);
-                        // Send a welcome message or confirmation
-                        let welcome_message = MessageType::Text(format!("Welcome, {}!", username));
-                        send_message(stream.clone(), &welcome_message).await?;
-                    }
-                    _ => {
-                        if handle_message(addr, message, clients.clone(), db_pool.clone()).await? {
-                            break; // .quit message
-                        }
-                    }
-                }
+        let envelope = match next_message(reader, handle).await {
+            Ok(envelope) => envelope,
+            Err(e) if is_timeout(&e) => {
+                warn!("Client {} stopped answering heartbeats: {}", addr, e);
+                return Err(e);
             }
-            Err(e) => {
-                error!("Error handling client {}: {:?}", addr, e);
-                report_error(stream.clone(), &e.to_string()).await?;
+            Err(e) if matches!(e.downcast_ref(), Some(FrameError::Io(_))) => {
+                info!("Client {} closed the connection: {}", addr, e);
                 break;
             }
+            Err(e) => return Err(e),
+        };
+        if !within_limits(handle, &envelope, &limits).await? {
+            continue;
+        }
+        match envelope.message {
+            MessageType::Login {
+                ref username,
+                ref password,
+            } => {
+                if !check_password(
+                    repository.as_ref(),
+                    guard,
+                    addr,
+                    handle,
+                    envelope.id,
+                    username,
+                    password,
+                )
+                .await?
+                {
+                    continue;
+                }
+                log_in(clients, addr, handle, username).await;
+                info!("User {} connected from {}", username, addr);
+                // Send a welcome message or confirmation
+                let welcome_message = MessageType::Text(format!("Welcome, {}!", username));
+                handle.reply(envelope.id, welcome_message).await?;
+                session::start_session(sessions, clients, addr, handle, username).await?;
+                send_recent_history(repository.as_ref(), handle).await?;
+                direct::deliver_pending(repository.as_ref(), handle, username).await?;
+            }
+            _ => {
+                if let Some(capability) = handle.missing_capability(&envelope.message) {
+                    let error_message = MessageType::Error(format!(
+                        "Capability {:?} was not negotiated for this connection",
+                        capability
+                    ));
+                    handle.reply(envelope.id, error_message).await?;
+                    continue;
+                }
+                let id = envelope.id;
+                let result = handle_message(
+                    addr,
+                    envelope,
+                    handle,
+                    &mut uploads,
+                    clients.clone(),
+                    repository.clone(),
+                    sessions,
+                )
+                .await;
+                match result {
+                    Ok(true) => break, // .quit message
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("Error handling message from {}: {:?}", addr, e);
+                        report_error(handle, Some(id), &e.to_string()).await?;
+                    }
+                }
+            }
         }
     }
 
-    clients.lock().await.remove(&addr);
     Ok(())
 }
 
-/// Reads a message from the client
+/// Records the username of a client that has logged in and announces it
+/// to the other clients
 ///
-/// This function reads a message from the clitnt's stream, deserializes it,
-/// and returns the deserialized message.
+/// # Arguments
+///
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `addr` - The client's socket address.
+/// * `handle` - The handle used to send messages to the client.
+/// * `username` - The username the client logged in as.
+async fn log_in(
+    clients: &Clients,
+    addr: std::net::SocketAddr,
+    handle: &ClientHandle,
+    username: &str,
+) {
+    let handle = ClientHandle {
+        username: username.to_string(),
+        ..handle.clone()
+    };
+    let previous = clients
+        .lock()
+        .await
+        .insert(addr, handle)
+        .map(|previous| previous.username)
+        .unwrap_or_default();
+    if previous == username {
+        return;
+    }
+    if !previous.is_empty() {
+        presence::announce(clients, addr, &previous, PresenceEvent::LoggedOut).await;
+    }
+    presence::announce(clients, addr, username, PresenceEvent::LoggedIn).await;
+}
+
+/// Logs a client in on a resumed session
+///
+/// The client rejoins the rooms it had joined and is sent the public and
+/// room messages it missed, followed by its pending direct messages. If the
+/// session was still attached to another connection, that connection is
+/// dropped, and there is nothing to replay.
 ///
 /// # Arguments
 ///
-/// * `stream` - The client's TCP stream wrapped in an Arc and Mutex.
-async fn read_message(stream: Arc<Mutex<TcpStream>>) -> Result<MessageType> {
-    let mut len_bytes = [0u8; 4];
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `addr` - The client's socket address.
+/// * `handle` - The handle used to send messages to the client.
+/// * `repository` - Where users and messages are stored.
+/// * `request_id` - The ID of the client's resume message.
+/// * `resumed` - The session taken over by the client.
+async fn resume_session(
+    clients: &Clients,
+    addr: std::net::SocketAddr,
+    handle: &ClientHandle,
+    repository: &dyn ChatRepository,
+    request_id: Uuid,
+    resumed: session::Resumed,
+) -> Result<()> {
+    let mut rooms = resumed.rooms;
     {
-        let mut stream = stream.lock().await;
-        stream
-            .read_exact(&mut len_bytes)
-            .await
-            .context("Failed to read message lenght")?;
-        //  info!("Received message length: {}", u32::from_be_bytes(len_bytes));
+        let mut clients = clients.lock().await;
+        if let Some(previous) = resumed
+            .previous
+            .and_then(|previous| clients.remove(&previous))
+        {
+            previous.disconnect.notify_one();
+            rooms = previous.rooms;
+        }
+        clients.insert(
+            addr,
+            ClientHandle {
+                username: resumed.username.clone(),
+                rooms: rooms.clone(),
+                session: Some(resumed.id),
+                ..handle.clone()
+            },
+        );
     }
-    let len = u32::from_be_bytes(len_bytes) as usize;
+    info!(
+        "User {} resumed their session from {}",
+        resumed.username, addr
+    );
+    // A session taken over from a live connection never went offline
+    if resumed.previous.is_none() {
+        presence::announce(clients, addr, &resumed.username, PresenceEvent::LoggedIn).await;
+    }
+    let welcome_message = MessageType::Text(format!("Welcome back, {}!", resumed.username));
+    handle.reply(request_id, welcome_message).await?;
 
-    let mut buffer = vec![0u8; len];
-    {
-        let mut stream = stream.lock().await;
-        stream
-            .read_exact(&mut buffer)
-            .await
-            .context("Failed to read message")?;
-        // info!("Received message: {:?}", buffer);
+    if let Some(since) = resumed.disconnected_at {
+        for envelope in
+            session::fetch_missed_messages(repository, &resumed.username, &rooms, since).await?
+        {
+            if handle.missing_capability(&envelope.message).is_none() {
+                handle.send_envelope(envelope).await?;
+            }
+        }
+    }
+    direct::deliver_pending(repository, handle, &resumed.username).await
+}
+
+/// Checks the password of a client logging in, answering it with an error
+/// if the login is refused
+///
+/// # Arguments
+///
+/// * `repository` - Where users and messages are stored.
+/// * `guard` - The failed logins tracked by the server.
+/// * `addr` - The client's socket address.
+/// * `handle` - The handle used to send messages to the client.
+/// * `request_id` - The ID of the client's login message.
+/// * `username` - The username to log in as.
+/// * `password` - The password the client sent.
+///
+/// # Returns
+///
+/// Whether the client may log in.
+async fn check_password(
+    repository: &dyn ChatRepository,
+    guard: &LoginGuard,
+    addr: std::net::SocketAddr,
+    handle: &ClientHandle,
+    request_id: Uuid,
+    username: &str,
+    password: &Password,
+) -> Result<bool> {
+    match auth::authenticate(repository, guard, addr.ip(), username, password).await {
+        Ok(()) => Ok(true),
+        Err(LoginError::Other(e)) => Err(e),
+        Err(e) => {
+            warn!("Refused login as {} from {}: {}", username, addr, e);
+            report_error(handle, Some(request_id), &e.to_string()).await?;
+            Ok(false)
+        }
     }
+}
 
-    deserialize_message(&buffer).map_err(|e| anyhow::anyhow!(e))
+/// Reads the next message from the client that is not a heartbeat
+///
+/// Pings are answered with a pong and pongs are dropped; either only shows
+/// that the client is alive. Unless the handshake showed that the client
+/// does not support heartbeats, a client that sends nothing for the
+/// heartbeat timeout is considered gone, and a `FrameError::Io` error of
+/// kind `TimedOut` is returned (see `is_timeout`).
+///
+/// # Arguments
+///
+/// * `reader` - The read half of the client's TCP stream.
+/// * `handle` - The handle used to send messages to the client.
+async fn next_message(reader: &mut ClientReader, handle: &ClientHandle) -> Result<Envelope> {
+    loop {
+        let envelope = match handle.heartbeat {
+            Some(heartbeat) => match timeout(heartbeat.timeout(), read_message(reader)).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    return Err(FrameError::Io(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("no message for {} seconds", heartbeat.timeout().as_secs()),
+                    ))
+                    .into())
+                }
+            },
+            None => read_message(reader).await?,
+        };
+        match envelope.message {
+            MessageType::Ping => handle.reply(envelope.id, MessageType::Pong).await?,
+            MessageType::Pong => {}
+            _ => return Ok(envelope),
+        }
+    }
 }
 
-/// Sends a message to the client
+/// Checks whether reading from a client failed because it missed its
+/// heartbeats.
+fn is_timeout(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref(),
+        Some(FrameError::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut
+    )
+}
+
+/// Pings a client at the heartbeat interval
 ///
-/// This function serializes a message and sends it to the client through
-/// the provided stream.
+/// Runs until the client's connection ends. Clients that have not
+/// negotiated heartbeats (yet) are not pinged.
 ///
 /// # Arguments
-/// * `stream` - The client's TCP stream wrapped in an Arc and Mutex.
-/// * `message` - The message to be sent.
-async fn send_message(stream: Arc<Mutex<TcpStream>>, message: &MessageType) -> Result<()> {
-    let serialized = serialize_message(message).map_err(|e| anyhow::anyhow!(e))?;
-    let len = serialized.len() as u32;
-    {
-        let mut stream = stream.lock().await;
-        stream
-            .write_all(&len.to_be_bytes())
-            .await
-            .context("Failed to send message length")?;
-        stream
-            .write_all(&serialized)
+///
+/// * `handle` - The handle used to send messages to the client.
+/// * `addr` - The client's socket address.
+/// * `clients` - A shared reference to the clients hashmap.
+async fn send_heartbeats(handle: &ClientHandle, addr: std::net::SocketAddr, clients: &Clients) {
+    let Some(heartbeat) = handle.heartbeat else {
+        return futures::future::pending().await;
+    };
+    loop {
+        sleep(heartbeat.interval).await;
+        let negotiated = clients
+            .lock()
             .await
-            .context("Failed to send message")?;
+            .get(&addr)
+            .is_some_and(|handle| handle.capabilities.contains(&Capability::Heartbeats));
+        if negotiated {
+            // A ping that does not fit the queue is not needed: a client that
+            // slow is disconnected anyway (see `broadcast_message`)
+            let _ = handle
+                .sender
+                .try_send(Arc::new(Envelope::new(MessageType::Ping).stamp(None)));
+        }
     }
-    Ok(())
+}
+
+/// Reads a message from the client
+///
+/// This function reads a message from the clitnt's stream, deserializes it,
+/// and returns the deserialized message in its envelope. A frame larger than
+/// the configured limit is rejected with `FrameError::TooLarge` before
+/// anything is allocated for it, and a closed connection is reported as a
+/// `FrameError::Io` error.
+///
+/// # Arguments
+///
+/// * `reader` - The read half of the client's TCP stream.
+async fn read_message(reader: &mut ClientReader) -> Result<Envelope> {
+    match reader.next().await {
+        Some(envelope) => Ok(envelope?),
+        None => Err(FrameError::Io(std::io::ErrorKind::UnexpectedEof.into()).into()),
+    }
+}
+
+/// Checks a message against the field limits, answering it with an error
+/// if it exceeds them
+///
+/// # Arguments
+///
+/// * `handle` - The handle used to send messages to the client.
+/// * `envelope` - The message received from the client in its envelope.
+/// * `limits` - The limits enforced on frames from the client.
+///
+/// # Returns
+///
+/// Whether the message is within the limits.
+async fn within_limits(
+    handle: &ClientHandle,
+    envelope: &Envelope,
+    limits: &FrameLimits,
+) -> Result<bool> {
+    match limits.check(&envelope.message) {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            warn!("Rejected message {}: {}", envelope.id, e);
+            report_error(handle, Some(envelope.id), &e.to_string()).await?;
+            Ok(false)
+        }
+    }
+}
+
+/// Writes queued messages to the client
+///
+/// This function runs as the client's writer task. It drains the client's
+/// queues until every sender is dropped or a write fails, then shuts down
+/// the write half of the stream. The bulk queue is only drained while the
+/// outbound queue is empty. Once the `HelloAck` has been written,
+/// everything after it is written in the negotiated wire format and
+/// compression.
+///
+/// # Arguments
+/// * `writer` - The write half of the client's TCP stream.
+/// * `receiver` - The receiving side of the client's outbound queue.
+/// * `bulk` - The receiving side of the client's queue for relayed files.
+/// * `addr` - The client's socket address.
+async fn write_loop(
+    mut writer: ClientWriter,
+    mut receiver: mpsc::Receiver<Arc<Envelope>>,
+    mut bulk: mpsc::Receiver<Arc<Envelope>>,
+    addr: std::net::SocketAddr,
+) {
+    loop {
+        let envelope = tokio::select! {
+            biased;
+            Some(envelope) = receiver.recv() => envelope,
+            Some(envelope) = bulk.recv() => envelope,
+            else => break,
+        };
+        let negotiated = match envelope.message {
+            MessageType::HelloAck {
+                format,
+                compression,
+                ..
+            } => Some((format, compression)),
+            _ => None,
+        };
+        if let Err(e) = writer.send(envelope).await {
+            error!("Failed to send message to {}: {:?}", addr, e);
+            return;
+        }
+        if let Some((format, compression)) = negotiated {
+            writer.encoder_mut().set_format(format);
+            writer.encoder_mut().set_compression(compression);
+        }
+    }
+    let _ = SinkExt::<Arc<Envelope>>::close(&mut writer).await;
 }
 
 /// Handles messages from the client
 /// This function processes messages from the client, including handling
-/// text, image, and file messages, as well as the quit message.
+/// text, image, and file messages, as well as the quit message. Messages
+/// are stamped with the sender's username and the server time before they
+/// are stored or relayed.
 ///
 /// # Arguments
 ///
 /// * `addr` - The client's socket address.
-/// * `message` - The message received from the client.
+/// * `envelope` - The message received from the client in its envelope.
+/// * `handle` - The handle used to send messages to the client.
+/// * `uploads` - The chunked uploads in progress on the connection.
 /// * `clients` - A shared reference to the clients hashmap.
-/// * `db_pool` - The PostgreSQL connection pool.
+/// * `repository` - Where users and messages are stored.
+/// * `sessions` - The sessions of the server.
 async fn handle_message(
     addr: std::net::SocketAddr,
-    message: MessageType,
+    envelope: Envelope,
+    handle: &ClientHandle,
+    uploads: &mut Uploads,
     clients: Clients,
-    db_pool: Arc<Pool<Postgres>>,
+    repository: Arc<dyn ChatRepository>,
+    sessions: &Sessions,
 ) -> Result<bool> {
     let username = {
         let clients = clients.lock().await;
-        if let Some((_, username)) = clients.get(&addr) {
-            username.clone()
+        if let Some(handle) = clients.get(&addr) {
+            handle.username.clone()
         } else {
             return Err(anyhow::anyhow!("User not logged in"));
         }
     };
 
-    match message {
+    let envelope = envelope.stamp(Some(&username));
+    match envelope.message {
         MessageType::Quit => {
             info!("User {} ({}) sent quit message", username, addr);
-            clients.lock().await.remove(&addr);
+            if let Some(id) = clients
+                .lock()
+                .await
+                .remove(&addr)
+                .and_then(|handle| handle.session)
+            {
+                sessions.end(id);
+            }
+            presence::announce(&clients, addr, &username, PresenceEvent::LoggedOut).await;
             return Ok(true);
         }
-        MessageType::Text(text) => {
+        MessageType::Text(ref text) => {
             info!("Text message from {}: {}", username, text);
-            save_message(&db_pool, username.to_string(), text).await?;
+            save_message(repository.as_ref(), &envelope, text, None).await?;
+            broadcast_message(&clients, addr, envelope).await;
         }
-        MessageType::Image(data) => {
-            ensure_directories_exist().await?;
+        MessageType::Image(data, sha256) => {
             info!("Receiving image from {}...", username);
-            let timestamp = Utc::now().timestamp();
-            let filename = format!("images/{}.png", timestamp);
-
-            // Convert image to PNG format
-            let data_clone = data.clone();
-            let filename_clone = filename.clone();
-            task::spawn_blocking(move || {
-                let image = image::load_from_memory(&data_clone)
-                    .context("Failed to load image from memory")?;
-                let mut output_file = std::fs::File::create(&filename_clone)
-                    .context("Failed to create image file")?;
-                image
-                    .write_to(&mut output_file, ImageFormat::Png)
-                    .context("Failed to write image as PNG")?;
-                Ok::<(), anyhow::Error>(())
+
+            // Verify the image, then check and decode it
+            let config = Arc::clone(&handle.images);
+            let decoded = task::spawn_blocking(move || {
+                if sha256_hex(&data) != sha256 {
+                    return Err(anyhow::anyhow!(
+                        "Checksum mismatch for image, please send it again"
+                    ));
+                }
+                images::decode_image(&data, &config)
             })
             .await??;
 
-            info!("Saved image to {}", filename);
+            let (attachment, data) = store_image(repository.as_ref(), handle, decoded).await?;
+            let envelope = Envelope {
+                message: MessageType::Image(data, attachment.sha256.clone()),
+                ..envelope
+            };
+            repository.save_attachment(&envelope, &attachment).await?;
+            broadcast_message(&clients, addr, envelope).await;
         }
-        MessageType::File(name, data) => {
-            ensure_directories_exist().await?;
+        MessageType::File(name, data, sha256) => {
             info!("Receiving file '{}' from {}...", name, addr);
-            let filename = format!("files/{}", name);
-            fs::write(&filename, &data)
-                .await
-                .context("Failed to save file")?;
-            info!("Saved file to {}", filename);
+            if sha256_hex(&data) != sha256 {
+                return Err(anyhow::anyhow!(
+                    "Checksum mismatch for file '{}', please send it again",
+                    name
+                ));
+            }
+            let name = sanitize_file_name(&name, attachments::MAX_NAME_LEN);
+            let attachment = Attachment::file(
+                Uuid::new_v4(),
+                name.clone(),
+                data.len() as u64,
+                sha256.clone(),
+            )?;
+            handle.storage.put(&attachment.storage_path, &data).await?;
+            info!("Saved file '{}' as {}", name, attachment.storage_path);
+            let envelope = Envelope {
+                message: MessageType::File(name, data, sha256),
+                ..envelope
+            };
+            repository.save_attachment(&envelope, &attachment).await?;
+            broadcast_message(&clients, addr, envelope).await;
+        }
+        MessageType::FileStart {
+            transfer_id,
+            name,
+            size,
+            sha256,
+        } => {
+            info!(
+                "Receiving file '{}' ({} bytes) from {}...",
+                name, size, username
+            );
+            let name = sanitize_file_name(&name, attachments::MAX_NAME_LEN);
+            let offset = uploads
+                .start(&username, transfer_id, name, size, sha256)
+                .await?;
+            let reply = MessageType::FileOffset {
+                transfer_id,
+                offset,
+            };
+            handle.reply(envelope.id, reply).await?;
+        }
+        MessageType::FileChunk {
+            transfer_id,
+            offset,
+            data,
+        } => {
+            uploads.write_chunk(transfer_id, offset, &data).await?;
+        }
+        MessageType::FileFinish { transfer_id } => {
+            let upload = uploads.finish(transfer_id, handle.storage.as_ref()).await?;
+            info!("Saved file '{}' as {}", upload.name, upload.key);
+            let attachment = Attachment::file(
+                upload.id,
+                upload.name.clone(),
+                upload.size,
+                upload.sha256.clone(),
+            )?;
+            repository.save_attachment(&envelope, &attachment).await?;
+            let reply = MessageType::Text(format!("File '{}' received", upload.name));
+            handle.reply(envelope.id, reply).await?;
+            relay_upload(&clients, addr, &username, upload).await;
+        }
+        MessageType::FileOffset { .. } => {
+            error!("Received unexpected file offset from {}", addr);
+        }
+        MessageType::Join(ref room) => {
+            if !is_valid_room_name(room) {
+                return Err(anyhow::anyhow!(
+                    "Invalid room name '{}': use letters, digits, '-' and '_'",
+                    room
+                ));
+            }
+            let room_id = repository.ensure_room(room).await?;
+            rooms::join(&clients, addr, room).await;
+            info!("User {} joined room {}", username, room);
+            let reply = MessageType::Text(format!("Joined room '{}'", room));
+            handle.reply(envelope.id, reply).await?;
+            for past in rooms::fetch_room_history(repository.as_ref(), room, room_id).await? {
+                handle.send_envelope(past).await?;
+            }
+        }
+        MessageType::Leave(ref room) => {
+            if !rooms::leave(&clients, addr, room).await {
+                return Err(anyhow::anyhow!("You are not in room '{}'", room));
+            }
+            info!("User {} left room {}", username, room);
+            let reply = MessageType::Text(format!("Left room '{}'", room));
+            handle.reply(envelope.id, reply).await?;
+        }
+        MessageType::RoomMessage { ref room, ref body } => {
+            if !rooms::is_member(&clients, addr, room).await {
+                return Err(anyhow::anyhow!("You are not in room '{}'", room));
+            }
+            info!("Message from {} to room {}: {}", username, room, body);
+            let room_id = repository
+                .room_id(room)
+                .await?
+                .with_context(|| format!("Room '{}' does not exist", room))?;
+            save_message(repository.as_ref(), &envelope, body, Some(room_id)).await?;
+            broadcast_message(&clients, addr, envelope).await;
+        }
+        MessageType::ListRooms => {
+            let reply = MessageType::Rooms(repository.list_rooms().await?);
+            handle.reply(envelope.id, reply).await?;
+        }
+        MessageType::Rooms(_) => {
+            error!("Received unexpected room list from {}", addr);
+        }
+        MessageType::Direct { ref to, ref body } => {
+            if !repository.user_exists(to).await? {
+                return Err(anyhow::anyhow!("User '{}' does not exist", to));
+            }
+            info!("Direct message from {} to {}", username, to);
+            let delivered = broadcast_message(&clients, addr, envelope.clone()).await > 0;
+            direct::save_direct_message(repository.as_ref(), &envelope, to, body, delivered)
+                .await?;
+            let reply = if delivered {
+                MessageType::Text(format!("Message to {} sent", to))
+            } else {
+                MessageType::Text(format!(
+                    "{} is offline, the message will be delivered when they log in",
+                    to
+                ))
+            };
+            handle.reply(envelope.id, reply).await?;
         }
         MessageType::Error(err) => {
             error!("Error from {}: {}", addr, err);
         }
-        MessageType::Login(_) => {
+        MessageType::Login { .. } => {
             error!("Received login message after user is already logged in");
         }
-        MessageType::Register(_) => {
+        MessageType::Register { .. } => {
             error!("Received register message after user is already logged in")
         }
+        MessageType::Hello { .. } | MessageType::HelloAck { .. } => {
+            error!("Received handshake message after handshake from {}", addr);
+        }
+        MessageType::Resume(_) => {
+            error!("Received resume message after user is already logged in")
+        }
+        MessageType::Ping | MessageType::Pong => {} // answered in `next_message`
+        MessageType::Who => {
+            let users = presence::online_users(&clients).await;
+            handle.reply(envelope.id, MessageType::Users(users)).await?;
+        }
+        MessageType::SetStatus(status) => {
+            info!("User {} set their status to {}", username, status);
+            presence::set_status(&clients, addr, status).await;
+            let reply = MessageType::Text(format!("Your status is now {}", status));
+            handle.reply(envelope.id, reply).await?;
+            presence::announce(
+                &clients,
+                addr,
+                &username,
+                PresenceEvent::StatusChanged(status),
+            )
+            .await;
+        }
+        MessageType::FetchHistory {
+            before,
+            since,
+            limit,
+        } => {
+            let history = fetch_messages(
+                repository.as_ref(),
+                handle.storage.as_ref(),
+                before,
+                since,
+                limit.min(MAX_HISTORY_PAGE),
+            )
+            .await?;
+            handle
+                .reply(envelope.id, MessageType::History(history))
+                .await?;
+        }
+        MessageType::Users(_) => {
+            error!("Received unexpected user list from {}", addr);
+        }
+        MessageType::History(_) => {
+            error!("Received unexpected message history from {}", addr);
+        }
+        MessageType::Presence { .. } => {
+            error!("Received unexpected presence event from {}", addr);
+        }
+        MessageType::SessionToken(_) => {
+            error!("Received session token from client {}", addr);
+        }
     }
     Ok(false)
 }
 
-/// Checks if a user exists in the database
+/// Broadcasts a message to all other logged-in clients
 ///
-/// This function queries the database to check if a user with the username exists.
+/// This function queues the stamped envelope for every client except the
+/// sender. Connections that have not logged in yet are skipped, and so are
+/// clients that did not negotiate the capability the message needs and
+/// clients the message is not meant for: room messages go to the room's
+/// members and direct messages to the recipient's sessions. A client whose
+/// outbound queue is full is too slow to keep up and gets disconnected.
 ///
 /// # Arguments
 ///
-/// * `db_pool` - The PostgreSQL connection pool.
-/// # `username` - The username to check.
-async fn user_exists(db_pool: &Pool<Postgres>, username: &str) -> Result<bool> {
-    let result = sqlx::query!("SELECT id FROM users WHERE username = $1", username)
-        .fetch_optional(db_pool)
-        .await?;
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `sender_addr` - The socket address of the sending client.
+/// * `envelope` - The stamped message to be broadcast.
+///
+/// # Returns
+///
+/// The number of clients the message was queued for.
+async fn broadcast_message(
+    clients: &Clients,
+    sender_addr: std::net::SocketAddr,
+    envelope: Envelope,
+) -> usize {
+    let broadcast = Arc::new(envelope);
 
-    Ok(result.is_some())
+    let clients = clients.lock().await;
+    let mut slow_clients = Vec::new();
+    let mut recipients = 0;
+    for (addr, handle) in clients.iter() {
+        if *addr == sender_addr
+            || handle.username.is_empty()
+            || handle.missing_capability(&broadcast.message).is_some()
+            || !handle.receives(&broadcast.message)
+        {
+            continue;
+        }
+        match handle.sender.try_send(Arc::clone(&broadcast)) {
+            Ok(()) => recipients += 1,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("Outbound queue of {} is full, disconnecting", addr);
+                slow_clients.push(*addr);
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                // The client is already shutting down and removes itself
+            }
+        }
+    }
+
+    // The clients' own tasks remove them, announcing their departure
+    for addr in slow_clients {
+        if let Some(handle) = clients.get(&addr) {
+            handle.disconnect.notify_one();
+        }
+    }
+    recipients
+}
+
+/// Relays a completed upload to all other logged-in clients
+///
+/// Every client that negotiated chunked file transfers gets the file
+/// streamed by its own relay task.
+///
+/// # Arguments
+///
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `sender_addr` - The socket address of the uploading client.
+/// * `sender` - The username of the uploading client.
+/// * `upload` - The completed upload.
+async fn relay_upload(
+    clients: &Clients,
+    sender_addr: std::net::SocketAddr,
+    sender: &str,
+    upload: CompletedUpload,
+) {
+    let upload = Arc::new(upload);
+    for (addr, handle) in clients.lock().await.iter() {
+        if *addr == sender_addr
+            || handle.username.is_empty()
+            || !handle.capabilities.contains(&Capability::ChunkedFiles)
+        {
+            continue;
+        }
+        let handle = handle.clone();
+        let sender = sender.to_string();
+        let upload = Arc::clone(&upload);
+        task::spawn(async move { relay_file(handle, sender, &upload).await });
+    }
 }
 
 /// Registers a new user in the database
 ///
-/// This function inserts a new user with the specified username into the
-/// database.
+/// This function inserts a new user with the specified username and
+/// password hash into the database.
 ///
 /// # Arguments
-/// * `db_pool` - The PostgreSQL connection pool.
+/// * `repository` - Where users and messages are stored.
 /// * `username` - The username to register.
-async fn register_user(db_pool: &Pool<Postgres>, username: &str) -> Result<()> {
-    let result = sqlx::query!("INSERT INTO users (username) VALUES ($1)", username)
-        .execute(db_pool)
-        .await;
+/// * `password_hash` - The Argon2 hash of the user's password.
+async fn register_user(
+    repository: &dyn ChatRepository,
+    username: &str,
+    password_hash: &str,
+) -> Result<()> {
+    let result = repository.register_user(username, password_hash).await;
 
     match result {
         Ok(_) => {
@@ -299,115 +1229,210 @@ async fn register_user(db_pool: &Pool<Postgres>, username: &str) -> Result<()> {
         }
         Err(e) => {
             error!("Failed to register user {}: {:?}", username, e);
-            Err(e.into())
+            Err(e)
         }
     }
 }
 
 /// Saves message to the database
 ///
-/// This function saves a message to the database with the associated user ID.
+/// This function saves a message to the database with the associated user ID,
+/// using the message ID and timestamp from the envelope stamped by the server.
 ///
 /// # Arguments
-/// * `db_pool` - The PostgreSQL connection pool.
-/// * `username` - The username of the user sending message.
+/// * `repository` - Where users and messages are stored.
+/// * `envelope` - The stamped envelope of the message.
 /// * `content` - The content of the message.
-async fn save_message(db_pool: &Pool<Postgres>, username: String, content: String) -> Result<()> {
-    // Find user_id according to username
-    let user_id_result = sqlx::query!("SELECT id FROM users WHERE username = $1", username)
-        .fetch_one(db_pool)
-        .await;
-
-    let user_id = match user_id_result {
-        Ok(record) => record.id,
-        Err(e) => {
-            error!("Failed to find user_id for username {}: {:?}", username, e);
-            return Err(e.into());
+/// * `room_id` - The ID of the room the message was posted to, `None` for
+///   messages to everybody.
+async fn save_message(
+    repository: &dyn ChatRepository,
+    envelope: &Envelope,
+    content: &str,
+    room_id: Option<i32>,
+) -> Result<()> {
+    repository.save_message(envelope, content, room_id).await?;
+    info!(
+        "Message saved to database for user: {}",
+        envelope.sender.as_deref().unwrap_or_default()
+    );
+    Ok(())
+}
+
+/// Stores an uploaded image with its thumbnails
+///
+/// An image that is a near-duplicate of one stored before is not stored
+/// again, its attachment refers to the earlier image instead. Returns the
+/// attachment to record and the stored image to relay.
+///
+/// # Arguments
+///
+/// * `repository` - Where users and messages are stored.
+/// * `handle` - The handle of the client that sent the image.
+/// * `decoded` - The checked and decoded image.
+async fn store_image(
+    repository: &dyn ChatRepository,
+    handle: &ClientHandle,
+    decoded: DecodedImage,
+) -> Result<(Attachment, Vec<u8>)> {
+    let dimensions = (decoded.image.width(), decoded.image.height());
+    if let Some(max_distance) = handle.images.duplicate_distance {
+        let duplicate = repository
+            .find_duplicate(dimensions, decoded.phash, max_distance)
+            .await?;
+        if let Some(attachment) = duplicate {
+            match read_stored(handle.storage.as_ref(), &attachment.storage_path).await {
+                Ok(data) => {
+                    info!(
+                        "Image is a near-duplicate of {}, not storing it again",
+                        attachment.storage_path
+                    );
+                    return Ok((attachment, data));
+                }
+                Err(e) => warn!(
+                    "Failed to read duplicate image {}: {}",
+                    attachment.storage_path, e
+                ),
+            }
         }
-    };
+    }
 
-    // Convert user_id to &str
-    //  let user_id_str = user_id.to_string();
+    let config = Arc::clone(&handle.images);
+    let image = task::spawn_blocking(move || images::encode_image(decoded, &config)).await??;
+    let attachment = Attachment::image(Uuid::new_v4(), &image)?;
+    handle
+        .storage
+        .put(&attachment.storage_path, &image.data)
+        .await?;
+    for (thumbnail, processed) in attachment.thumbnails.iter().zip(&image.thumbnails) {
+        if thumbnail.storage_path != attachment.storage_path {
+            handle
+                .storage
+                .put(&thumbnail.storage_path, &processed.data)
+                .await?;
+        }
+    }
+    info!(
+        "Saved image as {} with {} thumbnails",
+        attachment.storage_path,
+        attachment.thumbnails.len()
+    );
+    Ok((attachment, image.data))
+}
 
-    // Save message with correct user_id
-    let result = sqlx::query!(
-        "INSERT INTO messages (user_id, content) VALUES ($1, $2)",
-        user_id,
-        content
-    )
-    .execute(db_pool)
-    .await;
+/// Fetches a page of public messages from the database
+///
+/// Returns up to `limit` messages posted to everybody before `before` and
+/// after `since`, the latest ones if there are more. The messages are
+/// returned oldest first as envelopes carrying their original ID, sender
+/// and timestamp: images as `MessageType::Image` with their smallest
+/// thumbnail, everything else as `MessageType::Text`.
+///
+/// # Arguments
+///
+/// * `repository` - Where users and messages are stored.
+/// * `storage` - Where the thumbnails of images are kept.
+/// * `before` - Only messages posted before this time, if given.
+/// * `since` - Only messages posted after this time, if given.
+/// * `limit` - The maximum number of messages.
+async fn fetch_messages(
+    repository: &dyn ChatRepository,
+    storage: &dyn Storage,
+    before: Option<DateTime<Utc>>,
+    since: Option<DateTime<Utc>>,
+    limit: u32,
+) -> Result<Vec<Envelope>> {
+    let rows = repository.public_messages(before, since, limit).await?;
 
-    match result {
-        Ok(_) => {
-            info!("Message saved to database for user: {}", username);
-            Ok(())
-        }
-        Err(e) => {
-            error!("Failed to save message for user {}: {:?}", username, e);
-            Err(e.into())
-        }
+    let mut messages = Vec::with_capacity(rows.len());
+    for row in rows.into_iter().rev() {
+        let thumbnail = match &row.thumbnail {
+            Some(key) => match read_stored(storage, key).await {
+                Ok(data) => Some(data),
+                Err(e) => {
+                    warn!("Failed to read thumbnail {}: {}", key, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let message = match thumbnail {
+            Some(data) => {
+                let sha256 = sha256_hex(&data);
+                MessageType::Image(data, sha256)
+            }
+            None => MessageType::Text(row.content),
+        };
+        messages.push(Envelope {
+            id: row.message_id.unwrap_or_else(Uuid::new_v4),
+            timestamp: row.timestamp.map(|timestamp| timestamp.and_utc()),
+            sender: Some(row.username),
+            correlation_id: None,
+            message,
+        });
     }
+    Ok(messages)
+}
+
+/// Reads the whole content stored under a key
+///
+/// # Arguments
+///
+/// * `storage` - The storage the content is kept in.
+/// * `key` - The key the content is stored under.
+async fn read_stored(storage: &dyn Storage, key: &str) -> Result<Vec<u8>> {
+    let mut reader = storage.open(key).await?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await?;
+    Ok(data)
 }
 
-/// Fetches all messages from the database
+/// Sends a client that just logged in the latest public messages
 ///
-/// This function retrieves all messages from the database and rerurns them as a vector.
+/// Clients that did not negotiate history are skipped, as are all clients
+/// when the server is configured to send no history.
 ///
 /// # Arguments
 ///
-/// * `db_pool` - The PostgreSQL connection pool.
-async fn _fetch_messages(db_pool: &Pool<Postgres>) -> Result<Vec<(String, String, NaiveDateTime)>> {
-    let rows = sqlx::query!(
-        r#"
-        SELECT users.username, messages.content, messages.timestamp
-        FROM messages
-        JOIN users ON messages.user_id = users.id
-        "#
+/// * `repository` - Where users and messages are stored.
+/// * `handle` - The handle used to send messages to the client.
+async fn send_recent_history(repository: &dyn ChatRepository, handle: &ClientHandle) -> Result<()> {
+    if handle.history_length == 0 || !handle.capabilities.contains(&Capability::History) {
+        return Ok(());
+    }
+    let history = fetch_messages(
+        repository,
+        handle.storage.as_ref(),
+        None,
+        None,
+        handle.history_length,
     )
-    .fetch_all(db_pool)
-    .await;
-
-    match rows {
-        Ok(rows) => {
-            let messages = rows
-                .into_iter()
-                .map(|row| (row.username, row.content, row.timestamp.unwrap()))
-                .collect::<Vec<_>>();
-            info!("Fetch messages from database: {:?}", messages);
-            Ok(messages)
-        }
-        Err(e) => {
-            error!("Failed to fetch messages: {:?}", e);
-            Err(e.into())
-        }
+    .await?;
+    if history.is_empty() {
+        return Ok(());
     }
+    handle.send(MessageType::History(history)).await
 }
 
 /// Reports an error to the client
 ///
-/// This function sends an error message to the client.
+/// This function queues an error message for the client.
 ///
 /// # Arguments
-/// * `stream` - The client's TCP stream wrapped in an Arc and Mutex.
+/// * `handle` - The handle used to send messages to the client.
+/// * `correlation_id` - The ID of the client's message the error refers to, if any.
 /// # `error_message` - The error message to sent.
-async fn report_error(stream: Arc<Mutex<TcpStream>>, error_message: &str) -> Result<()> {
+async fn report_error(
+    handle: &ClientHandle,
+    correlation_id: Option<Uuid>,
+    error_message: &str,
+) -> Result<()> {
     let error_message = MessageType::Error(error_message.to_string());
-    let serialized = serialize_message(&error_message)?;
-    let len = serialized.len() as u32;
-    {
-        let mut stream = stream.lock().await;
-        stream
-            .write_all(&len.to_be_bytes())
-            .await
-            .context("Failed to send error length")?;
-        stream
-            .write_all(&serialized)
-            .await
-            .context("Failed to send error message")?;
-        info!("Sent error message: {:?}", error_message);
+    info!("Sent error message: {:?}", error_message);
+    match correlation_id {
+        Some(correlation_id) => handle.reply(correlation_id, error_message).await,
+        None => handle.send(error_message).await,
     }
-    Ok(())
 }
 
 /// Listens for and accepts incoming connections
@@ -418,44 +1443,207 @@ async fn report_error(stream: Arc<Mutex<TcpStream>>, error_message: &str) -> Res
 /// # Arguments
 ///
 /// * `address` - The address to bind the server to.
-/// * `db_pool` - The PostgreSQL connection pool.
-async fn listen_and_accept(address: &str, db_pool: Arc<Pool<Postgres>>) -> std::io::Result<()> {
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `repository` - Where users and messages are stored.
+/// * `config` - The server settings: frame limits, heartbeats and history.
+/// * `acceptor` - The TLS acceptor, or `None` to speak plain TCP.
+/// * `storage` - Where uploaded images and files are kept.
+async fn listen_and_accept(
+    address: &str,
+    clients: Clients,
+    repository: Arc<dyn ChatRepository>,
+    config: Config,
+    acceptor: Option<TlsAcceptor>,
+    storage: Arc<dyn Storage>,
+) -> std::io::Result<()> {
+    let Config {
+        limits,
+        heartbeat,
+        history_length,
+        images,
+        uploads,
+        ..
+    } = config;
+    let images = Arc::new(images);
+    let uploads = Arc::new(uploads);
+    task::spawn(expire_partial_uploads(Arc::clone(&uploads)));
     let listener = TcpListener::bind(address).await?;
-    info!("Server running on {}", address);
+    match acceptor {
+        Some(_) => info!("Server running on {} with TLS", address),
+        None => info!("Server running on {} without TLS", address),
+    }
 
-    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+    let guard = Arc::new(LoginGuard::default());
+    let sessions = Arc::new(Sessions::default());
 
     loop {
         let (stream, addr) = listener.accept().await?;
-        let stream = Arc::new(Mutex::new(stream));
-        clients
-            .lock()
-            .await
-            .insert(addr, (stream.clone(), String::new()));
-
+        let acceptor = acceptor.clone();
         let clients = Arc::clone(&clients);
-        let db_pool = db_pool.clone();
+        let repository = repository.clone();
+        let guard = Arc::clone(&guard);
+        let sessions = Arc::clone(&sessions);
+        let storage = Arc::clone(&storage);
+        let images = Arc::clone(&images);
+        let uploads = Arc::clone(&uploads);
         task::spawn(async move {
-            if let Err(e) = handle_client(stream, addr, clients, db_pool).await {
+            // The TLS handshake runs in the client's task, so a slow client
+            // cannot hold up accepting others
+            let (reader, writer) = match tls::accept(acceptor.as_ref(), stream).await {
+                Ok(halves) => halves,
+                Err(e) => {
+                    warn!("TLS handshake with {} failed: {}", addr, e);
+                    return;
+                }
+            };
+            let reader = FramedRead::new(reader, EnvelopeCodec::new(limits));
+            let writer = FramedWrite::new(writer, EnvelopeCodec::new(limits));
+            let (sender, receiver) = mpsc::channel(OUTBOUND_QUEUE_CAPACITY);
+            let (bulk, bulk_receiver) = mpsc::channel(BULK_QUEUE_CAPACITY);
+            task::spawn(write_loop(writer, receiver, bulk_receiver, addr));
+
+            let handle = ClientHandle {
+                sender,
+                bulk,
+                username: String::new(),
+                capabilities: Vec::new(),
+                rooms: HashSet::new(),
+                disconnect: Arc::new(Notify::new()),
+                session: None,
+                heartbeat: Some(heartbeat),
+                status: Status::Online,
+                history_length,
+                storage,
+                images,
+                uploads,
+            };
+            clients.lock().await.insert(addr, handle.clone());
+
+            if let Err(e) =
+                handle_client(reader, handle, addr, clients, repository, guard, sessions).await
+            {
                 error!("Error handling client {}: {:?}", addr, e);
             }
         });
     }
 }
 
-// Ensures necessary directories exist
+/// Generates a self-signed certificate for development
 ///
-/// This function checks if the directories for storing images and files exist,
-/// and creates them if they do not.
-async fn ensure_directories_exist() -> Result<()> {
-    let paths = ["images", "files"];
-    for path in &paths {
-        if !Path::new(path).exists() {
-            fs::create_dir(path)
-                .await
-                .context(format!("Failed to create directory {}", path))?;
+/// Handles `server gen-cert [dir] [hostname...]`: the certificate and key
+/// are written to `dir` (default `certs`) and are valid for the given host
+/// names, or for `localhost` and `127.0.0.1`.
+///
+/// # Arguments
+///
+/// * `args` - The arguments following `gen-cert`.
+async fn generate_certificate(args: &[String]) -> Result<()> {
+    let dir = Path::new(args.first().map(String::as_str).unwrap_or("certs"));
+    let hostnames = match args.get(1..) {
+        Some(hostnames) if !hostnames.is_empty() => hostnames.to_vec(),
+        _ => vec!["localhost".to_string(), "127.0.0.1".to_string()],
+    };
+    let fingerprint = tls::generate_dev_certificate(dir, hostnames.clone()).await?;
+    println!(
+        "Wrote a self-signed certificate for {} to {}",
+        hostnames.join(", "),
+        dir.display()
+    );
+    println!("SHA-256 fingerprint: {}", fingerprint);
+    println!(
+        "Start the server with TLS_CERT_FILE={0}/cert.pem TLS_KEY_FILE={0}/key.pem",
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Applies or lists the schema migrations
+///
+/// Handles `server migrate`, which applies the migrations the database is
+/// missing, and `server migrate status`, which lists them. Both fail when
+/// the database cannot be migrated by this server, e.g. because a newer
+/// server migrated it.
+///
+/// # Arguments
+///
+/// * `args` - The arguments following `migrate`.
+async fn migrate_database(args: &[String]) -> Result<()> {
+    let database_url = env::var("DATABASE_URL").context("DATABASE_URL must be set")?;
+    let repository = repository::connect(&database_url).await?;
+    let status = repository.migration_status().await?;
+    match args.first().map(String::as_str) {
+        None => {
+            status.check()?;
+            let pending = status.pending();
+            if pending > 0 {
+                repository.migrate().await?;
+            }
+            let status = repository.migration_status().await?;
+            println!(
+                "Applied {} migrations, the database schema is at version {}",
+                pending,
+                status.version().unwrap_or_default()
+            );
+        }
+        Some("status") => {
+            for migration in &status.migrations {
+                let state = match migration.applied {
+                    true if status.modified.contains(&migration.version) => "modified",
+                    true if status.dirty == Some(migration.version) => "failed",
+                    true => "applied",
+                    false => "pending",
+                };
+                println!(
+                    "{:>6}  {:<8}  {}",
+                    migration.version, state, migration.description
+                );
+            }
+            for version in &status.unknown {
+                println!(
+                    "{:>6}  {:<8}  (applied by a newer server)",
+                    version, "unknown"
+                );
+            }
+            status.check()?;
+            println!("{} pending migrations", status.pending());
+        }
+        Some(command) => anyhow::bail!(
+            "Unknown command 'migrate {}', use `migrate` or `migrate status`",
+            command
+        ),
+    }
+    Ok(())
+}
+
+/// Checks the database schema before the server starts
+///
+/// Fails if the database was migrated by a newer server or a migration
+/// went wrong, and applies pending migrations or fails, depending on
+/// `AUTO_MIGRATE`.
+///
+/// # Arguments
+///
+/// * `repository` - Where users and messages are stored.
+/// * `auto_migrate` - Whether to apply pending migrations.
+async fn prepare_schema(repository: &dyn ChatRepository, auto_migrate: bool) -> Result<()> {
+    let status = repository.migration_status().await?;
+    status.check()?;
+    match status.pending() {
+        0 => {}
+        pending if auto_migrate => {
+            info!("Applying {} database migrations", pending);
+            repository.migrate().await?;
         }
+        pending => anyhow::bail!(
+            "The database is missing {} migrations, apply them with `server migrate`",
+            pending
+        ),
     }
+    let status = repository.migration_status().await?;
+    info!(
+        "Database schema is at version {}",
+        status.version().unwrap_or_default()
+    );
     Ok(())
 }
 
@@ -471,8 +1659,31 @@ async fn main() -> Result<()> {
         .init();
 
     let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("gen-cert") {
+        return generate_certificate(&args[2..]).await;
+    }
+    if args.get(1).map(String::as_str) == Some("migrate") {
+        return migrate_database(&args[2..]).await;
+    }
+
+    let config = Config::from_env()?;
+    info!("Frame limits: {:?}", config.limits);
+    info!(
+        "Pinging clients every {} seconds, dropping them after {} missed heartbeats",
+        config.heartbeat.interval.as_secs(),
+        config.heartbeat.max_missed
+    );
+    let acceptor = config
+        .tls
+        .as_ref()
+        .map(|files| tls::load_acceptor(&files.cert, &files.key))
+        .transpose()?;
+
     let address = if args.len() < 2 {
-        println!("Usage: {} <address>", args[0]);
+        println!(
+            "Usage: {} <address> | gen-cert [dir] [hostname...] | migrate [status]",
+            args[0]
+        );
         println!("Setting default: localhost:11111");
         "localhost:11111".to_string()
     } else {
@@ -481,25 +1692,48 @@ async fn main() -> Result<()> {
 
     // Connect to database
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let db_pool = Arc::new(
-        PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await
-        .context("Failed to connect to the database")?,
-    );
+    let repository = repository::connect(&database_url).await?;
+    prepare_schema(repository.as_ref(), config.auto_migrate).await?;
+
+    let storage: Arc<dyn Storage> = match &config.storage {
+        StorageConfig::Local(dir) => {
+            info!("Storing images and files in {}", dir.display());
+            Arc::new(LocalStorage::new(dir).await?)
+        }
+        StorageConfig::S3(s3) => {
+            info!(
+                "Storing images and files in bucket {} at {}",
+                s3.bucket, s3.endpoint
+            );
+            Arc::new(S3Storage::connect(s3).await?)
+        }
+    };
+
+    // Shared with the web server, which lists the users who are online
+    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
 
     let tcp_server_address = address.clone();
-    let tcp_server_db_pool = Arc::clone(&db_pool);
+    let tcp_server_clients = Arc::clone(&clients);
+    let tcp_server_repository = Arc::clone(&repository);
+    let tcp_server_storage = Arc::clone(&storage);
     let tcp_server = task::spawn(async move {
-        if let Err(e) = listen_and_accept(&tcp_server_address, tcp_server_db_pool).await {
+        if let Err(e) = listen_and_accept(
+            &tcp_server_address,
+            tcp_server_clients,
+            tcp_server_repository,
+            config,
+            acceptor,
+            tcp_server_storage,
+        )
+        .await
+        {
             error!("Error: {}", e);
         }    
     });
     
-    let http_server_db_pool = Arc::clone(&db_pool);
+    let http_server_repository = Arc::clone(&repository);
     actix_rt::spawn(async move { 
-        if let Err(e) = web_server::run(http_server_db_pool).await {
+        if let Err(e) = web_server::run(http_server_repository, clients, storage).await {
             error!("HTTP Server Error: {}", e);
         }
     });
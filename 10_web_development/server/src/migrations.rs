@@ -0,0 +1,282 @@
+use anyhow::{Context, Result};
+use sqlx::migrate::{Migrate, MigrationType, Migrator};
+
+/// A migration the server knows of
+pub struct Migration {
+    pub version: i64,
+    pub description: String,
+    /// Whether the migration has been applied to the database.
+    pub applied: bool,
+}
+
+/// How the schema of a database compares to the migrations of the server
+pub struct MigrationStatus {
+    /// The migrations of the server, oldest first.
+    pub migrations: Vec<Migration>,
+    /// Versions applied to the database that the server does not know of,
+    /// because a newer server migrated it.
+    pub unknown: Vec<i64>,
+    /// Versions whose migration was changed after it was applied.
+    pub modified: Vec<i64>,
+    /// The version of a migration that failed partway through.
+    pub dirty: Option<i64>,
+}
+
+impl MigrationStatus {
+    /// Returns the latest applied migration the server knows of, `None` for
+    /// a database that has not been migrated yet.
+    pub fn version(&self) -> Option<i64> {
+        self.migrations
+            .iter()
+            .filter(|migration| migration.applied)
+            .map(|migration| migration.version)
+            .max()
+    }
+
+    /// Returns the number of migrations that have not been applied yet.
+    pub fn pending(&self) -> usize {
+        self.migrations
+            .iter()
+            .filter(|migration| !migration.applied)
+            .count()
+    }
+
+    /// Fails if the server cannot safely use or migrate the database
+    ///
+    /// That is the case when the database was migrated by a newer server,
+    /// when a migration failed partway through, or when an applied
+    /// migration was changed since.
+    pub fn check(&self) -> Result<()> {
+        if let Some(newest) = self.unknown.iter().max() {
+            anyhow::bail!(
+                "The database schema is at version {}, newer than this server knows ({}); upgrade the server",
+                newest,
+                self.migrations
+                    .last()
+                    .map_or("none".to_string(), |migration| migration.version.to_string())
+            );
+        }
+        if let Some(version) = self.dirty {
+            anyhow::bail!(
+                "Migration {} failed partway through; repair the database and delete its row from _sqlx_migrations",
+                version
+            );
+        }
+        if let Some(version) = self.modified.first() {
+            anyhow::bail!(
+                "Migration {} was changed after it was applied to the database",
+                version
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Compares the migrations applied to a database with those of the server
+///
+/// Creates the table that records applied migrations if it is missing.
+///
+/// # Arguments
+///
+/// * `migrator` - The migrations embedded in the server.
+/// * `connection` - A connection to the database.
+pub async fn status<C>(migrator: &Migrator, connection: &mut C) -> Result<MigrationStatus>
+where
+    C: Migrate,
+{
+    connection
+        .ensure_migrations_table()
+        .await
+        .context("Failed to create the migrations table")?;
+    let dirty = connection
+        .dirty_version()
+        .await
+        .context("Failed to read the applied migrations")?;
+    let applied = connection
+        .list_applied_migrations()
+        .await
+        .context("Failed to read the applied migrations")?;
+
+    let known: Vec<_> = migrator
+        .iter()
+        .filter(|migration| !matches!(migration.migration_type, MigrationType::ReversibleDown))
+        .collect();
+    let migrations = known
+        .iter()
+        .map(|migration| Migration {
+            version: migration.version,
+            description: migration.description.to_string(),
+            applied: applied
+                .iter()
+                .any(|applied| applied.version == migration.version),
+        })
+        .collect();
+    let unknown = applied
+        .iter()
+        .filter(|applied| !migrator.version_exists(applied.version))
+        .map(|applied| applied.version)
+        .collect();
+    let modified = applied
+        .iter()
+        .filter(|applied| {
+            known.iter().any(|migration| {
+                migration.version == applied.version && migration.checksum != applied.checksum
+            })
+        })
+        .map(|applied| applied.version)
+        .collect();
+    Ok(MigrationStatus {
+        migrations,
+        unknown,
+        modified,
+        dirty,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{Connection, SqliteConnection};
+
+    static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+    async fn connect() -> SqliteConnection {
+        SqliteConnection::connect("sqlite::memory:").await.unwrap()
+    }
+
+    async fn table_exists(connection: &mut SqliteConnection, name: &str) -> bool {
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = ?1)")
+            .bind(name)
+            .fetch_one(connection)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fresh_database() {
+        let mut connection = connect().await;
+        let current = status(&MIGRATOR, &mut connection).await.unwrap();
+        assert_eq!(current.version(), None);
+        assert_eq!(current.pending(), MIGRATOR.iter().count());
+        assert!(current.check().is_ok());
+
+        MIGRATOR.run(&mut connection).await.unwrap();
+        let current = status(&MIGRATOR, &mut connection).await.unwrap();
+        assert_eq!(
+            current.version(),
+            MIGRATOR.iter().map(|migration| migration.version).max()
+        );
+        assert_eq!(current.pending(), 0);
+        assert!(current.migrations.iter().all(|migration| migration.applied));
+        assert!(current.check().is_ok());
+        assert!(table_exists(&mut connection, "direct_messages").await);
+    }
+
+    #[tokio::test]
+    async fn test_schema_created_by_hand_is_adopted() {
+        let mut connection = connect().await;
+        sqlx::raw_sql(
+            r#"
+            CREATE TABLE users (
+                id INTEGER PRIMARY KEY,
+                username TEXT UNIQUE NOT NULL,
+                password_hash TEXT
+            );
+            CREATE TABLE messages (
+                id INTEGER PRIMARY KEY,
+                message_id BLOB UNIQUE,
+                user_id INTEGER REFERENCES users(id),
+                room_id INTEGER,
+                content TEXT NOT NULL,
+                timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+            INSERT INTO users (username, password_hash) VALUES ('alice', 'hash');
+            INSERT INTO messages (user_id, content) VALUES (1, 'hello');
+            "#,
+        )
+        .execute(&mut connection)
+        .await
+        .unwrap();
+        let current = status(&MIGRATOR, &mut connection).await.unwrap();
+        assert_eq!(current.version(), None);
+        assert!(current.check().is_ok());
+
+        MIGRATOR.run(&mut connection).await.unwrap();
+        let content: String = sqlx::query_scalar(
+            "SELECT content FROM messages JOIN users ON users.id = messages.user_id
+            WHERE users.username = 'alice'",
+        )
+        .fetch_one(&mut connection)
+        .await
+        .unwrap();
+        assert_eq!(content, "hello");
+        assert!(table_exists(&mut connection, "attachments").await);
+        assert_eq!(
+            status(&MIGRATOR, &mut connection).await.unwrap().pending(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_newer_database_is_refused() {
+        let mut connection = connect().await;
+        MIGRATOR.run(&mut connection).await.unwrap();
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations
+                (version, description, success, checksum, execution_time)
+            VALUES (9999, 'from the future', TRUE, X'00', 0)",
+        )
+        .execute(&mut connection)
+        .await
+        .unwrap();
+
+        let current = status(&MIGRATOR, &mut connection).await.unwrap();
+        assert_eq!(current.unknown, [9999]);
+        let error = current.check().unwrap_err().to_string();
+        assert!(error.contains("version 9999, newer than this server knows (1)"));
+        assert!(MIGRATOR.run(&mut connection).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dirty_database_is_refused() {
+        let mut connection = connect().await;
+        status(&MIGRATOR, &mut connection).await.unwrap();
+        let migration = MIGRATOR.iter().next().unwrap();
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations
+                (version, description, success, checksum, execution_time)
+            VALUES (?1, ?2, FALSE, ?3, 0)",
+        )
+        .bind(migration.version)
+        .bind(migration.description.as_ref())
+        .bind(migration.checksum.as_ref())
+        .execute(&mut connection)
+        .await
+        .unwrap();
+
+        let current = status(&MIGRATOR, &mut connection).await.unwrap();
+        assert_eq!(current.dirty, Some(migration.version));
+        let error = current.check().unwrap_err().to_string();
+        assert!(error.contains("failed partway through"));
+        assert!(MIGRATOR.run(&mut connection).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_changed_migration_is_refused() {
+        let mut connection = connect().await;
+        MIGRATOR.run(&mut connection).await.unwrap();
+        let version = MIGRATOR.iter().next().unwrap().version;
+        sqlx::query("UPDATE _sqlx_migrations SET checksum = X'00' WHERE version = ?1")
+            .bind(version)
+            .execute(&mut connection)
+            .await
+            .unwrap();
+
+        let current = status(&MIGRATOR, &mut connection).await.unwrap();
+        assert_eq!(current.modified, [version]);
+        assert!(current.unknown.is_empty());
+        let error = current.check().unwrap_err().to_string();
+        assert!(error.contains("was changed after it was applied"));
+        assert!(MIGRATOR.run(&mut connection).await.is_err());
+    }
+}
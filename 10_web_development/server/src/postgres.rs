@@ -0,0 +1,547 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use shared::Envelope;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::PgPool;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::attachments::Attachment;
+use crate::migrations::{self, MigrationStatus};
+use crate::repository::{
+    ChatRepository, DuplicateImage, ListedMessage, PendingDirectMessage, SimilarAttachment,
+    StoredAttachment, StoredMessage, StoredThumbnail, ThumbnailRow,
+};
+
+/// The migrations that build the schema, embedded at compile time.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// A repository in a PostgreSQL database
+///
+/// The schema is built by the migrations in `migrations/postgres`.
+/// Perceptual hashes are compared in the database with `bit_count`, which
+/// PostgreSQL has since version 14; older servers fail to look for
+/// duplicate and similar images.
+pub struct PgRepository {
+    pool: PgPool,
+}
+
+impl PgRepository {
+    /// Connects to a PostgreSQL database
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The `postgres://` URL of the database.
+    pub async fn connect(url: &str) -> Result<Self> {
+        // Migrations create everything "if not exists", which PostgreSQL
+        // would otherwise log a notice for on every start
+        let options = PgConnectOptions::from_str(url)
+            .context("Invalid PostgreSQL database URL")?
+            .options([("client_min_messages", "warning")]);
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await
+            .context("Failed to connect to the database")?;
+        Ok(PgRepository { pool })
+    }
+}
+
+#[async_trait]
+impl ChatRepository for PgRepository {
+    async fn migration_status(&self) -> Result<MigrationStatus> {
+        let mut connection = self.pool.acquire().await?;
+        migrations::status(&MIGRATOR, &mut *connection).await
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        MIGRATOR
+            .run(&self.pool)
+            .await
+            .context("Failed to migrate the database")
+    }
+
+    async fn user_exists(&self, username: &str) -> Result<bool> {
+        let result = sqlx::query("SELECT id FROM users WHERE username = $1")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(result.is_some())
+    }
+
+    async fn register_user(&self, username: &str, password_hash: &str) -> Result<()> {
+        sqlx::query("INSERT INTO users (username, password_hash) VALUES ($1, $2)")
+            .bind(username)
+            .bind(password_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn password_hash(&self, username: &str) -> Result<Option<String>> {
+        let password_hash =
+            sqlx::query_scalar("SELECT password_hash FROM users WHERE username = $1")
+                .bind(username)
+                .fetch_optional(&self.pool)
+                .await
+                .context("Failed to look up user")?;
+        Ok(password_hash.flatten())
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<Option<Vec<String>>> {
+        let mut tx = self.pool.begin().await?;
+        let user_id: Option<i32> = sqlx::query_scalar("SELECT id FROM users WHERE username = $1")
+            .bind(username)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(user_id) = user_id else {
+            return Ok(None);
+        };
+
+        sqlx::query("DELETE FROM direct_messages WHERE sender_id = $1 OR recipient_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        let mut storage_paths: Vec<String> = sqlx::query_scalar(
+            r#"
+            DELETE FROM thumbnails
+            WHERE attachment_id IN (SELECT id FROM attachments WHERE uploader_id = $1)
+            RETURNING storage_path
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        storage_paths.extend(
+            sqlx::query_scalar::<_, String>(
+                "DELETE FROM attachments WHERE uploader_id = $1 RETURNING storage_path",
+            )
+            .bind(user_id)
+            .fetch_all(&mut *tx)
+            .await?,
+        );
+        storage_paths.sort();
+        storage_paths.dedup();
+
+        // Equal content is stored once, other users may still share it
+        let mut unused = Vec::with_capacity(storage_paths.len());
+        for storage_path in storage_paths {
+            let shared: bool = sqlx::query_scalar(
+                r#"
+                SELECT EXISTS(SELECT 1 FROM attachments WHERE storage_path = $1)
+                    OR EXISTS(SELECT 1 FROM thumbnails WHERE storage_path = $1)
+                "#,
+            )
+            .bind(&storage_path)
+            .fetch_one(&mut *tx)
+            .await?;
+            if !shared {
+                unused.push(storage_path);
+            }
+        }
+
+        sqlx::query("DELETE FROM messages WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(unused))
+    }
+
+    async fn save_message(
+        &self,
+        envelope: &Envelope,
+        content: &str,
+        room_id: Option<i32>,
+    ) -> Result<()> {
+        let username = envelope
+            .sender
+            .as_deref()
+            .context("Message has no sender")?;
+        let timestamp = envelope.timestamp.context("Message has no timestamp")?;
+
+        let result = sqlx::query(
+            r#"
+            INSERT INTO messages (message_id, user_id, content, timestamp, room_id)
+            SELECT $1, users.id, $3, $4, $5 FROM users WHERE users.username = $2
+            "#,
+        )
+        .bind(envelope.id)
+        .bind(username)
+        .bind(content)
+        .bind(timestamp.naive_utc())
+        .bind(room_id)
+        .execute(&self.pool)
+        .await
+        .with_context(|| format!("Failed to save message for user {}", username))?;
+        if result.rows_affected() == 0 {
+            anyhow::bail!("User '{}' does not exist", username);
+        }
+        Ok(())
+    }
+
+    async fn public_messages(
+        &self,
+        before: Option<DateTime<Utc>>,
+        since: Option<DateTime<Utc>>,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>> {
+        sqlx::query_as(
+            r#"
+            SELECT messages.message_id, users.username, messages.content, messages.timestamp,
+                (SELECT storage_path FROM thumbnails
+                 WHERE thumbnails.attachment_id = attachments.id
+                 ORDER BY max_size
+                 LIMIT 1) AS thumbnail
+            FROM messages
+            JOIN users ON messages.user_id = users.id
+            LEFT JOIN attachments ON attachments.message_id = messages.id
+            WHERE messages.room_id IS NULL
+                AND ($1::timestamp IS NULL OR messages.timestamp < $1)
+                AND ($2::timestamp IS NULL OR messages.timestamp > $2)
+            ORDER BY messages.timestamp DESC
+            LIMIT $3
+            "#,
+        )
+        .bind(before.map(|before| before.naive_utc()))
+        .bind(since.map(|since| since.naive_utc()))
+        .bind(i64::from(limit))
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to fetch messages")
+    }
+
+    async fn missed_messages(
+        &self,
+        username: &str,
+        rooms: &[String],
+        since: DateTime<Utc>,
+    ) -> Result<Vec<StoredMessage>> {
+        sqlx::query_as(
+            r#"
+            SELECT messages.message_id, users.username, messages.content, messages.timestamp,
+                rooms.name AS room
+            FROM messages
+            JOIN users ON messages.user_id = users.id
+            LEFT JOIN rooms ON messages.room_id = rooms.id
+            WHERE messages.timestamp > $1 AND users.username <> $2
+                AND (messages.room_id IS NULL OR rooms.name = ANY($3))
+                AND messages.message_id IS NOT NULL
+            ORDER BY messages.timestamp
+            "#,
+        )
+        .bind(since.naive_utc())
+        .bind(username)
+        .bind(rooms)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to fetch missed messages")
+    }
+
+    async fn list_messages(&self, room: Option<&str>) -> Result<Vec<ListedMessage>> {
+        sqlx::query_as(
+            r#"
+            SELECT messages.id, users.username, messages.content, messages.timestamp,
+                rooms.name AS room, attachments.id AS attachment_id, attachments.original_name,
+                attachments.mime_type, attachments.size, attachments.sha256,
+                EXISTS(SELECT 1 FROM thumbnails WHERE attachment_id = attachments.id)
+                    AS has_thumbnail
+            FROM messages
+            JOIN users ON messages.user_id = users.id
+            LEFT JOIN rooms ON messages.room_id = rooms.id
+            LEFT JOIN attachments ON attachments.message_id = messages.id
+            WHERE $1::TEXT IS NULL OR rooms.name = $1
+            "#,
+        )
+        .bind(room)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to list messages")
+    }
+
+    async fn ensure_room(&self, name: &str) -> Result<i32> {
+        sqlx::query_scalar(
+            "INSERT INTO rooms (name) VALUES ($1)
+            ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name
+            RETURNING id",
+        )
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await
+        .with_context(|| format!("Failed to create room '{}'", name))
+    }
+
+    async fn room_id(&self, name: &str) -> Result<Option<i32>> {
+        let id = sqlx::query_scalar("SELECT id FROM rooms WHERE name = $1")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(id)
+    }
+
+    async fn list_rooms(&self) -> Result<Vec<String>> {
+        let names = sqlx::query_scalar("SELECT name FROM rooms ORDER BY name")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(names)
+    }
+
+    async fn room_history(&self, room_id: i32, limit: u32) -> Result<Vec<StoredMessage>> {
+        let history = sqlx::query_as(
+            r#"
+            SELECT messages.message_id, users.username, messages.content, messages.timestamp
+            FROM messages
+            JOIN users ON messages.user_id = users.id
+            WHERE messages.room_id = $1
+            ORDER BY messages.timestamp DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(room_id)
+        .bind(i64::from(limit))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(history)
+    }
+
+    async fn save_direct_message(
+        &self,
+        envelope: &Envelope,
+        to: &str,
+        body: &str,
+        delivered: bool,
+    ) -> Result<bool> {
+        let sender = envelope
+            .sender
+            .as_deref()
+            .context("Message has no sender")?;
+        let timestamp = envelope.timestamp.context("Message has no timestamp")?;
+
+        let result = sqlx::query(
+            "INSERT INTO direct_messages
+                (message_id, sender_id, recipient_id, content, timestamp, delivered)
+            SELECT $1, sender.id, recipient.id, $4, $5, $6
+            FROM users sender, users recipient
+            WHERE sender.username = $2 AND recipient.username = $3",
+        )
+        .bind(envelope.id)
+        .bind(sender)
+        .bind(to)
+        .bind(body)
+        .bind(timestamp.naive_utc())
+        .bind(delivered)
+        .execute(&self.pool)
+        .await
+        .context("Failed to save direct message")?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn pending_direct_messages(&self, username: &str) -> Result<Vec<PendingDirectMessage>> {
+        let pending = sqlx::query_as(
+            r#"
+            SELECT direct_messages.id, direct_messages.message_id, sender.username,
+                direct_messages.content, direct_messages.timestamp
+            FROM direct_messages
+            JOIN users sender ON direct_messages.sender_id = sender.id
+            JOIN users recipient ON direct_messages.recipient_id = recipient.id
+            WHERE recipient.username = $1 AND NOT direct_messages.delivered
+            ORDER BY direct_messages.timestamp
+            "#,
+        )
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(pending)
+    }
+
+    async fn mark_delivered(&self, ids: &[i32]) -> Result<()> {
+        sqlx::query("UPDATE direct_messages SET delivered = TRUE WHERE id = ANY($1)")
+            .bind(ids)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn save_attachment(&self, envelope: &Envelope, attachment: &Attachment) -> Result<()> {
+        let username = envelope
+            .sender
+            .as_deref()
+            .context("Message has no sender")?;
+        let timestamp = envelope
+            .timestamp
+            .context("Message has no timestamp")?
+            .naive_utc();
+
+        let mut tx = self.pool.begin().await?;
+        let (message_id, user_id): (i32, i32) = sqlx::query_as(
+            r#"
+            INSERT INTO messages (message_id, user_id, content, timestamp)
+            SELECT $1, users.id, $3, $4 FROM users WHERE users.username = $2
+            RETURNING id, user_id
+            "#,
+        )
+        .bind(envelope.id)
+        .bind(username)
+        .bind(attachment.summary())
+        .bind(timestamp)
+        .fetch_one(&mut *tx)
+        .await
+        .with_context(|| format!("Failed to save message for user {}", username))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO attachments
+                (id, message_id, uploader_id, original_name, mime_type, size, sha256,
+                 storage_path, width, height, phash, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            "#,
+        )
+        .bind(attachment.id)
+        .bind(message_id)
+        .bind(user_id)
+        .bind(&attachment.original_name)
+        .bind(&attachment.mime_type)
+        .bind(attachment.size as i64)
+        .bind(&attachment.sha256)
+        .bind(&attachment.storage_path)
+        .bind(attachment.dimensions.map(|(width, _)| width as i32))
+        .bind(attachment.dimensions.map(|(_, height)| height as i32))
+        .bind(attachment.phash.map(|phash| phash as i64))
+        .bind(timestamp)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to save attachment")?;
+
+        for thumbnail in &attachment.thumbnails {
+            sqlx::query(
+                r#"
+                INSERT INTO thumbnails (attachment_id, max_size, width, height, size, storage_path)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                "#,
+            )
+            .bind(attachment.id)
+            .bind(thumbnail.max_size as i32)
+            .bind(thumbnail.width as i32)
+            .bind(thumbnail.height as i32)
+            .bind(thumbnail.size as i64)
+            .bind(&thumbnail.storage_path)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to save thumbnail")?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn find_duplicate(
+        &self,
+        dimensions: (u32, u32),
+        phash: u64,
+        max_distance: u32,
+    ) -> Result<Option<Attachment>> {
+        if phash == 0 {
+            return Ok(None);
+        }
+        let existing: Option<DuplicateImage> = sqlx::query_as(
+            r#"
+            SELECT id, mime_type, size, sha256, storage_path, phash
+            FROM attachments
+            WHERE phash IS NOT NULL AND width = $1 AND height = $2
+                AND bit_count((phash # $3)::bit(64)) <= $4
+            ORDER BY bit_count((phash # $3)::bit(64)), created_at
+            LIMIT 1
+            "#,
+        )
+        .bind(dimensions.0 as i32)
+        .bind(dimensions.1 as i32)
+        .bind(phash as i64)
+        .bind(i64::from(max_distance))
+        .fetch_optional(&self.pool)
+        .await
+        .context("Failed to look for duplicate images")?;
+        let Some(existing) = existing else {
+            return Ok(None);
+        };
+
+        let thumbnails: Vec<ThumbnailRow> = sqlx::query_as(
+            r#"
+            SELECT max_size, width, height, size, storage_path
+            FROM thumbnails
+            WHERE attachment_id = $1
+            ORDER BY max_size
+            "#,
+        )
+        .bind(existing.id)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to fetch thumbnails")?;
+        Ok(Some(existing.into_attachment(dimensions, thumbnails)))
+    }
+
+    async fn attachment(&self, id: Uuid) -> Result<Option<StoredAttachment>> {
+        let attachment = sqlx::query_as(
+            "SELECT original_name, mime_type, storage_path, phash FROM attachments WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(attachment)
+    }
+
+    async fn thumbnail(&self, id: Uuid, size: i32) -> Result<Option<StoredThumbnail>> {
+        let thumbnail = sqlx::query_as(
+            r#"
+            SELECT thumbnails.max_size, thumbnails.storage_path, attachments.mime_type
+            FROM thumbnails
+            JOIN attachments ON attachments.id = thumbnails.attachment_id
+            WHERE thumbnails.attachment_id = $1
+            ORDER BY thumbnails.max_size < $2,
+                CASE WHEN thumbnails.max_size < $2 THEN -thumbnails.max_size
+                    ELSE thumbnails.max_size END
+            LIMIT 1
+            "#,
+        )
+        .bind(id)
+        .bind(size)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(thumbnail)
+    }
+
+    async fn similar_images(
+        &self,
+        id: Uuid,
+        phash: i64,
+        max_distance: u32,
+        limit: u32,
+    ) -> Result<Vec<SimilarAttachment>> {
+        let images = sqlx::query_as(
+            r#"
+            SELECT attachments.id, users.username, messages.timestamp,
+                bit_count((attachments.phash # $1)::bit(64)) AS distance,
+                EXISTS(SELECT 1 FROM thumbnails WHERE attachment_id = attachments.id)
+                    AS has_thumbnail
+            FROM attachments
+            JOIN users ON users.id = attachments.uploader_id
+            JOIN messages ON messages.id = attachments.message_id
+            WHERE attachments.id <> $2 AND attachments.phash IS NOT NULL
+                AND bit_count((attachments.phash # $1)::bit(64)) <= $3
+            ORDER BY 4, messages.timestamp
+            LIMIT $4
+            "#,
+        )
+        .bind(phash)
+        .bind(id)
+        .bind(i64::from(max_distance))
+        .bind(i64::from(limit))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(images)
+    }
+}
@@ -0,0 +1,71 @@
+use shared::{Envelope, MessageType, OnlineUser, PresenceEvent, Status};
+use std::net::SocketAddr;
+
+use crate::{broadcast_message, Clients};
+
+/// Returns the users who are logged in, sorted by username
+///
+/// A user logged in on several connections is listed once, with the status
+/// of the first of them.
+///
+/// # Arguments
+///
+/// * `clients` - A shared reference to the clients hashmap.
+pub async fn online_users(clients: &Clients) -> Vec<OnlineUser> {
+    let mut users: Vec<OnlineUser> = clients
+        .lock()
+        .await
+        .values()
+        .filter(|handle| !handle.username.is_empty())
+        .map(|handle| OnlineUser {
+            username: handle.username.clone(),
+            status: handle.status,
+        })
+        .collect();
+    users.sort_by(|a, b| a.username.cmp(&b.username));
+    users.dedup_by(|a, b| a.username == b.username);
+    users
+}
+
+/// Sets the status a client is shown with
+///
+/// # Arguments
+///
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `addr` - The client's socket address.
+/// * `status` - The new status.
+pub async fn set_status(clients: &Clients, addr: SocketAddr, status: Status) {
+    if let Some(handle) = clients.lock().await.get_mut(&addr) {
+        handle.status = status;
+    }
+}
+
+/// Tells the other clients that a user's presence changed
+///
+/// Logging in and out is only announced for the user's first and last
+/// session, so a user logged in on several connections stays online until
+/// all of them are gone.
+///
+/// # Arguments
+///
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `addr` - The socket address of the client the change happened on.
+/// * `username` - The username of the user.
+/// * `event` - What happened.
+pub async fn announce(clients: &Clients, addr: SocketAddr, username: &str, event: PresenceEvent) {
+    if !matches!(event, PresenceEvent::StatusChanged(_)) {
+        let other_session = clients
+            .lock()
+            .await
+            .iter()
+            .any(|(other, handle)| *other != addr && handle.username == username);
+        if other_session {
+            return;
+        }
+    }
+    let presence = MessageType::Presence {
+        username: username.to_string(),
+        event,
+    };
+    broadcast_message(clients, addr, Envelope::new(presence).stamp(None)).await;
+}
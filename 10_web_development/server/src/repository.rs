@@ -0,0 +1,320 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use shared::Envelope;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::attachments::{Attachment, Thumbnail};
+use crate::migrations::MigrationStatus;
+use crate::postgres::PgRepository;
+use crate::sqlite::SqliteRepository;
+
+/// Where users, rooms, messages and the records of attachments are kept
+///
+/// Implemented for PostgreSQL and SQLite, see `connect`. Timestamps are
+/// stored in UTC without a time zone. Methods that look something up by
+/// name return `None` or `false` when there is no such thing, and errors
+/// only when the database fails.
+#[async_trait]
+pub trait ChatRepository: Send + Sync {
+    /// Compares the schema of the database with the migrations embedded in
+    /// the server.
+    async fn migration_status(&self) -> Result<MigrationStatus>;
+
+    /// Applies the migrations the database is missing, in order.
+    async fn migrate(&self) -> Result<()>;
+
+    /// Checks whether a user with the username exists.
+    async fn user_exists(&self, username: &str) -> Result<bool>;
+
+    /// Registers a new user with the Argon2 hash of their password.
+    async fn register_user(&self, username: &str, password_hash: &str) -> Result<()>;
+
+    /// Returns the password hash of a user, `None` for unknown users and
+    /// users registered before passwords were added.
+    async fn password_hash(&self, username: &str) -> Result<Option<String>>;
+
+    /// Deletes a user with their messages, direct messages and attachments
+    ///
+    /// Returns the storage keys of the deleted attachments and thumbnails
+    /// that no other attachment refers to, for the caller to delete from
+    /// the storage, or `None` if the user does not exist.
+    async fn delete_user(&self, username: &str) -> Result<Option<Vec<String>>>;
+
+    /// Saves a message posted by the envelope's sender
+    ///
+    /// # Arguments
+    ///
+    /// * `envelope` - The stamped envelope of the message.
+    /// * `content` - The content of the message.
+    /// * `room_id` - The ID of the room the message was posted to, `None`
+    ///   for messages to everybody.
+    async fn save_message(
+        &self,
+        envelope: &Envelope,
+        content: &str,
+        room_id: Option<i32>,
+    ) -> Result<()>;
+
+    /// Fetches up to `limit` messages posted to everybody before `before`
+    /// and after `since`, the latest ones first, with the smallest
+    /// thumbnail of their image if they have one.
+    async fn public_messages(
+        &self,
+        before: Option<DateTime<Utc>>,
+        since: Option<DateTime<Utc>>,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>>;
+
+    /// Fetches the messages posted by others after `since`, to everybody
+    /// or to one of `rooms`, oldest first and with their room.
+    async fn missed_messages(
+        &self,
+        username: &str,
+        rooms: &[String],
+        since: DateTime<Utc>,
+    ) -> Result<Vec<StoredMessage>>;
+
+    /// Lists all messages with their room and attachment, or only those
+    /// posted to `room`.
+    async fn list_messages(&self, room: Option<&str>) -> Result<Vec<ListedMessage>>;
+
+    /// Returns the ID of a room, creating the room if it does not exist yet.
+    async fn ensure_room(&self, name: &str) -> Result<i32>;
+
+    /// Returns the ID of an existing room.
+    async fn room_id(&self, name: &str) -> Result<Option<i32>>;
+
+    /// Returns the names of all rooms in alphabetical order.
+    async fn list_rooms(&self) -> Result<Vec<String>>;
+
+    /// Fetches up to `limit` messages posted to a room, the latest ones
+    /// first.
+    async fn room_history(&self, room_id: i32, limit: u32) -> Result<Vec<StoredMessage>>;
+
+    /// Saves a direct message from the envelope's sender
+    ///
+    /// Returns `false` if the sender or the recipient does not exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `envelope` - The stamped envelope of the message.
+    /// * `to` - The username of the recipient.
+    /// * `body` - The text of the message.
+    /// * `delivered` - Whether the message was relayed to the recipient.
+    async fn save_direct_message(
+        &self,
+        envelope: &Envelope,
+        to: &str,
+        body: &str,
+        delivered: bool,
+    ) -> Result<bool>;
+
+    /// Fetches the direct messages a user has not been sent yet, oldest
+    /// first.
+    async fn pending_direct_messages(&self, username: &str) -> Result<Vec<PendingDirectMessage>>;
+
+    /// Marks direct messages as delivered.
+    async fn mark_delivered(&self, ids: &[i32]) -> Result<()>;
+
+    /// Records an attachment and the message it was sent with
+    ///
+    /// The message is saved like a text message to everybody, with a
+    /// summary of the attachment as its content, so attachments show up in
+    /// history.
+    ///
+    /// # Arguments
+    ///
+    /// * `envelope` - The stamped envelope the attachment was sent in.
+    /// * `attachment` - The stored attachment.
+    async fn save_attachment(&self, envelope: &Envelope, attachment: &Attachment) -> Result<()>;
+
+    /// Looks for an earlier image a newly uploaded one is a near-duplicate of
+    ///
+    /// Images count as duplicates when they have the same size and their
+    /// perceptual hashes differ in at most `max_distance` bits. Flat images,
+    /// whose hash is 0, are never taken for duplicates since the hash tells
+    /// nothing about them. Returns a new attachment that refers to the
+    /// stored content and thumbnails of the closest match, oldest first on
+    /// a tie.
+    ///
+    /// # Arguments
+    ///
+    /// * `dimensions` - The width and height of the new image.
+    /// * `phash` - The perceptual hash of the new image.
+    /// * `max_distance` - The most bits in which the hashes may differ.
+    async fn find_duplicate(
+        &self,
+        dimensions: (u32, u32),
+        phash: u64,
+        max_distance: u32,
+    ) -> Result<Option<Attachment>>;
+
+    /// Looks up an attachment.
+    async fn attachment(&self, id: Uuid) -> Result<Option<StoredAttachment>>;
+
+    /// Looks up the smallest thumbnail of an image attachment that is at
+    /// least `size` pixels large, or the largest one there is.
+    async fn thumbnail(&self, id: Uuid, size: i32) -> Result<Option<StoredThumbnail>>;
+
+    /// Lists up to `limit` images other than `id` whose perceptual hash
+    /// differs from `phash` in at most `max_distance` bits, the most similar
+    /// first.
+    async fn similar_images(
+        &self,
+        id: Uuid,
+        phash: i64,
+        max_distance: u32,
+        limit: u32,
+    ) -> Result<Vec<SimilarAttachment>>;
+}
+
+/// A message as it is sent to clients
+#[derive(sqlx::FromRow)]
+pub struct StoredMessage {
+    pub message_id: Option<Uuid>,
+    pub username: String,
+    pub content: String,
+    pub timestamp: Option<NaiveDateTime>,
+    /// The room the message was posted to, only fetched by
+    /// `missed_messages`.
+    #[sqlx(default)]
+    pub room: Option<String>,
+    /// The key of the smallest thumbnail of the message's image, only
+    /// fetched by `public_messages`.
+    #[sqlx(default)]
+    pub thumbnail: Option<String>,
+}
+
+/// A message as it is listed by the web server
+#[derive(sqlx::FromRow)]
+pub struct ListedMessage {
+    pub id: i32,
+    pub username: String,
+    pub content: String,
+    pub timestamp: Option<NaiveDateTime>,
+    pub room: Option<String>,
+    pub attachment_id: Option<Uuid>,
+    pub original_name: Option<String>,
+    pub mime_type: Option<String>,
+    pub size: Option<i64>,
+    pub sha256: Option<String>,
+    pub has_thumbnail: bool,
+}
+
+/// A direct message waiting to be delivered
+#[derive(sqlx::FromRow)]
+pub struct PendingDirectMessage {
+    pub id: i32,
+    pub message_id: Uuid,
+    pub username: String,
+    pub content: String,
+    pub timestamp: NaiveDateTime,
+}
+
+/// The record of an image or file
+#[derive(sqlx::FromRow)]
+pub struct StoredAttachment {
+    pub original_name: Option<String>,
+    pub mime_type: String,
+    pub storage_path: String,
+    pub phash: Option<i64>,
+}
+
+/// A thumbnail with the MIME type of its image
+#[derive(sqlx::FromRow)]
+pub struct StoredThumbnail {
+    pub max_size: i32,
+    pub storage_path: String,
+    pub mime_type: String,
+}
+
+/// An image found by `similar_images`
+#[derive(sqlx::FromRow)]
+pub struct SimilarAttachment {
+    pub id: Uuid,
+    pub username: String,
+    pub timestamp: Option<NaiveDateTime>,
+    /// Bits in which the perceptual hashes of the images differ.
+    pub distance: i64,
+    pub has_thumbnail: bool,
+}
+
+/// An image `find_duplicate` matched
+#[derive(sqlx::FromRow)]
+pub struct DuplicateImage {
+    pub id: Uuid,
+    pub mime_type: String,
+    pub size: i64,
+    pub sha256: String,
+    pub storage_path: String,
+    pub phash: i64,
+}
+
+/// A thumbnail as it is stored
+#[derive(sqlx::FromRow)]
+pub struct ThumbnailRow {
+    pub max_size: i32,
+    pub width: i32,
+    pub height: i32,
+    pub size: i64,
+    pub storage_path: String,
+}
+
+impl DuplicateImage {
+    /// Returns a new attachment referring to the image's stored content and
+    /// thumbnails
+    ///
+    /// # Arguments
+    ///
+    /// * `dimensions` - The width and height of the image.
+    /// * `thumbnails` - The thumbnails of the image, smallest first.
+    pub fn into_attachment(
+        self,
+        dimensions: (u32, u32),
+        thumbnails: Vec<ThumbnailRow>,
+    ) -> Attachment {
+        Attachment {
+            id: Uuid::new_v4(),
+            original_name: None,
+            mime_type: self.mime_type,
+            size: self.size as u64,
+            sha256: self.sha256,
+            storage_path: self.storage_path,
+            dimensions: Some(dimensions),
+            phash: Some(self.phash as u64),
+            thumbnails: thumbnails
+                .into_iter()
+                .map(|thumbnail| Thumbnail {
+                    max_size: thumbnail.max_size as u32,
+                    width: thumbnail.width as u32,
+                    height: thumbnail.height as u32,
+                    size: thumbnail.size as u64,
+                    storage_path: thumbnail.storage_path,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Connects to the database a URL points to
+///
+/// `postgres://` and `postgresql://` URLs connect to PostgreSQL, `sqlite:`
+/// URLs open an SQLite database file, creating it if needed. The schema is
+/// left alone, see `migrate`.
+///
+/// # Arguments
+///
+/// * `url` - The URL of the database, usually `DATABASE_URL`.
+pub async fn connect(url: &str) -> Result<Arc<dyn ChatRepository>> {
+    let scheme = url.split(':').next().unwrap_or_default();
+    match scheme {
+        "postgres" | "postgresql" => Ok(Arc::new(PgRepository::connect(url).await?)),
+        "sqlite" => Ok(Arc::new(SqliteRepository::connect(url).await?)),
+        _ => anyhow::bail!(
+            "Unsupported database '{}', DATABASE_URL must start with postgres:// or sqlite:",
+            scheme
+        ),
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::Result;
+use shared::{Envelope, MessageType};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::repository::ChatRepository;
+use crate::Clients;
+
+/// Number of past messages sent to a client when it joins a room.
+const HISTORY_LENGTH: u32 = 50;
+
+/// Fetches the most recent messages posted to a room
+///
+/// The messages are returned oldest first as `MessageType::RoomMessage`
+/// envelopes carrying their original ID, sender and timestamp, ready to be
+/// sent to a client that joins the room.
+///
+/// # Arguments
+///
+/// * `repository` - Where users and messages are stored.
+/// * `room` - The name of the room.
+/// * `room_id` - The ID of the room.
+pub async fn fetch_room_history(
+    repository: &dyn ChatRepository,
+    room: &str,
+    room_id: i32,
+) -> Result<Vec<Envelope>> {
+    let rows = repository.room_history(room_id, HISTORY_LENGTH).await?;
+
+    let history = rows
+        .into_iter()
+        .rev()
+        .map(|row| Envelope {
+            id: row.message_id.unwrap_or_else(Uuid::new_v4),
+            timestamp: row.timestamp.map(|timestamp| timestamp.and_utc()),
+            sender: Some(row.username),
+            correlation_id: None,
+            message: MessageType::RoomMessage {
+                room: room.to_string(),
+                body: row.content,
+            },
+        })
+        .collect();
+    Ok(history)
+}
+
+/// Adds a client to the members of a room
+///
+/// # Arguments
+///
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `addr` - The client's socket address.
+/// * `room` - The name of the room.
+pub async fn join(clients: &Clients, addr: SocketAddr, room: &str) {
+    if let Some(handle) = clients.lock().await.get_mut(&addr) {
+        handle.rooms.insert(room.to_string());
+    }
+}
+
+/// Removes a client from the members of a room
+///
+/// # Arguments
+///
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `addr` - The client's socket address.
+/// * `room` - The name of the room.
+///
+/// # Returns
+///
+/// Whether the client was a member of the room.
+pub async fn leave(clients: &Clients, addr: SocketAddr, room: &str) -> bool {
+    match clients.lock().await.get_mut(&addr) {
+        Some(handle) => handle.rooms.remove(room),
+        None => false,
+    }
+}
+
+/// Checks whether a client is a member of a room
+///
+/// # Arguments
+///
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `addr` - The client's socket address.
+/// * `room` - The name of the room.
+pub async fn is_member(clients: &Clients, addr: SocketAddr, room: &str) -> bool {
+    clients
+        .lock()
+        .await
+        .get(&addr)
+        .is_some_and(|handle| handle.rooms.contains(room))
+}
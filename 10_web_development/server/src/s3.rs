@@ -0,0 +1,533 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use reqwest::header::ETAG;
+use reqwest::{Client, RequestBuilder, Response};
+use rusty_s3::actions::CreateMultipartUpload;
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+
+use crate::config::S3Config;
+use crate::storage::{check_key, Storage, StoredReader};
+
+/// Size of the parts content larger than one part is uploaded in. S3 wants
+/// at least 5 MiB for every part but the last.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// How long the signatures of the server's own requests stay valid.
+const REQUEST_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// A storage in a bucket of an S3-compatible object store, such as MinIO
+///
+/// Keys are stored as object keys below the configured prefix. Content
+/// larger than `PART_SIZE` is sent with a multipart upload, one part at a
+/// time, so large files are never held in memory as a whole. Objects only
+/// become visible once they are complete.
+pub struct S3Storage {
+    bucket: Bucket,
+    credentials: Credentials,
+    prefix: String,
+    url_expiry: Duration,
+    client: Client,
+}
+
+impl S3Storage {
+    /// Connects to the bucket, making sure it exists and can be accessed
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The object store and bucket to keep content in.
+    pub async fn connect(config: &S3Config) -> Result<Self> {
+        let endpoint = config
+            .endpoint
+            .parse()
+            .with_context(|| format!("Invalid S3 endpoint {}", config.endpoint))?;
+        let url_style = if config.path_style {
+            UrlStyle::Path
+        } else {
+            UrlStyle::VirtualHost
+        };
+        let bucket = Bucket::new(
+            endpoint,
+            url_style,
+            config.bucket.clone(),
+            config.region.clone(),
+        )
+        .with_context(|| format!("Invalid S3 endpoint {}", config.endpoint))?;
+        let storage = S3Storage {
+            bucket,
+            credentials: Credentials::new(&config.access_key_id, &config.secret_access_key),
+            prefix: config.prefix.clone(),
+            url_expiry: config.url_expiry,
+            client: Client::new(),
+        };
+
+        let url = storage
+            .bucket
+            .head_bucket(Some(&storage.credentials))
+            .sign(REQUEST_EXPIRY);
+        check_status(storage.client.head(url).send().await?, &config.bucket)
+            .await
+            .with_context(|| format!("Failed to access bucket {}", config.bucket))?;
+        Ok(storage)
+    }
+
+    /// Returns the object key content is stored under
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The storage key of the content.
+    fn object(&self, key: &str) -> Result<String> {
+        check_key(key)?;
+        Ok(format!("{}{}", self.prefix, key))
+    }
+
+    /// Uploads everything a reader returns, in one request if it fits in a
+    /// single part
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to store the content under.
+    /// * `reader` - The content to upload.
+    async fn upload<R>(&self, key: &str, reader: &mut R) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let object = self.object(key)?;
+        let first = read_part(reader).await?;
+        if first.len() < PART_SIZE {
+            let url = self
+                .bucket
+                .put_object(Some(&self.credentials), &object)
+                .sign(REQUEST_EXPIRY);
+            return send(self.client.put(url).body(first), key).await.map(drop);
+        }
+
+        let url = self
+            .bucket
+            .create_multipart_upload(Some(&self.credentials), &object)
+            .sign(REQUEST_EXPIRY);
+        let response = send(self.client.post(url), key).await?;
+        let created = CreateMultipartUpload::parse_response(&response.text().await?)
+            .context("Invalid response to CreateMultipartUpload")?;
+        let upload_id = created.upload_id();
+
+        let result = self
+            .upload_parts(key, &object, upload_id, first, reader)
+            .await;
+        if result.is_err() {
+            // Otherwise the store keeps the parts sent so far
+            let url = self
+                .bucket
+                .abort_multipart_upload(Some(&self.credentials), &object, upload_id)
+                .sign(REQUEST_EXPIRY);
+            let _ = self.client.delete(url).send().await;
+        }
+        result
+    }
+
+    /// Sends the parts of a multipart upload and completes it
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key the content is stored under.
+    /// * `object` - The object key the content is stored under.
+    /// * `upload_id` - The ID of the multipart upload.
+    /// * `first` - The first part, already read.
+    /// * `reader` - The rest of the content.
+    async fn upload_parts<R>(
+        &self,
+        key: &str,
+        object: &str,
+        upload_id: &str,
+        first: Vec<u8>,
+        reader: &mut R,
+    ) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut etags = Vec::new();
+        let mut part = first;
+        while !part.is_empty() {
+            let number = u16::try_from(etags.len() + 1)
+                .with_context(|| format!("{} has too many parts", key))?;
+            let url = self
+                .bucket
+                .upload_part(Some(&self.credentials), object, number, upload_id)
+                .sign(REQUEST_EXPIRY);
+            let response = send(self.client.put(url).body(part), key).await?;
+            let etag = response
+                .headers()
+                .get(ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .context("Uploaded part has no ETag")?;
+            etags.push(etag.to_string());
+            part = read_part(reader).await?;
+        }
+
+        let action = self.bucket.complete_multipart_upload(
+            Some(&self.credentials),
+            object,
+            upload_id,
+            etags.iter().map(String::as_str),
+        );
+        let url = action.sign(REQUEST_EXPIRY);
+        send(self.client.post(url).body(action.body()), key)
+            .await
+            .map(drop)
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, mut data: &[u8]) -> Result<()> {
+        self.upload(key, &mut data)
+            .await
+            .with_context(|| format!("Failed to store {}", key))
+    }
+
+    async fn put_file(&self, key: &str, source: &Path) -> Result<()> {
+        let mut file = File::open(source)
+            .await
+            .with_context(|| format!("Failed to open {}", source.display()))?;
+        self.upload(key, &mut file)
+            .await
+            .with_context(|| format!("Failed to store {}", key))?;
+        fs::remove_file(source).await?;
+        Ok(())
+    }
+
+    async fn open(&self, key: &str) -> Result<StoredReader> {
+        let url = self
+            .bucket
+            .get_object(Some(&self.credentials), &self.object(key)?)
+            .sign(REQUEST_EXPIRY);
+        let response = send(self.client.get(url), key)
+            .await
+            .with_context(|| format!("Failed to open {}", key))?;
+        let body = response.bytes_stream().map_err(io::Error::other);
+        Ok(Box::new(StreamReader::new(body)))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let url = self
+            .bucket
+            .delete_object(Some(&self.credentials), &self.object(key)?)
+            .sign(REQUEST_EXPIRY);
+        send(self.client.delete(url), key)
+            .await
+            .with_context(|| format!("Failed to delete {}", key))
+            .map(drop)
+    }
+
+    fn download_url(
+        &self,
+        key: &str,
+        content_type: &str,
+        content_disposition: &str,
+    ) -> Option<String> {
+        let object = self.object(key).ok()?;
+        let mut action = self.bucket.get_object(Some(&self.credentials), &object);
+        let query = action.query_mut();
+        query.insert("response-content-type", content_type.to_string());
+        query.insert(
+            "response-content-disposition",
+            content_disposition.to_string(),
+        );
+        Some(action.sign(self.url_expiry).to_string())
+    }
+}
+
+/// Reads up to `PART_SIZE` bytes, fewer only at the end of the content.
+async fn read_part<R>(reader: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut part = Vec::new();
+    (&mut *reader)
+        .take(PART_SIZE as u64)
+        .read_to_end(&mut part)
+        .await?;
+    Ok(part)
+}
+
+/// Sends a request to the object store, turning error responses into errors
+///
+/// # Arguments
+///
+/// * `request` - The signed request.
+/// * `key` - The key the request is about, for the error message.
+async fn send(request: RequestBuilder, key: &str) -> Result<Response> {
+    check_status(request.send().await?, key).await
+}
+
+/// Turns an error response of the object store into an error carrying the
+/// message it returned
+///
+/// # Arguments
+///
+/// * `response` - The response to check.
+/// * `target` - The key or bucket the request was about.
+async fn check_status(response: Response, target: &str) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    anyhow::bail!("Object store answered {} for {}: {}", status, target, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::extract::{DefaultBodyLimit, State};
+    use axum::http::{Method, StatusCode, Uri};
+    use axum::response::{IntoResponse, Response as MockResponse};
+    use axum::Router;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    const BUCKET: &str = "chat";
+    const PREFIX: &str = "uploads/";
+    const KEY: &str = "files/ba/ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    /// An in-memory stand-in for an S3-compatible object store, serving one
+    /// bucket in path style
+    ///
+    /// Requests must be presigned, but signatures are not checked.
+    #[derive(Default)]
+    struct MockS3 {
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+        uploads: Mutex<HashMap<String, BTreeMap<u16, Vec<u8>>>>,
+        /// The S3 actions requested, in order.
+        actions: Mutex<Vec<&'static str>>,
+    }
+
+    impl MockS3 {
+        fn actions(&self) -> Vec<&'static str> {
+            self.actions.lock().unwrap().clone()
+        }
+
+        fn object(&self, key: &str) -> Option<Vec<u8>> {
+            self.objects.lock().unwrap().get(key).cloned()
+        }
+    }
+
+    async fn handle(
+        State(mock): State<Arc<MockS3>>,
+        method: Method,
+        uri: Uri,
+        body: Bytes,
+    ) -> MockResponse {
+        let query: HashMap<&str, &str> = uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+            .collect();
+        if !query.contains_key("X-Amz-Signature") {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        let Some(path) = uri.path().strip_prefix(&format!("/{}", BUCKET)) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let key = path.trim_start_matches('/').to_string();
+        let upload_id = query.get("uploadId").map(|id| id.to_string());
+        let mut actions = mock.actions.lock().unwrap();
+        let mut objects = mock.objects.lock().unwrap();
+        let mut uploads = mock.uploads.lock().unwrap();
+        match (method, upload_id) {
+            (Method::HEAD, None) if key.is_empty() => {
+                actions.push("HeadBucket");
+                StatusCode::OK.into_response()
+            }
+            (Method::PUT, None) => {
+                actions.push("PutObject");
+                objects.insert(key, body.to_vec());
+                StatusCode::OK.into_response()
+            }
+            (Method::POST, None) if query.contains_key("uploads") => {
+                actions.push("CreateMultipartUpload");
+                let upload_id = uuid::Uuid::new_v4().simple().to_string();
+                uploads.insert(upload_id.clone(), BTreeMap::new());
+                format!(
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+<InitiateMultipartUploadResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>"#,
+                    BUCKET, key, upload_id
+                )
+                .into_response()
+            }
+            (Method::PUT, Some(upload_id)) => {
+                actions.push("UploadPart");
+                let number: u16 = query["partNumber"].parse().unwrap();
+                let Some(parts) = uploads.get_mut(&upload_id) else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                parts.insert(number, body.to_vec());
+                ([("ETag", format!("\"etag-{}\"", number))], "").into_response()
+            }
+            (Method::POST, Some(upload_id)) => {
+                actions.push("CompleteMultipartUpload");
+                let Some(parts) = uploads.remove(&upload_id) else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                let body = String::from_utf8_lossy(&body);
+                for number in parts.keys() {
+                    assert!(body.contains(&format!("etag-{}", number)));
+                }
+                objects.insert(key, parts.into_values().flatten().collect());
+                "<CompleteMultipartUploadResult/>".into_response()
+            }
+            (Method::DELETE, Some(upload_id)) => {
+                actions.push("AbortMultipartUpload");
+                uploads.remove(&upload_id);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            (Method::GET, None) => {
+                actions.push("GetObject");
+                match objects.get(&key) {
+                    Some(data) => data.clone().into_response(),
+                    None => (
+                        StatusCode::NOT_FOUND,
+                        "<Error><Code>NoSuchKey</Code></Error>",
+                    )
+                        .into_response(),
+                }
+            }
+            (Method::DELETE, None) => {
+                actions.push("DeleteObject");
+                objects.remove(&key);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            _ => StatusCode::NOT_IMPLEMENTED.into_response(),
+        }
+    }
+
+    /// Starts a mock object store and connects a storage to it.
+    async fn connect() -> (S3Storage, Arc<MockS3>, String) {
+        let mock = Arc::new(MockS3::default());
+        let app = Router::new()
+            .fallback(handle)
+            .layer(DefaultBodyLimit::disable())
+            .with_state(Arc::clone(&mock));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = S3Config {
+            endpoint: endpoint.clone(),
+            bucket: BUCKET.to_string(),
+            region: "us-east-1".to_string(),
+            prefix: PREFIX.to_string(),
+            access_key_id: "minioadmin".to_string(),
+            secret_access_key: "minioadmin".to_string(),
+            path_style: true,
+            url_expiry: Duration::from_secs(900),
+        };
+        let storage = S3Storage::connect(&config).await.unwrap();
+        (storage, mock, endpoint)
+    }
+
+    async fn read(storage: &S3Storage, key: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        storage.open(key).await?.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    #[tokio::test]
+    async fn test_connect_checks_bucket() {
+        let (_, mock, _) = connect().await;
+        assert_eq!(mock.actions(), ["HeadBucket"]);
+    }
+
+    #[tokio::test]
+    async fn test_put_get_delete() {
+        let (storage, mock, _) = connect().await;
+        storage.put(KEY, b"abc").await.unwrap();
+        assert_eq!(mock.object(&format!("{}{}", PREFIX, KEY)).unwrap(), b"abc");
+        assert_eq!(read(&storage, KEY).await.unwrap(), b"abc");
+
+        storage.delete(KEY).await.unwrap();
+        assert!(mock.object(&format!("{}{}", PREFIX, KEY)).is_none());
+        let error = read(&storage, KEY).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("404"));
+        assert_eq!(
+            mock.actions(),
+            [
+                "HeadBucket",
+                "PutObject",
+                "GetObject",
+                "DeleteObject",
+                "GetObject"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_keys_are_refused() {
+        let (storage, mock, _) = connect().await;
+        assert!(storage.put("../escape", b"abc").await.is_err());
+        assert!(storage.open("/etc/passwd").await.is_err());
+        assert!(storage
+            .download_url("a/../b", "text/plain", "inline")
+            .is_none());
+        assert_eq!(mock.actions(), ["HeadBucket"]);
+    }
+
+    #[tokio::test]
+    async fn test_large_content_uses_multipart_upload() {
+        let (storage, mock, _) = connect().await;
+        let data: Vec<u8> = (0..PART_SIZE + 1000).map(|i| (i % 251) as u8).collect();
+        let source = std::env::temp_dir().join(format!("s3_test_{}", uuid::Uuid::new_v4()));
+        std::fs::write(&source, &data).unwrap();
+
+        storage.put_file(KEY, &source).await.unwrap();
+        assert!(!source.exists());
+        assert_eq!(
+            mock.actions(),
+            [
+                "HeadBucket",
+                "CreateMultipartUpload",
+                "UploadPart",
+                "UploadPart",
+                "CompleteMultipartUpload"
+            ]
+        );
+        assert!(mock.uploads.lock().unwrap().is_empty());
+        assert_eq!(read(&storage, KEY).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_content_of_one_part_is_put_at_once() {
+        let (storage, mock, _) = connect().await;
+        let data = vec![7u8; PART_SIZE - 1];
+        storage.put(KEY, &data).await.unwrap();
+        assert_eq!(mock.actions(), ["HeadBucket", "PutObject"]);
+        assert_eq!(read(&storage, KEY).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_download_url_is_presigned() {
+        let (storage, _, endpoint) = connect().await;
+        storage.put(KEY, b"abc").await.unwrap();
+        let url = storage
+            .download_url(KEY, "text/plain", "attachment; filename=\"a.txt\"")
+            .unwrap();
+        assert!(url.starts_with(&format!("{}/{}/{}{}?", endpoint, BUCKET, PREFIX, KEY)));
+        assert!(url.contains("X-Amz-Expires=900"));
+        assert!(url.contains("response-content-type=text%2Fplain"));
+        assert!(url.contains("response-content-disposition=attachment"));
+        assert!(url.contains("X-Amz-Signature="));
+
+        let response = reqwest::get(&url).await.unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(response.bytes().await.unwrap().as_ref(), b"abc");
+    }
+}
@@ -0,0 +1,360 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use shared::{Capability, Envelope, MessageType};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use thiserror::Error;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::repository::ChatRepository;
+use crate::{ClientHandle, Clients};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How long a session token stays valid after login.
+const SESSION_LIFETIME: Duration = Duration::hours(24);
+
+#[derive(Error, Debug)]
+pub enum ResumeError {
+    #[error("Invalid session token, please log in again")]
+    InvalidToken,
+    #[error("Session expired, please log in again")]
+    Expired,
+}
+
+/// A session started by a login, which a client can resume after its
+/// connection dropped
+struct Session {
+    username: String,
+    expires_at: DateTime<Utc>,
+    /// The connection the session is attached to, if any.
+    addr: Option<SocketAddr>,
+    /// The rooms the client had joined when its connection dropped.
+    rooms: HashSet<String>,
+    /// When the server noticed the connection dropped.
+    disconnected_at: Option<DateTime<Utc>>,
+}
+
+/// A session taken over by a new connection
+pub struct Resumed {
+    pub id: Uuid,
+    pub username: String,
+    pub rooms: HashSet<String>,
+    /// When the previous connection dropped, or `None` if it has not been
+    /// noticed yet.
+    pub disconnected_at: Option<DateTime<Utc>>,
+    /// The previous connection, if the session was still attached to it.
+    pub previous: Option<SocketAddr>,
+}
+
+/// The sessions of the server and the key their tokens are signed with
+///
+/// Sessions live in memory and the key is generated at startup, so tokens
+/// stop being valid when the server restarts.
+pub struct Sessions {
+    key: [u8; 32],
+    sessions: Mutex<HashMap<Uuid, Session>>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions {
+            key: rand::random(),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Sessions {
+    /// Starts a session for a user who logged in and returns its ID and
+    /// token
+    ///
+    /// The token has the form `<session id>.<expiry>.<hex username>.<mac>`,
+    /// where the MAC is an HMAC-SHA256 of the rest.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the user.
+    /// * `addr` - The connection the user logged in on.
+    pub fn start(&self, username: &str, addr: SocketAddr) -> (Uuid, String) {
+        let id = Uuid::new_v4();
+        let now = Utc::now();
+        let expires_at = now + SESSION_LIFETIME;
+        let payload = format!(
+            "{}.{}.{}",
+            id.simple(),
+            expires_at.timestamp(),
+            hex::encode(username)
+        );
+        let token = format!("{}.{}", payload, hex::encode(self.sign(&payload)));
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(
+            id,
+            Session {
+                username: username.to_string(),
+                expires_at,
+                addr: Some(addr),
+                rooms: HashSet::new(),
+                disconnected_at: None,
+            },
+        );
+        (id, token)
+    }
+
+    /// Verifies a token and attaches its session to a new connection
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token the client sent.
+    /// * `addr` - The new connection.
+    pub fn resume(&self, token: &str, addr: SocketAddr) -> Result<Resumed, ResumeError> {
+        let (payload, mac) = token.rsplit_once('.').ok_or(ResumeError::InvalidToken)?;
+        let mac = hex::decode(mac).map_err(|_| ResumeError::InvalidToken)?;
+        let mut verifier = self.mac();
+        verifier.update(payload.as_bytes());
+        verifier
+            .verify_slice(&mac)
+            .map_err(|_| ResumeError::InvalidToken)?;
+
+        let mut parts = payload.split('.');
+        let id = parts
+            .next()
+            .and_then(|id| Uuid::parse_str(id).ok())
+            .ok_or(ResumeError::InvalidToken)?;
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(&id).ok_or(ResumeError::Expired)?;
+        if session.expires_at <= Utc::now() {
+            sessions.remove(&id);
+            return Err(ResumeError::Expired);
+        }
+        let previous = session.addr.replace(addr);
+        Ok(Resumed {
+            id,
+            username: session.username.clone(),
+            rooms: std::mem::take(&mut session.rooms),
+            disconnected_at: session.disconnected_at.take(),
+            previous,
+        })
+    }
+
+    /// Detaches a session from a connection that dropped, remembering the
+    /// rooms it had joined
+    ///
+    /// Does nothing if the session has been resumed on another connection
+    /// in the meantime.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the session.
+    /// * `addr` - The connection that dropped.
+    /// * `rooms` - The rooms the client had joined.
+    pub fn suspend(&self, id: Uuid, addr: SocketAddr, rooms: HashSet<String>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(&id) {
+            if session.addr == Some(addr) {
+                session.addr = None;
+                session.rooms = rooms;
+                session.disconnected_at = Some(Utc::now());
+            }
+        }
+    }
+
+    /// Ends a session, so its token can no longer be used
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the session.
+    pub fn end(&self, id: Uuid) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length")
+    }
+
+    fn sign(&self, payload: &str) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// Starts a session for a client that logged in and sends it the token
+///
+/// Clients that did not negotiate sessions are skipped. A session the
+/// client had before, under another login, is ended.
+///
+/// # Arguments
+///
+/// * `sessions` - The sessions of the server.
+/// * `clients` - A shared reference to the clients hashmap.
+/// * `addr` - The client's socket address.
+/// * `handle` - The handle used to send messages to the client.
+/// * `username` - The username the client logged in as.
+pub async fn start_session(
+    sessions: &Sessions,
+    clients: &Clients,
+    addr: SocketAddr,
+    handle: &ClientHandle,
+    username: &str,
+) -> Result<()> {
+    if !handle.capabilities.contains(&Capability::Sessions) {
+        return Ok(());
+    }
+    let (id, token) = sessions.start(username, addr);
+    if let Some(handle) = clients.lock().await.get_mut(&addr) {
+        if let Some(previous) = handle.session.replace(id) {
+            sessions.end(previous);
+        }
+    }
+    handle.send(MessageType::SessionToken(token)).await
+}
+
+/// Fetches the public and room messages a resumed session missed
+///
+/// Returns the messages posted since the session's connection dropped to
+/// the public chat and to the given rooms, oldest first and without the
+/// user's own, as `MessageType::Text` and `MessageType::RoomMessage`
+/// envelopes with their original ID, sender and timestamp.
+///
+/// # Arguments
+///
+/// * `repository` - Where users and messages are stored.
+/// * `username` - The username of the user.
+/// * `rooms` - The rooms the session had joined.
+/// * `since` - When the session's connection dropped.
+pub async fn fetch_missed_messages(
+    repository: &dyn ChatRepository,
+    username: &str,
+    rooms: &HashSet<String>,
+    since: DateTime<Utc>,
+) -> Result<Vec<Envelope>> {
+    let rooms: Vec<String> = rooms.iter().cloned().collect();
+    let rows = repository.missed_messages(username, &rooms, since).await?;
+
+    let missed: Vec<Envelope> = rows
+        .into_iter()
+        .map(|row| Envelope {
+            id: row.message_id.unwrap_or_else(Uuid::new_v4),
+            timestamp: row.timestamp.map(|timestamp| timestamp.and_utc()),
+            sender: Some(row.username),
+            correlation_id: None,
+            message: match row.room {
+                Some(room) => MessageType::RoomMessage {
+                    room,
+                    body: row.content,
+                },
+                None => MessageType::Text(row.content),
+            },
+        })
+        .collect();
+    info!("Replaying {} missed messages to {}", missed.len(), username);
+    Ok(missed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_token_round_trip() {
+        let sessions = Sessions::default();
+        let (id, token) = sessions.start("alice", addr(1));
+        assert_eq!(token.split('.').count(), 4);
+        let resumed = sessions.resume(&token, addr(2)).unwrap();
+        assert_eq!(resumed.id, id);
+        assert_eq!(resumed.username, "alice");
+        assert_eq!(resumed.previous, Some(addr(1)));
+    }
+
+    #[test]
+    fn test_tampered_mac_is_refused() {
+        let sessions = Sessions::default();
+        let (_, token) = sessions.start("alice", addr(1));
+        let last = if token.ends_with('0') { '1' } else { '0' };
+        let tampered = format!("{}{}", &token[..token.len() - 1], last);
+        assert!(matches!(
+            sessions.resume(&tampered, addr(2)),
+            Err(ResumeError::InvalidToken)
+        ));
+        let truncated = token.rsplit_once('.').unwrap().0;
+        assert!(matches!(
+            sessions.resume(truncated, addr(2)),
+            Err(ResumeError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_tampered_username_is_refused() {
+        let sessions = Sessions::default();
+        let (_, token) = sessions.start("alice", addr(1));
+        let parts: Vec<&str> = token.split('.').collect();
+        let mallory = hex::encode("mallory");
+        let tampered = format!("{}.{}.{}.{}", parts[0], parts[1], mallory, parts[3]);
+        assert!(matches!(
+            sessions.resume(&tampered, addr(2)),
+            Err(ResumeError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_expired_session_is_refused() {
+        let sessions = Sessions::default();
+        let (id, token) = sessions.start("alice", addr(1));
+        sessions
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .unwrap()
+            .expires_at = Utc::now() - Duration::seconds(1);
+        assert!(matches!(
+            sessions.resume(&token, addr(2)),
+            Err(ResumeError::Expired)
+        ));
+        assert!(sessions.sessions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ended_session_is_refused() {
+        let sessions = Sessions::default();
+        let (id, token) = sessions.start("alice", addr(1));
+        sessions.end(id);
+        assert!(matches!(
+            sessions.resume(&token, addr(2)),
+            Err(ResumeError::Expired)
+        ));
+    }
+
+    #[test]
+    fn test_token_from_another_server_is_refused() {
+        let (_, token) = Sessions::default().start("alice", addr(1));
+        assert!(matches!(
+            Sessions::default().resume(&token, addr(2)),
+            Err(ResumeError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_suspend_keeps_rooms_for_resume() {
+        let sessions = Sessions::default();
+        let (id, token) = sessions.start("alice", addr(1));
+        let rooms = HashSet::from(["general".to_string()]);
+        sessions.suspend(id, addr(1), rooms.clone());
+        let resumed = sessions.resume(&token, addr(2)).unwrap();
+        assert_eq!(resumed.rooms, rooms);
+        assert_eq!(resumed.previous, None);
+        assert!(resumed.disconnected_at.is_some());
+    }
+}
@@ -0,0 +1,960 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use shared::Envelope;
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::attachments::Attachment;
+use crate::migrations::{self, MigrationStatus};
+use crate::repository::{
+    ChatRepository, DuplicateImage, ListedMessage, PendingDirectMessage, SimilarAttachment,
+    StoredAttachment, StoredMessage, StoredThumbnail, ThumbnailRow,
+};
+
+/// An image `similar_images` compares against
+#[derive(sqlx::FromRow)]
+struct HashedImage {
+    id: Uuid,
+    username: String,
+    timestamp: Option<NaiveDateTime>,
+    phash: i64,
+    has_thumbnail: bool,
+}
+
+/// The migrations that build the schema, embedded at compile time.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// A repository in an SQLite database file, for small deployments and tests
+///
+/// The database is created when it is first opened and its schema is built
+/// by the migrations in `migrations/sqlite`. SQLite cannot count bits, so
+/// perceptual hashes are compared after fetching the hashes of the
+/// candidate images.
+pub struct SqliteRepository {
+    pool: SqlitePool,
+}
+
+impl SqliteRepository {
+    /// Opens an SQLite database, creating it if needed
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The `sqlite:` URL of the database, e.g. `sqlite:chat.db`.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)
+            .context("Invalid SQLite database URL")?
+            .create_if_missing(true)
+            .foreign_keys(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await
+            .context("Failed to open the database")?;
+        Ok(SqliteRepository { pool })
+    }
+}
+
+#[async_trait]
+impl ChatRepository for SqliteRepository {
+    async fn migration_status(&self) -> Result<MigrationStatus> {
+        let mut connection = self.pool.acquire().await?;
+        migrations::status(&MIGRATOR, &mut *connection).await
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        MIGRATOR
+            .run(&self.pool)
+            .await
+            .context("Failed to migrate the database")
+    }
+
+    async fn user_exists(&self, username: &str) -> Result<bool> {
+        let result = sqlx::query("SELECT id FROM users WHERE username = ?1")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(result.is_some())
+    }
+
+    async fn register_user(&self, username: &str, password_hash: &str) -> Result<()> {
+        sqlx::query("INSERT INTO users (username, password_hash) VALUES (?1, ?2)")
+            .bind(username)
+            .bind(password_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn password_hash(&self, username: &str) -> Result<Option<String>> {
+        let password_hash =
+            sqlx::query_scalar("SELECT password_hash FROM users WHERE username = ?1")
+                .bind(username)
+                .fetch_optional(&self.pool)
+                .await
+                .context("Failed to look up user")?;
+        Ok(password_hash.flatten())
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<Option<Vec<String>>> {
+        let mut tx = self.pool.begin().await?;
+        let user_id: Option<i32> = sqlx::query_scalar("SELECT id FROM users WHERE username = ?1")
+            .bind(username)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(user_id) = user_id else {
+            return Ok(None);
+        };
+
+        sqlx::query("DELETE FROM direct_messages WHERE sender_id = ?1 OR recipient_id = ?1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        let mut storage_paths: Vec<String> = sqlx::query_scalar(
+            r#"
+            DELETE FROM thumbnails
+            WHERE attachment_id IN (SELECT id FROM attachments WHERE uploader_id = ?1)
+            RETURNING storage_path
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        storage_paths.extend(
+            sqlx::query_scalar::<_, String>(
+                "DELETE FROM attachments WHERE uploader_id = ?1 RETURNING storage_path",
+            )
+            .bind(user_id)
+            .fetch_all(&mut *tx)
+            .await?,
+        );
+        storage_paths.sort();
+        storage_paths.dedup();
+
+        // Equal content is stored once, other users may still share it
+        let mut unused = Vec::with_capacity(storage_paths.len());
+        for storage_path in storage_paths {
+            let shared: bool = sqlx::query_scalar(
+                r#"
+                SELECT EXISTS(SELECT 1 FROM attachments WHERE storage_path = ?1)
+                    OR EXISTS(SELECT 1 FROM thumbnails WHERE storage_path = ?1)
+                "#,
+            )
+            .bind(&storage_path)
+            .fetch_one(&mut *tx)
+            .await?;
+            if !shared {
+                unused.push(storage_path);
+            }
+        }
+
+        sqlx::query("DELETE FROM messages WHERE user_id = ?1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM users WHERE id = ?1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(unused))
+    }
+
+    async fn save_message(
+        &self,
+        envelope: &Envelope,
+        content: &str,
+        room_id: Option<i32>,
+    ) -> Result<()> {
+        let username = envelope
+            .sender
+            .as_deref()
+            .context("Message has no sender")?;
+        let timestamp = envelope.timestamp.context("Message has no timestamp")?;
+
+        let result = sqlx::query(
+            r#"
+            INSERT INTO messages (message_id, user_id, content, timestamp, room_id)
+            SELECT ?1, users.id, ?3, ?4, ?5 FROM users WHERE users.username = ?2
+            "#,
+        )
+        .bind(envelope.id)
+        .bind(username)
+        .bind(content)
+        .bind(timestamp.naive_utc())
+        .bind(room_id)
+        .execute(&self.pool)
+        .await
+        .with_context(|| format!("Failed to save message for user {}", username))?;
+        if result.rows_affected() == 0 {
+            anyhow::bail!("User '{}' does not exist", username);
+        }
+        Ok(())
+    }
+
+    async fn public_messages(
+        &self,
+        before: Option<DateTime<Utc>>,
+        since: Option<DateTime<Utc>>,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>> {
+        sqlx::query_as(
+            r#"
+            SELECT messages.message_id, users.username, messages.content, messages.timestamp,
+                (SELECT storage_path FROM thumbnails
+                 WHERE thumbnails.attachment_id = attachments.id
+                 ORDER BY max_size
+                 LIMIT 1) AS thumbnail
+            FROM messages
+            JOIN users ON messages.user_id = users.id
+            LEFT JOIN attachments ON attachments.message_id = messages.id
+            WHERE messages.room_id IS NULL
+                AND (?1 IS NULL OR messages.timestamp < ?1)
+                AND (?2 IS NULL OR messages.timestamp > ?2)
+            ORDER BY messages.timestamp DESC
+            LIMIT ?3
+            "#,
+        )
+        .bind(before.map(|before| before.naive_utc()))
+        .bind(since.map(|since| since.naive_utc()))
+        .bind(i64::from(limit))
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to fetch messages")
+    }
+
+    async fn missed_messages(
+        &self,
+        username: &str,
+        rooms: &[String],
+        since: DateTime<Utc>,
+    ) -> Result<Vec<StoredMessage>> {
+        let mut query = QueryBuilder::<Sqlite>::new(
+            r#"
+            SELECT messages.message_id, users.username, messages.content, messages.timestamp,
+                rooms.name AS room
+            FROM messages
+            JOIN users ON messages.user_id = users.id
+            LEFT JOIN rooms ON messages.room_id = rooms.id
+            WHERE messages.timestamp > "#,
+        );
+        query.push_bind(since.naive_utc());
+        query.push(" AND users.username <> ");
+        query.push_bind(username);
+        query.push(" AND (messages.room_id IS NULL OR rooms.name IN (");
+        let mut names = query.separated(", ");
+        for room in rooms {
+            names.push_bind(room);
+        }
+        query.push(")) AND messages.message_id IS NOT NULL ORDER BY messages.timestamp");
+
+        query
+            .build_query_as()
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to fetch missed messages")
+    }
+
+    async fn list_messages(&self, room: Option<&str>) -> Result<Vec<ListedMessage>> {
+        sqlx::query_as(
+            r#"
+            SELECT messages.id, users.username, messages.content, messages.timestamp,
+                rooms.name AS room, attachments.id AS attachment_id, attachments.original_name,
+                attachments.mime_type, attachments.size, attachments.sha256,
+                EXISTS(SELECT 1 FROM thumbnails WHERE attachment_id = attachments.id)
+                    AS has_thumbnail
+            FROM messages
+            JOIN users ON messages.user_id = users.id
+            LEFT JOIN rooms ON messages.room_id = rooms.id
+            LEFT JOIN attachments ON attachments.message_id = messages.id
+            WHERE ?1 IS NULL OR rooms.name = ?1
+            "#,
+        )
+        .bind(room)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to list messages")
+    }
+
+    async fn ensure_room(&self, name: &str) -> Result<i32> {
+        sqlx::query_scalar(
+            "INSERT INTO rooms (name) VALUES (?1)
+            ON CONFLICT (name) DO UPDATE SET name = excluded.name
+            RETURNING id",
+        )
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await
+        .with_context(|| format!("Failed to create room '{}'", name))
+    }
+
+    async fn room_id(&self, name: &str) -> Result<Option<i32>> {
+        let id = sqlx::query_scalar("SELECT id FROM rooms WHERE name = ?1")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(id)
+    }
+
+    async fn list_rooms(&self) -> Result<Vec<String>> {
+        let names = sqlx::query_scalar("SELECT name FROM rooms ORDER BY name")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(names)
+    }
+
+    async fn room_history(&self, room_id: i32, limit: u32) -> Result<Vec<StoredMessage>> {
+        let history = sqlx::query_as(
+            r#"
+            SELECT messages.message_id, users.username, messages.content, messages.timestamp
+            FROM messages
+            JOIN users ON messages.user_id = users.id
+            WHERE messages.room_id = ?1
+            ORDER BY messages.timestamp DESC
+            LIMIT ?2
+            "#,
+        )
+        .bind(room_id)
+        .bind(i64::from(limit))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(history)
+    }
+
+    async fn save_direct_message(
+        &self,
+        envelope: &Envelope,
+        to: &str,
+        body: &str,
+        delivered: bool,
+    ) -> Result<bool> {
+        let sender = envelope
+            .sender
+            .as_deref()
+            .context("Message has no sender")?;
+        let timestamp = envelope.timestamp.context("Message has no timestamp")?;
+
+        let result = sqlx::query(
+            "INSERT INTO direct_messages
+                (message_id, sender_id, recipient_id, content, timestamp, delivered)
+            SELECT ?1, sender.id, recipient.id, ?4, ?5, ?6
+            FROM users sender, users recipient
+            WHERE sender.username = ?2 AND recipient.username = ?3",
+        )
+        .bind(envelope.id)
+        .bind(sender)
+        .bind(to)
+        .bind(body)
+        .bind(timestamp.naive_utc())
+        .bind(delivered)
+        .execute(&self.pool)
+        .await
+        .context("Failed to save direct message")?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn pending_direct_messages(&self, username: &str) -> Result<Vec<PendingDirectMessage>> {
+        let pending = sqlx::query_as(
+            r#"
+            SELECT direct_messages.id, direct_messages.message_id, sender.username,
+                direct_messages.content, direct_messages.timestamp
+            FROM direct_messages
+            JOIN users sender ON direct_messages.sender_id = sender.id
+            JOIN users recipient ON direct_messages.recipient_id = recipient.id
+            WHERE recipient.username = ?1 AND NOT direct_messages.delivered
+            ORDER BY direct_messages.timestamp
+            "#,
+        )
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(pending)
+    }
+
+    async fn mark_delivered(&self, ids: &[i32]) -> Result<()> {
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "UPDATE direct_messages SET delivered = TRUE WHERE id IN (",
+        );
+        let mut separated = query.separated(", ");
+        for id in ids {
+            separated.push_bind(*id);
+        }
+        query.push(")");
+        query.build().execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn save_attachment(&self, envelope: &Envelope, attachment: &Attachment) -> Result<()> {
+        let username = envelope
+            .sender
+            .as_deref()
+            .context("Message has no sender")?;
+        let timestamp = envelope
+            .timestamp
+            .context("Message has no timestamp")?
+            .naive_utc();
+
+        let mut tx = self.pool.begin().await?;
+        let (message_id, user_id): (i32, i32) = sqlx::query_as(
+            r#"
+            INSERT INTO messages (message_id, user_id, content, timestamp)
+            SELECT ?1, users.id, ?3, ?4 FROM users WHERE users.username = ?2
+            RETURNING id, user_id
+            "#,
+        )
+        .bind(envelope.id)
+        .bind(username)
+        .bind(attachment.summary())
+        .bind(timestamp)
+        .fetch_one(&mut *tx)
+        .await
+        .with_context(|| format!("Failed to save message for user {}", username))?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO attachments
+                (id, message_id, uploader_id, original_name, mime_type, size, sha256,
+                 storage_path, width, height, phash, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+            "#,
+        )
+        .bind(attachment.id)
+        .bind(message_id)
+        .bind(user_id)
+        .bind(&attachment.original_name)
+        .bind(&attachment.mime_type)
+        .bind(attachment.size as i64)
+        .bind(&attachment.sha256)
+        .bind(&attachment.storage_path)
+        .bind(attachment.dimensions.map(|(width, _)| width as i32))
+        .bind(attachment.dimensions.map(|(_, height)| height as i32))
+        .bind(attachment.phash.map(|phash| phash as i64))
+        .bind(timestamp)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to save attachment")?;
+
+        for thumbnail in &attachment.thumbnails {
+            sqlx::query(
+                r#"
+                INSERT INTO thumbnails (attachment_id, max_size, width, height, size, storage_path)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                "#,
+            )
+            .bind(attachment.id)
+            .bind(thumbnail.max_size as i32)
+            .bind(thumbnail.width as i32)
+            .bind(thumbnail.height as i32)
+            .bind(thumbnail.size as i64)
+            .bind(&thumbnail.storage_path)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to save thumbnail")?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn find_duplicate(
+        &self,
+        dimensions: (u32, u32),
+        phash: u64,
+        max_distance: u32,
+    ) -> Result<Option<Attachment>> {
+        if phash == 0 {
+            return Ok(None);
+        }
+        let candidates: Vec<DuplicateImage> = sqlx::query_as(
+            r#"
+            SELECT id, mime_type, size, sha256, storage_path, phash
+            FROM attachments
+            WHERE phash IS NOT NULL AND width = ?1 AND height = ?2
+            ORDER BY created_at
+            "#,
+        )
+        .bind(dimensions.0 as i32)
+        .bind(dimensions.1 as i32)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to look for duplicate images")?;
+
+        // The first of the closest ones, which is the oldest
+        let mut closest: Option<(u32, DuplicateImage)> = None;
+        for candidate in candidates {
+            let distance = distance(candidate.phash, phash as i64);
+            let closer = closest.as_ref().is_none_or(|(best, _)| distance < *best);
+            if distance <= max_distance && closer {
+                closest = Some((distance, candidate));
+            }
+        }
+        let Some((_, existing)) = closest else {
+            return Ok(None);
+        };
+
+        let thumbnails: Vec<ThumbnailRow> = sqlx::query_as(
+            r#"
+            SELECT max_size, width, height, size, storage_path
+            FROM thumbnails
+            WHERE attachment_id = ?1
+            ORDER BY max_size
+            "#,
+        )
+        .bind(existing.id)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to fetch thumbnails")?;
+        Ok(Some(existing.into_attachment(dimensions, thumbnails)))
+    }
+
+    async fn attachment(&self, id: Uuid) -> Result<Option<StoredAttachment>> {
+        let attachment = sqlx::query_as(
+            "SELECT original_name, mime_type, storage_path, phash FROM attachments WHERE id = ?1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(attachment)
+    }
+
+    async fn thumbnail(&self, id: Uuid, size: i32) -> Result<Option<StoredThumbnail>> {
+        let thumbnail = sqlx::query_as(
+            r#"
+            SELECT thumbnails.max_size, thumbnails.storage_path, attachments.mime_type
+            FROM thumbnails
+            JOIN attachments ON attachments.id = thumbnails.attachment_id
+            WHERE thumbnails.attachment_id = ?1
+            ORDER BY thumbnails.max_size < ?2,
+                CASE WHEN thumbnails.max_size < ?2 THEN -thumbnails.max_size
+                    ELSE thumbnails.max_size END
+            LIMIT 1
+            "#,
+        )
+        .bind(id)
+        .bind(size)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(thumbnail)
+    }
+
+    async fn similar_images(
+        &self,
+        id: Uuid,
+        phash: i64,
+        max_distance: u32,
+        limit: u32,
+    ) -> Result<Vec<SimilarAttachment>> {
+        let images: Vec<HashedImage> = sqlx::query_as(
+            r#"
+            SELECT attachments.id, users.username, messages.timestamp, attachments.phash,
+                EXISTS(SELECT 1 FROM thumbnails WHERE attachment_id = attachments.id)
+                    AS has_thumbnail
+            FROM attachments
+            JOIN users ON users.id = attachments.uploader_id
+            JOIN messages ON messages.id = attachments.message_id
+            WHERE attachments.id <> ?1 AND attachments.phash IS NOT NULL
+            ORDER BY messages.timestamp
+            "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut similar: Vec<SimilarAttachment> = images
+            .into_iter()
+            .map(|image| SimilarAttachment {
+                distance: i64::from(distance(image.phash, phash)),
+                id: image.id,
+                username: image.username,
+                timestamp: image.timestamp,
+                has_thumbnail: image.has_thumbnail,
+            })
+            .filter(|image| image.distance <= i64::from(max_distance))
+            .collect();
+        // Stable, so equally similar images stay in the order they were posted
+        similar.sort_by_key(|image| image.distance);
+        similar.truncate(limit as usize);
+        Ok(similar)
+    }
+}
+
+/// Returns the number of bits in which two perceptual hashes differ.
+fn distance(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachments::Thumbnail;
+    use chrono::TimeZone;
+    use shared::MessageType;
+    use std::path::{Path, PathBuf};
+
+    /// Opens a migrated database in a fresh file below the system's
+    /// temporary directory.
+    async fn open() -> (SqliteRepository, PathBuf) {
+        let path = std::env::temp_dir().join(format!("sqlite_test_{}.db", Uuid::new_v4()));
+        let repository = SqliteRepository::connect(&format!("sqlite:{}", path.display()))
+            .await
+            .unwrap();
+        repository.migrate().await.unwrap();
+        (repository, path)
+    }
+
+    /// Closes the database and removes its files.
+    async fn close(repository: SqliteRepository, path: &Path) {
+        repository.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    /// The time `minute` minutes after a fixed point.
+    fn at(minute: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap() + chrono::Duration::minutes(minute)
+    }
+
+    /// An envelope stamped as if `sender` had sent it at `minute`.
+    fn envelope(sender: &str, minute: i64) -> Envelope {
+        let mut envelope = Envelope::new(MessageType::Text(String::new())).stamp(Some(sender));
+        envelope.timestamp = Some(at(minute));
+        envelope
+    }
+
+    fn contents(messages: &[StoredMessage]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect()
+    }
+
+    fn image(storage_path: &str, phash: u64) -> Attachment {
+        Attachment {
+            id: Uuid::new_v4(),
+            original_name: None,
+            mime_type: "image/png".to_string(),
+            size: 1000,
+            sha256: "ab".repeat(32),
+            storage_path: storage_path.to_string(),
+            dimensions: Some((640, 480)),
+            phash: Some(phash),
+            thumbnails: [64, 256]
+                .into_iter()
+                .map(|max_size| Thumbnail {
+                    max_size,
+                    width: max_size,
+                    height: max_size * 3 / 4,
+                    size: u64::from(max_size),
+                    storage_path: format!("images/thumb_{}.png", max_size),
+                })
+                .collect(),
+        }
+    }
+
+    fn file(storage_path: &str) -> Attachment {
+        Attachment {
+            id: Uuid::new_v4(),
+            original_name: Some("notes.txt".to_string()),
+            mime_type: "text/plain".to_string(),
+            size: 12,
+            sha256: "cd".repeat(32),
+            storage_path: storage_path.to_string(),
+            dimensions: None,
+            phash: None,
+            thumbnails: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_users() {
+        let (repository, path) = open().await;
+        assert!(!repository.user_exists("alice").await.unwrap());
+        assert!(repository.password_hash("alice").await.unwrap().is_none());
+
+        repository.register_user("alice", "hash").await.unwrap();
+        assert!(repository.user_exists("alice").await.unwrap());
+        assert_eq!(
+            repository.password_hash("alice").await.unwrap().as_deref(),
+            Some("hash")
+        );
+        assert!(repository.register_user("alice", "other").await.is_err());
+        assert!(repository.delete_user("bob").await.unwrap().is_none());
+        assert_eq!(repository.delete_user("alice").await.unwrap(), Some(vec![]));
+        assert!(!repository.user_exists("alice").await.unwrap());
+        close(repository, &path).await;
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_paged_latest_first() {
+        let (repository, path) = open().await;
+        repository.register_user("alice", "hash").await.unwrap();
+        for minute in 0..5 {
+            let content = format!("message {}", minute);
+            repository
+                .save_message(&envelope("alice", minute), &content, None)
+                .await
+                .unwrap();
+        }
+        assert!(repository
+            .save_message(&envelope("nobody", 0), "lost", None)
+            .await
+            .is_err());
+
+        let page = repository.public_messages(None, None, 2).await.unwrap();
+        assert_eq!(contents(&page), ["message 4", "message 3"]);
+        let page = repository
+            .public_messages(Some(at(3)), None, 2)
+            .await
+            .unwrap();
+        assert_eq!(contents(&page), ["message 2", "message 1"]);
+        let page = repository
+            .public_messages(Some(at(1)), None, 2)
+            .await
+            .unwrap();
+        assert_eq!(contents(&page), ["message 0"]);
+        let page = repository
+            .public_messages(None, Some(at(2)), 10)
+            .await
+            .unwrap();
+        assert_eq!(contents(&page), ["message 4", "message 3"]);
+        assert_eq!(page[0].timestamp, Some(at(4).naive_utc()));
+        close(repository, &path).await;
+    }
+
+    #[tokio::test]
+    async fn test_rooms() {
+        let (repository, path) = open().await;
+        repository.register_user("alice", "hash").await.unwrap();
+        repository.register_user("bob", "hash").await.unwrap();
+        let rust = repository.ensure_room("rust").await.unwrap();
+        assert_eq!(repository.ensure_room("rust").await.unwrap(), rust);
+        let go = repository.ensure_room("go").await.unwrap();
+        assert_ne!(go, rust);
+        assert_eq!(repository.room_id("rust").await.unwrap(), Some(rust));
+        assert_eq!(repository.room_id("zig").await.unwrap(), None);
+        assert_eq!(repository.list_rooms().await.unwrap(), ["go", "rust"]);
+
+        let rooms = [
+            (Some(rust), "in rust"),
+            (Some(go), "in go"),
+            (None, "public"),
+        ];
+        for (minute, (room_id, content)) in rooms.into_iter().enumerate() {
+            repository
+                .save_message(&envelope("alice", minute as i64), content, room_id)
+                .await
+                .unwrap();
+        }
+        repository
+            .save_message(&envelope("bob", 3), "from bob", Some(rust))
+            .await
+            .unwrap();
+
+        let history = repository.room_history(rust, 10).await.unwrap();
+        assert_eq!(contents(&history), ["from bob", "in rust"]);
+        assert_eq!(
+            contents(&repository.room_history(rust, 1).await.unwrap()),
+            ["from bob"]
+        );
+        let public = repository.public_messages(None, None, 10).await.unwrap();
+        assert_eq!(contents(&public), ["public"]);
+
+        // Missed messages leave out the user's own and other rooms' messages
+        let missed = repository
+            .missed_messages("bob", &["rust".to_string()], at(-1))
+            .await
+            .unwrap();
+        assert_eq!(contents(&missed), ["in rust", "public"]);
+        assert_eq!(missed[0].room.as_deref(), Some("rust"));
+        assert_eq!(missed[1].room, None);
+
+        let listed = repository.list_messages(Some("rust")).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed
+            .iter()
+            .all(|message| message.room.as_deref() == Some("rust")));
+        assert_eq!(repository.list_messages(None).await.unwrap().len(), 4);
+        close(repository, &path).await;
+    }
+
+    #[tokio::test]
+    async fn test_direct_messages() {
+        let (repository, path) = open().await;
+        repository.register_user("alice", "hash").await.unwrap();
+        repository.register_user("bob", "hash").await.unwrap();
+        assert!(!repository
+            .save_direct_message(&envelope("alice", 0), "nobody", "hi", false)
+            .await
+            .unwrap());
+        for (minute, delivered) in [(2, false), (1, false), (0, true)] {
+            let body = format!("hi {}", minute);
+            assert!(repository
+                .save_direct_message(&envelope("alice", minute), "bob", &body, delivered)
+                .await
+                .unwrap());
+        }
+
+        let pending = repository.pending_direct_messages("bob").await.unwrap();
+        let bodies: Vec<_> = pending
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(bodies, ["hi 1", "hi 2"]);
+        assert!(pending.iter().all(|message| message.username == "alice"));
+        assert!(repository
+            .pending_direct_messages("alice")
+            .await
+            .unwrap()
+            .is_empty());
+
+        repository.mark_delivered(&[pending[0].id]).await.unwrap();
+        let pending = repository.pending_direct_messages("bob").await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].content, "hi 2");
+        close(repository, &path).await;
+    }
+
+    #[tokio::test]
+    async fn test_attachments() {
+        let (repository, path) = open().await;
+        repository.register_user("alice", "hash").await.unwrap();
+        repository.register_user("bob", "hash").await.unwrap();
+        let original = image("images/original.png", 0b1111_0000);
+        repository
+            .save_attachment(&envelope("alice", 0), &original)
+            .await
+            .unwrap();
+        let notes = file("files/notes");
+        repository
+            .save_attachment(&envelope("alice", 1), &notes)
+            .await
+            .unwrap();
+
+        let stored = repository.attachment(original.id).await.unwrap().unwrap();
+        assert_eq!(stored.storage_path, "images/original.png");
+        assert_eq!(stored.phash, Some(0b1111_0000));
+        assert!(stored.original_name.is_none());
+        let stored = repository.attachment(notes.id).await.unwrap().unwrap();
+        assert_eq!(stored.original_name.as_deref(), Some("notes.txt"));
+        assert!(repository
+            .attachment(Uuid::new_v4())
+            .await
+            .unwrap()
+            .is_none());
+
+        // The smallest thumbnail that is large enough, or the largest
+        for (size, expected) in [(0, 64), (64, 64), (100, 256), (1000, 256)] {
+            let thumbnail = repository
+                .thumbnail(original.id, size)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(thumbnail.max_size, expected);
+            assert_eq!(thumbnail.mime_type, "image/png");
+        }
+        assert!(repository.thumbnail(notes.id, 0).await.unwrap().is_none());
+
+        // Attachments show up in history with their smallest thumbnail
+        let public = repository.public_messages(None, None, 10).await.unwrap();
+        assert_eq!(
+            contents(&public),
+            ["[file 'notes.txt', 12 bytes]", "[image, 1000 bytes]"]
+        );
+        assert_eq!(public[1].thumbnail.as_deref(), Some("images/thumb_64.png"));
+        let listed = repository.list_messages(None).await.unwrap();
+        let listed_notes = listed
+            .iter()
+            .find(|message| message.attachment_id == Some(notes.id))
+            .unwrap();
+        assert_eq!(listed_notes.size, Some(12));
+        assert!(!listed_notes.has_thumbnail);
+        close(repository, &path).await;
+    }
+
+    #[tokio::test]
+    async fn test_near_duplicates_share_stored_content() {
+        let (repository, path) = open().await;
+        repository.register_user("alice", "hash").await.unwrap();
+        repository.register_user("bob", "hash").await.unwrap();
+        let original = image("images/original.png", 0b1111_0000);
+        repository
+            .save_attachment(&envelope("alice", 0), &original)
+            .await
+            .unwrap();
+        repository
+            .save_attachment(&envelope("alice", 1), &file("files/notes"))
+            .await
+            .unwrap();
+
+        assert!(repository
+            .find_duplicate((640, 480), 0b1111_0011, 1)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(repository
+            .find_duplicate((640, 481), 0b1111_0000, 1)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(repository
+            .find_duplicate((640, 480), 0, 64)
+            .await
+            .unwrap()
+            .is_none());
+        let duplicate = repository
+            .find_duplicate((640, 480), 0b1111_0001, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(duplicate.id, original.id);
+        assert_eq!(duplicate.storage_path, original.storage_path);
+        assert_eq!(duplicate.thumbnails.len(), 2);
+        repository
+            .save_attachment(&envelope("bob", 2), &duplicate)
+            .await
+            .unwrap();
+
+        let similar = repository
+            .similar_images(original.id, 0b1111_0000, 4, 10)
+            .await
+            .unwrap();
+        assert_eq!(similar.len(), 1);
+        assert_eq!(similar[0].id, duplicate.id);
+        assert_eq!(similar[0].username, "bob");
+        assert_eq!(similar[0].distance, 0);
+        assert!(similar[0].has_thumbnail);
+
+        // Bob's copy still refers to the image and its thumbnails
+        let mut unused = repository.delete_user("alice").await.unwrap().unwrap();
+        unused.sort();
+        assert_eq!(unused, ["files/notes"]);
+        assert!(repository.attachment(original.id).await.unwrap().is_none());
+        assert!(repository
+            .thumbnail(duplicate.id, 0)
+            .await
+            .unwrap()
+            .is_some());
+        let mut unused = repository.delete_user("bob").await.unwrap().unwrap();
+        unused.sort();
+        assert_eq!(
+            unused,
+            [
+                "images/original.png",
+                "images/thumb_256.png",
+                "images/thumb_64.png"
+            ]
+        );
+        close(repository, &path).await;
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(distance(0, 0), 0);
+        assert_eq!(distance(0b1010, 0b0110), 2);
+        assert_eq!(distance(0, -1), 64);
+    }
+}
@@ -0,0 +1,361 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use shared::is_sha256_hex;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use uuid::Uuid;
+
+/// Reads content back from a storage.
+pub type StoredReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Where uploaded images and files are kept
+///
+/// Content is addressed by keys such as `files/ab/abcd...`, relative paths
+/// built from the SHA-256 digest of the content (see `image_key` and
+/// `file_key`). Equal content is stored once, and different content never
+/// ends up under the same key, so nothing is ever overwritten by another
+/// upload.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores content under a key. Readers see either the complete content
+    /// or nothing.
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+
+    /// Moves a local file into the storage under a key.
+    async fn put_file(&self, key: &str, source: &Path) -> Result<()>;
+
+    /// Opens the content stored under a key for reading.
+    async fn open(&self, key: &str) -> Result<StoredReader>;
+
+    /// Deletes the content stored under a key, if there is any.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Returns a URL the content stored under a key can be downloaded from
+    /// directly, or `None` if it has to be read with `open`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key the content is stored under.
+    /// * `content_type` - The `Content-Type` to download the content with.
+    /// * `content_disposition` - The `Content-Disposition` to download the
+    ///   content with.
+    fn download_url(
+        &self,
+        _key: &str,
+        _content_type: &str,
+        _content_disposition: &str,
+    ) -> Option<String> {
+        None
+    }
+}
+
+/// Checks that a key is a relative path without `.` or `..` components
+///
+/// Backslashes are refused too, so a key means the same on every platform
+/// and to every backend.
+///
+/// # Arguments
+///
+/// * `key` - The key to check.
+pub fn check_key(key: &str) -> Result<()> {
+    let valid = Path::new(key)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if key.is_empty() || key.contains('\\') || !valid {
+        anyhow::bail!("Invalid storage key '{}'", key);
+    }
+    Ok(())
+}
+
+/// Returns the key an image or thumbnail is stored under, failing if the
+/// digest is not a hex-encoded SHA-256 digest.
+///
+/// # Arguments
+///
+/// * `sha256` - The hex-encoded SHA-256 digest of the encoded image.
+/// * `extension` - The file extension of the image's format.
+pub fn image_key(sha256: &str, extension: &str) -> Result<String> {
+    check_digest(sha256)?;
+    Ok(format!("images/{}/{}.{}", &sha256[..2], sha256, extension))
+}
+
+/// Returns the key a file is stored under, failing if the digest is not a
+/// hex-encoded SHA-256 digest.
+///
+/// # Arguments
+///
+/// * `sha256` - The hex-encoded SHA-256 digest of the file.
+pub fn file_key(sha256: &str) -> Result<String> {
+    check_digest(sha256)?;
+    Ok(format!("files/{}/{}", &sha256[..2], sha256))
+}
+
+/// Fails if a string is not a hex-encoded SHA-256 digest.
+fn check_digest(sha256: &str) -> Result<()> {
+    if !is_sha256_hex(sha256) {
+        anyhow::bail!("Invalid SHA-256 digest '{}'", sha256);
+    }
+    Ok(())
+}
+
+/// A storage in a directory of the local file system
+///
+/// Keys are checked to stay inside the directory, and paths that pass
+/// through a symbolic link are refused, so neither a crafted key nor a link
+/// planted in the directory can reach other files. Content is written to a
+/// temporary file next to its destination and renamed into place.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    /// Opens the storage in a directory, creating the directories for
+    /// images and files
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The directory to store content in.
+    pub async fn new(root: &Path) -> Result<Self> {
+        for dir in ["images", "files"] {
+            fs::create_dir_all(root.join(dir))
+                .await
+                .with_context(|| format!("Failed to create directory {}", dir))?;
+        }
+        let root = fs::canonicalize(root)
+            .await
+            .with_context(|| format!("Invalid storage directory {}", root.display()))?;
+        Ok(LocalStorage { root })
+    }
+
+    /// Resolves a key to a path inside the storage directory
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to resolve.
+    async fn path(&self, key: &str) -> Result<PathBuf> {
+        check_key(key)?;
+        let relative = Path::new(key);
+        let mut path = self.root.clone();
+        for component in relative.components() {
+            path.push(component);
+            match fs::symlink_metadata(&path).await {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    anyhow::bail!("Refusing to follow a symbolic link for '{}'", key);
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// Creates the directory a path is stored in and returns a fresh
+    /// temporary path next to it.
+    async fn prepare(&self, path: &Path) -> Result<PathBuf> {
+        let dir = path.parent().context("Storage path has no parent")?;
+        fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        Ok(dir.join(format!(".{}.tmp", Uuid::new_v4())))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(key).await?;
+        let temp = self.prepare(&path).await?;
+        let result = async {
+            // `create_new` refuses to open anything already there, links
+            // included
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp)
+                .await?;
+            file.write_all(data).await?;
+            file.sync_all().await?;
+            fs::rename(&temp, &path).await
+        }
+        .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&temp).await;
+        }
+        result.with_context(|| format!("Failed to store {}", key))
+    }
+
+    async fn put_file(&self, key: &str, source: &Path) -> Result<()> {
+        let path = self.path(key).await?;
+        let temp = self.prepare(&path).await?;
+        // Renaming only works within one file system, otherwise copy
+        if fs::rename(source, &temp).await.is_err() {
+            if let Err(e) = fs::copy(source, &temp).await {
+                let _ = fs::remove_file(&temp).await;
+                return Err(e).with_context(|| format!("Failed to store {}", key));
+            }
+            fs::remove_file(source).await?;
+        }
+        fs::rename(&temp, &path)
+            .await
+            .with_context(|| format!("Failed to store {}", key))
+    }
+
+    async fn open(&self, key: &str) -> Result<StoredReader> {
+        let path = self.path(key).await?;
+        let file = File::open(&path)
+            .await
+            .with_context(|| format!("Failed to open {}", key))?;
+        Ok(Box::new(file))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key).await?;
+        match fs::remove_file(&path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to delete {}", key))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    const DIGEST: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    /// A fresh directory below the system's temporary directory.
+    fn temp_root() -> PathBuf {
+        std::env::temp_dir().join(format!("storage_test_{}", Uuid::new_v4()))
+    }
+
+    async fn read(storage: &LocalStorage, key: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        storage
+            .open(key)
+            .await
+            .unwrap()
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        data
+    }
+
+    /// The names of the entries of a directory, sorted.
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_check_key() {
+        assert!(check_key("files/ba/ba7816bf").is_ok());
+        assert!(check_key("images/ba/ba7816bf.png").is_ok());
+        for key in [
+            "",
+            "..",
+            "../etc/passwd",
+            "files/../../etc/passwd",
+            "./files/a",
+            "/etc/passwd",
+            "files\\..\\..\\etc",
+            "..\\secret",
+        ] {
+            assert!(check_key(key).is_err(), "accepted {:?}", key);
+        }
+    }
+
+    #[test]
+    fn test_keys_are_built_from_digests() {
+        assert_eq!(file_key(DIGEST).unwrap(), format!("files/ba/{}", DIGEST));
+        assert_eq!(
+            image_key(DIGEST, "png").unwrap(),
+            format!("images/ba/{}.png", DIGEST)
+        );
+        assert!(file_key("").is_err());
+        assert!(file_key("a").is_err());
+        assert!(image_key("../../etc/passwd", "png").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_put_open_delete() {
+        let root = temp_root();
+        let storage = LocalStorage::new(&root).await.unwrap();
+        let key = file_key(DIGEST).unwrap();
+        storage.put(&key, b"abc").await.unwrap();
+        assert_eq!(read(&storage, &key).await, b"abc");
+        storage.delete(&key).await.unwrap();
+        assert!(storage.open(&key).await.is_err());
+        // Deleting what is not there is fine
+        storage.delete(&key).await.unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_put_writes_atomically() {
+        let root = temp_root();
+        let storage = LocalStorage::new(&root).await.unwrap();
+        let key = "files/ba/content";
+        storage.put(key, b"first").await.unwrap();
+        storage.put(key, b"second").await.unwrap();
+        assert_eq!(read(&storage, key).await, b"second");
+        // Only the renamed file is left, no temporary files
+        assert_eq!(entries(&root.join("files/ba")), ["content"]);
+
+        // A write that cannot be renamed into place leaves nothing behind
+        std::fs::create_dir_all(root.join("files/ba/taken/inner")).unwrap();
+        assert!(storage.put("files/ba/taken", b"data").await.is_err());
+        assert_eq!(entries(&root.join("files/ba")), ["content", "taken"]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_put_file_moves_source() {
+        let root = temp_root();
+        let storage = LocalStorage::new(&root).await.unwrap();
+        let source = root.join("upload.part");
+        std::fs::write(&source, b"chunked").unwrap();
+        let key = file_key(DIGEST).unwrap();
+        storage.put_file(&key, &source).await.unwrap();
+        assert!(!source.exists());
+        assert_eq!(read(&storage, &key).await, b"chunked");
+        assert_eq!(entries(&root.join("files/ba")), [DIGEST]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlinks_are_refused() {
+        let root = temp_root();
+        let outside = temp_root();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("secret"), b"secret").unwrap();
+        let storage = LocalStorage::new(&root).await.unwrap();
+
+        // A linked directory along the way
+        std::os::unix::fs::symlink(&outside, root.join("files/linked")).unwrap();
+        assert!(storage.open("files/linked/secret").await.is_err());
+        assert!(storage.put("files/linked/planted", b"x").await.is_err());
+        assert!(storage.delete("files/linked/secret").await.is_err());
+        assert!(!outside.join("planted").exists());
+        assert!(outside.join("secret").exists());
+
+        // A linked file
+        std::os::unix::fs::symlink(outside.join("secret"), root.join("files/secret")).unwrap();
+        assert!(storage.open("files/secret").await.is_err());
+        assert!(storage.put("files/secret", b"x").await.is_err());
+        assert_eq!(std::fs::read(outside.join("secret")).unwrap(), b"secret");
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::{Context, Result};
+use rcgen::CertifiedKey;
+use shared::sha256_hex;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// The read half of a client connection, plain TCP or TLS.
+pub type ReadStream = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The write half of a client connection, plain TCP or TLS.
+pub type WriteStream = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Builds a TLS acceptor from a certificate chain and private key
+///
+/// # Arguments
+///
+/// * `cert_path` - The PEM file holding the server certificate, followed
+///   by any intermediate certificates.
+/// * `key_path` - The PEM file holding the private key of the certificate.
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .with_context(|| format!("Failed to read certificates from {}", cert_path.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate in {}", cert_path.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to read private key from {}", key_path.display()))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Certificate and private key do not match")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Splits a client connection into its halves, after performing the TLS
+/// handshake if the server uses TLS
+///
+/// # Arguments
+///
+/// * `acceptor` - The TLS acceptor, or `None` for plain TCP.
+/// * `stream` - The accepted TCP stream.
+pub async fn accept(
+    acceptor: Option<&TlsAcceptor>,
+    stream: TcpStream,
+) -> std::io::Result<(ReadStream, WriteStream)> {
+    match acceptor {
+        Some(acceptor) => {
+            let stream = acceptor.accept(stream).await?;
+            let (reader, writer) = tokio::io::split(stream);
+            Ok((Box::new(reader), Box::new(writer)))
+        }
+        None => {
+            let (reader, writer) = stream.into_split();
+            Ok((Box::new(reader), Box::new(writer)))
+        }
+    }
+}
+
+/// Generates a self-signed certificate for development
+///
+/// Writes `cert.pem` and `key.pem` into `dir`. Clients can trust the
+/// certificate by using `cert.pem` as their CA bundle or by pinning its
+/// fingerprint.
+///
+/// # Arguments
+///
+/// * `dir` - The directory to write the files to.
+/// * `hostnames` - The host names and IP addresses the certificate is
+///   valid for.
+///
+/// # Returns
+///
+/// The SHA-256 fingerprint of the certificate in hex.
+pub async fn generate_dev_certificate(dir: &Path, hostnames: Vec<String>) -> Result<String> {
+    let CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(hostnames).context("Failed to generate certificate")?;
+
+    fs::create_dir_all(dir).await?;
+    fs::write(dir.join("cert.pem"), cert.pem()).await?;
+    fs::write(dir.join("key.pem"), key_pair.serialize_pem()).await?;
+    Ok(sha256_hex(cert.der()))
+}
@@ -0,0 +1,333 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use shared::{is_sha256_hex, sha256_file, Envelope, MessageType, FILE_CHUNK_SIZE};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task;
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::config::UploadConfig;
+use crate::storage::{file_key, Storage};
+use crate::ClientHandle;
+
+/// How often abandoned partial uploads are looked for.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// An upload in progress on one connection
+struct Upload {
+    name: String,
+    size: u64,
+    sha256: String,
+    path: PathBuf,
+    file: File,
+    received: u64,
+}
+
+/// A file whose upload has been verified and committed
+pub struct CompletedUpload {
+    /// The ID of the attachment the file is recorded as.
+    pub id: Uuid,
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    /// The key the file is stored under.
+    pub key: String,
+}
+
+/// The chunked uploads of one client connection
+///
+/// Chunks are appended straight to a partial file on disk. The partial file
+/// is named after the uploader and the content digest, so when the same
+/// user starts uploading the same content again after a disconnect, the
+/// transfer resumes where it stopped.
+pub struct Uploads {
+    uploads: HashMap<Uuid, Upload>,
+    config: Arc<UploadConfig>,
+}
+
+impl Uploads {
+    /// Creates the uploads of a connection
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The size limit and the directory of partial uploads.
+    pub fn new(config: Arc<UploadConfig>) -> Self {
+        Uploads {
+            uploads: HashMap::new(),
+            config,
+        }
+    }
+
+    /// Starts or resumes an upload
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username of the uploader.
+    /// * `transfer_id` - The ID the client chose for the transfer.
+    /// * `name` - The name of the uploaded file.
+    /// * `size` - The size of the file in bytes.
+    /// * `sha256` - The hex-encoded SHA-256 digest of the file.
+    ///
+    /// # Returns
+    ///
+    /// The offset the client should continue from.
+    pub async fn start(
+        &mut self,
+        username: &str,
+        transfer_id: Uuid,
+        name: String,
+        size: u64,
+        sha256: String,
+    ) -> Result<u64> {
+        if !is_sha256_hex(&sha256) {
+            return Err(anyhow::anyhow!(
+                "Invalid SHA-256 digest for file '{}'",
+                name
+            ));
+        }
+        if size > self.config.max_file_size {
+            return Err(anyhow::anyhow!(
+                "File '{}' is larger than the limit of {} bytes",
+                name,
+                self.config.max_file_size
+            ));
+        }
+
+        let partial_dir = &self.config.partial_dir;
+        fs::create_dir_all(partial_dir)
+            .await
+            .context("Failed to create directory for partial uploads")?;
+        // Usernames may contain anything, so the partial file is named after
+        // a digest of the username and the content digest
+        let key = Sha256::digest(format!("{}/{}", username, sha256));
+        let path = partial_dir.join(format!("{:x}", key));
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .context("Failed to open partial upload")?;
+        let mut received = file.metadata().await?.len();
+        if received > size {
+            // Leftover of a different upload attempt, start over
+            file.set_len(0).await?;
+            received = 0;
+        }
+
+        if received > 0 {
+            info!(
+                "Resuming upload of '{}' from {} at offset {}",
+                name, username, received
+            );
+        }
+        self.uploads.insert(
+            transfer_id,
+            Upload {
+                name,
+                size,
+                sha256,
+                path,
+                file,
+                received,
+            },
+        );
+        Ok(received)
+    }
+
+    /// Appends a chunk to an upload
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - The ID of the transfer.
+    /// * `offset` - The offset of the chunk within the file.
+    /// * `data` - The content of the chunk.
+    pub async fn write_chunk(&mut self, transfer_id: Uuid, offset: u64, data: &[u8]) -> Result<()> {
+        let upload = self
+            .uploads
+            .get_mut(&transfer_id)
+            .context("Unknown file transfer")?;
+        if data.len() > FILE_CHUNK_SIZE {
+            return Err(anyhow::anyhow!(
+                "Chunk of file '{}' is too large",
+                upload.name
+            ));
+        }
+        if offset != upload.received {
+            return Err(anyhow::anyhow!(
+                "Chunk of file '{}' at offset {}, expected offset {}",
+                upload.name,
+                offset,
+                upload.received
+            ));
+        }
+        if upload.received + data.len() as u64 > upload.size {
+            return Err(anyhow::anyhow!(
+                "File '{}' is larger than announced",
+                upload.name
+            ));
+        }
+
+        upload
+            .file
+            .write_all(data)
+            .await
+            .context("Failed to write file chunk")?;
+        upload.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// Finishes an upload, verifying its size and digest and moving it
+    /// into the storage
+    ///
+    /// A partial file whose digest does not match is deleted.
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer_id` - The ID of the transfer.
+    /// * `storage` - The storage to keep the file in.
+    pub async fn finish(
+        &mut self,
+        transfer_id: Uuid,
+        storage: &dyn Storage,
+    ) -> Result<CompletedUpload> {
+        let mut upload = self
+            .uploads
+            .remove(&transfer_id)
+            .context("Unknown file transfer")?;
+        if upload.received != upload.size {
+            return Err(anyhow::anyhow!(
+                "File '{}' is incomplete: received {} of {} bytes",
+                upload.name,
+                upload.received,
+                upload.size
+            ));
+        }
+        upload.file.flush().await?;
+        drop(upload.file);
+
+        let partial_path = upload.path.clone();
+        let sha256 = task::spawn_blocking(move || sha256_file(&partial_path)).await??;
+        if sha256 != upload.sha256 {
+            fs::remove_file(&upload.path).await?;
+            return Err(anyhow::anyhow!(
+                "Checksum mismatch for file '{}', please send it again",
+                upload.name
+            ));
+        }
+
+        let key = file_key(&sha256)?;
+        storage.put_file(&key, &upload.path).await?;
+        Ok(CompletedUpload {
+            id: Uuid::new_v4(),
+            name: upload.name,
+            size: upload.size,
+            sha256,
+            key,
+        })
+    }
+}
+
+/// Deletes abandoned partial uploads, checking every hour
+///
+/// A partial upload is abandoned when nothing has been written to it for
+/// longer than the configured expiry; uploads interrupted more recently
+/// can still be resumed.
+///
+/// # Arguments
+///
+/// * `config` - The directory of partial uploads and their expiry.
+pub async fn expire_partial_uploads(config: Arc<UploadConfig>) {
+    loop {
+        match remove_stale_files(&config.partial_dir, config.partial_expiry).await {
+            Ok(0) => {}
+            Ok(removed) => info!("Deleted {} abandoned partial uploads", removed),
+            Err(e) => warn!("Failed to delete abandoned partial uploads: {:?}", e),
+        }
+        sleep(EXPIRY_CHECK_INTERVAL).await;
+    }
+}
+
+/// Deletes the files in a directory that were last modified longer than
+/// `max_age` ago, returning how many were deleted.
+///
+/// # Arguments
+///
+/// * `dir` - The directory to clean up.
+/// * `max_age` - How long a file is kept after it was last modified.
+async fn remove_stale_files(dir: &Path, max_age: Duration) -> Result<usize> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+        if metadata.is_file() && age > max_age {
+            fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Streams a stored file to a client as a chunked transfer
+///
+/// Relayed files always start at offset 0, without waiting for a
+/// `FileOffset` answer. The transfer goes through the recipient's bulk
+/// queue with backpressure, so relaying a large file waits for the
+/// recipient without filling the queue its other messages go through.
+///
+/// # Arguments
+///
+/// * `handle` - The handle of the recipient.
+/// * `sender` - The username of the user who uploaded the file.
+/// * `upload` - The file to relay.
+pub async fn relay_file(handle: ClientHandle, sender: String, upload: &CompletedUpload) {
+    if let Err(e) = send_file(&handle, &sender, upload).await {
+        error!("Failed to relay file '{}': {:?}", upload.name, e);
+    }
+}
+
+async fn send_file(handle: &ClientHandle, sender: &str, upload: &CompletedUpload) -> Result<()> {
+    let transfer_id = Uuid::new_v4();
+    let stamped = |message| Envelope::new(message).stamp(Some(sender));
+
+    let start = MessageType::FileStart {
+        transfer_id,
+        name: upload.name.clone(),
+        size: upload.size,
+        sha256: upload.sha256.clone(),
+    };
+    handle.send_bulk(stamped(start)).await?;
+
+    let mut file = handle.storage.open(&upload.key).await?;
+    let mut offset = 0;
+    let mut buffer = vec![0u8; FILE_CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        let chunk = MessageType::FileChunk {
+            transfer_id,
+            offset,
+            data: buffer[..read].to_vec(),
+        };
+        handle.send_bulk(stamped(chunk)).await?;
+        offset += read as u64;
+    }
+
+    handle
+        .send_bulk(stamped(MessageType::FileFinish { transfer_id }))
+        .await
+}
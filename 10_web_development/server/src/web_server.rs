@@ -1,8 +1,22 @@
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType, LOCATION};
 use actix_web::{web, App, HttpServer, Responder, HttpResponse};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
 use actix_files::Files;
 use std::sync::Arc;
+use tokio_util::io::ReaderStream;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::repository::ChatRepository;
+use crate::storage::Storage;
+use crate::{presence, Clients};
+
+/// Bits in which perceptual hashes may differ for `/attachments/{id}/similar`
+/// by default.
+const DEFAULT_SIMILAR_DISTANCE: u32 = 10;
+
+/// The most images `/attachments/{id}/similar` lists.
+const MAX_SIMILAR_IMAGES: u32 = 50;
 
 
 #[derive(Serialize, Deserialize)]
@@ -11,12 +25,46 @@ struct Message {
     username: String,
     content: String,
     timestamp: String,
+    room: Option<String>,
+    attachment: Option<Attachment>,
 }
 
 #[derive(Serialize, Deserialize)]
-struct User {
-    id: i32,
+struct Attachment {
+    id: Uuid,
+    name: Option<String>,
+    mime_type: String,
+    size: i64,
+    sha256: String,
+    url: String,
+    /// Where the smallest thumbnail of an image is, none for files.
+    thumbnail_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MessagesQuery {
+    room: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ThumbnailQuery {
+    size: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct SimilarQuery {
+    distance: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SimilarImage {
+    id: Uuid,
     username: String,
+    timestamp: String,
+    /// Bits in which the perceptual hashes of the images differ.
+    distance: i64,
+    url: String,
+    thumbnail_url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -24,17 +72,17 @@ struct UserDeleteRequest {
     username: String,
 }
 
-async fn get_messages(pool: web::Data<Arc<PgPool>>) -> impl Responder {
-    let rows = sqlx::query!(
-        r#"
-        SELECT messages.id, users.username, messages.content, messages.timestamp
-        FROM messages
-        JOIN users ON messages.user_id = users.id
-        "#
-    )
-    .fetch_all(pool.get_ref().as_ref())
-    .await
-    .unwrap();
+async fn get_messages(
+    repository: web::Data<Arc<dyn ChatRepository>>,
+    query: web::Query<MessagesQuery>,
+) -> impl Responder {
+    let rows = match repository.list_messages(query.room.as_deref()).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Failed to list messages: {:#}", e);
+            return HttpResponse::InternalServerError().json("Messages could not be read.");
+        }
+    };
 
     let messages: Vec<Message> = rows
         .into_iter()
@@ -42,47 +90,225 @@ async fn get_messages(pool: web::Data<Arc<PgPool>>) -> impl Responder {
             id: row.id,
             username: row.username,
             content: row.content,
-            timestamp: row.timestamp.unwrap().to_string(),
+            timestamp: row
+                .timestamp
+                .map(|timestamp| timestamp.to_string())
+                .unwrap_or_default(),
+            room: row.room,
+            attachment: row.attachment_id.map(|id| Attachment {
+                id,
+                name: row.original_name,
+                mime_type: row.mime_type.unwrap_or_default(),
+                size: row.size.unwrap_or_default(),
+                sha256: row.sha256.unwrap_or_default(),
+                url: format!("/attachments/{}", id),
+                thumbnail_url: row
+                    .has_thumbnail
+                    .then(|| format!("/attachments/{}/thumbnail", id)),
+            }),
         })
         .collect();
         
     HttpResponse::Ok().json(messages)
 }
 
+/// Downloads an attachment, redirecting to the storage when it can serve
+/// the content itself.
+async fn get_attachment(
+    repository: web::Data<Arc<dyn ChatRepository>>,
+    storage: web::Data<Arc<dyn Storage>>,
+    id: web::Path<Uuid>,
+) -> impl Responder {
+    let attachment = match repository.attachment(*id).await {
+        Ok(Some(attachment)) => attachment,
+        Ok(None) => return HttpResponse::NotFound().json("Attachment not found."),
+        Err(e) => {
+            error!("Failed to look up attachment {}: {:#}", id, e);
+            return HttpResponse::InternalServerError().json("Attachment could not be read.");
+        }
+    };
+
+    // Images have no name of their own, but are stored under a key ending in
+    // the extension of their format
+    let name = attachment.original_name.unwrap_or_else(|| {
+        let extension = std::path::Path::new(&attachment.storage_path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .or_else(|| {
+                mime_guess::get_mime_extensions_str(&attachment.mime_type)
+                    .and_then(|extensions| extensions.first().copied())
+            });
+        match extension {
+            Some(extension) => format!("{}.{}", id, extension),
+            None => id.to_string(),
+        }
+    });
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(name)],
+    };
+    serve_stored(
+        storage.as_ref().as_ref(),
+        &attachment.storage_path,
+        attachment.mime_type,
+        disposition,
+    )
+    .await
+}
+
+/// Shows a thumbnail of an image attachment, the smallest one at least
+/// `size` pixels large or the largest one there is.
+async fn get_thumbnail(
+    repository: web::Data<Arc<dyn ChatRepository>>,
+    storage: web::Data<Arc<dyn Storage>>,
+    id: web::Path<Uuid>,
+    query: web::Query<ThumbnailQuery>,
+) -> impl Responder {
+    let thumbnail = match repository.thumbnail(*id, query.size.unwrap_or(0)).await {
+        Ok(Some(thumbnail)) => thumbnail,
+        Ok(None) => return HttpResponse::NotFound().json("Thumbnail not found."),
+        Err(e) => {
+            error!("Failed to look up a thumbnail of {}: {:#}", id, e);
+            return HttpResponse::InternalServerError().json("Thumbnail could not be read.");
+        }
+    };
+
+    let extension = std::path::Path::new(&thumbnail.storage_path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("img");
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Inline,
+        parameters: vec![DispositionParam::Filename(format!(
+            "{}_{}.{}",
+            id, thumbnail.max_size, extension
+        ))],
+    };
+    serve_stored(
+        storage.as_ref().as_ref(),
+        &thumbnail.storage_path,
+        thumbnail.mime_type,
+        disposition,
+    )
+    .await
+}
+
+/// Lists the images whose perceptual hash differs from the one of an image
+/// in at most `distance` bits, the most similar first.
+async fn get_similar_images(
+    repository: web::Data<Arc<dyn ChatRepository>>,
+    id: web::Path<Uuid>,
+    query: web::Query<SimilarQuery>,
+) -> impl Responder {
+    let attachment = match repository.attachment(*id).await {
+        Ok(Some(attachment)) => attachment,
+        Ok(None) => return HttpResponse::NotFound().json("Attachment not found."),
+        Err(e) => {
+            error!("Failed to look up attachment {}: {:#}", id, e);
+            return HttpResponse::InternalServerError().json("Attachment could not be read.");
+        }
+    };
+    let Some(phash) = attachment.phash else {
+        return HttpResponse::NotFound().json("Attachment is not an image.");
+    };
+
+    let distance = query.distance.unwrap_or(DEFAULT_SIMILAR_DISTANCE).min(64);
+    let rows = match repository
+        .similar_images(*id, phash, distance, MAX_SIMILAR_IMAGES)
+        .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Failed to look for images similar to {}: {:#}", id, e);
+            return HttpResponse::InternalServerError().json("Similar images could not be found.");
+        }
+    };
+
+    let images: Vec<SimilarImage> = rows
+        .into_iter()
+        .map(|row| SimilarImage {
+            id: row.id,
+            username: row.username,
+            timestamp: row
+                .timestamp
+                .map(|timestamp| timestamp.to_string())
+                .unwrap_or_default(),
+            distance: row.distance,
+            url: format!("/attachments/{}", row.id),
+            thumbnail_url: row
+                .has_thumbnail
+                .then(|| format!("/attachments/{}/thumbnail", row.id)),
+        })
+        .collect();
+    HttpResponse::Ok().json(images)
+}
+
+/// Answers with stored content, or with a redirect to where the storage
+/// serves it itself.
+async fn serve_stored(
+    storage: &dyn Storage,
+    key: &str,
+    mime_type: String,
+    disposition: ContentDisposition,
+) -> HttpResponse {
+    if let Some(url) = storage.download_url(key, &mime_type, &disposition.to_string()) {
+        return HttpResponse::TemporaryRedirect()
+            .insert_header((LOCATION, url))
+            .finish();
+    }
+
+    match storage.open(key).await {
+        Ok(reader) => HttpResponse::Ok()
+            .content_type(mime_type)
+            .insert_header(disposition)
+            .streaming(ReaderStream::new(reader)),
+        Err(_) => HttpResponse::InternalServerError().json("Attachment could not be read."),
+    }
+}
+
+async fn get_online_users(clients: web::Data<Clients>) -> impl Responder {
+    HttpResponse::Ok().json(presence::online_users(clients.get_ref()).await)
+}
+
 async fn delete_user(
-    pool: web::Data<Arc<PgPool>>,
+    repository: web::Data<Arc<dyn ChatRepository>>,
+    storage: web::Data<Arc<dyn Storage>>,
     user_info: web::Json<UserDeleteRequest>,
 ) -> impl Responder {
-    let username = &user_info.username;
-    let user_id_result = sqlx::query!("SELECT id FROM users WHERE username = $1", username)
-        .fetch_one(pool.get_ref().as_ref())
-        .await;
-        
-    match user_id_result {
-        Ok(record) => {
-            let user_id = record.id;
-            sqlx::query!("DELETE FROM messages WHERE user_id = $1", user_id)
-                .execute(pool.get_ref().as_ref())
-                .await
-                .unwrap();
-            
-            sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
-                .execute(pool.get_ref().as_ref())
-                .await
-                .unwrap();
-                
+    match repository.delete_user(&user_info.username).await {
+        Ok(Some(storage_paths)) => {
+            for storage_path in storage_paths {
+                let _ = storage.delete(&storage_path).await;
+            }
             HttpResponse::Ok().json("User and associated messages deleted successfully.")
         }
-        Err(_) => HttpResponse::NotFound().json("User not found."),
+        Ok(None) => HttpResponse::NotFound().json("User not found."),
+        Err(e) => {
+            error!("Failed to delete user {}: {:#}", user_info.username, e);
+            HttpResponse::InternalServerError().json("User could not be deleted.")
+        }
     }
 }
 
 
-pub async fn run(db_pool: Arc<PgPool>) -> std::io::Result<()> {
+pub async fn run(
+    repository: Arc<dyn ChatRepository>,
+    clients: Clients,
+    storage: Arc<dyn Storage>,
+) -> std::io::Result<()> {
     HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(db_pool.clone()))
+            .app_data(web::Data::new(repository.clone()))
+            .app_data(web::Data::new(clients.clone()))
+            .app_data(web::Data::new(storage.clone()))
             .route("/messages", web::get().to(get_messages))
+            .route("/attachments/{id}", web::get().to(get_attachment))
+            .route("/attachments/{id}/thumbnail", web::get().to(get_thumbnail))
+            .route(
+                "/attachments/{id}/similar",
+                web::get().to(get_similar_images),
+            )
+            .route("/users/online", web::get().to(get_online_users))
             .route("/delete_user", web::post().to(delete_user))
             .service(Files::new("/", "./static").index_file("index.html"))
     })
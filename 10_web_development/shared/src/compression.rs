@@ -0,0 +1,176 @@
+// shared/src/compression.rs
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+/// Frames smaller than this are never compressed; compressing them costs
+/// more than it saves.
+pub const COMPRESSION_THRESHOLD: usize = 512;
+
+/// Flag byte of a frame whose payload is not compressed.
+pub(crate) const FLAG_UNCOMPRESSED: u8 = 0;
+
+/// The compression algorithms a peer can offer in its `Hello` message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Compression {
+    Zstd,
+    Deflate,
+}
+
+impl Compression {
+    /// All algorithms implemented by this crate, in order of preference.
+    pub const ALL: &'static [Compression] = &[Compression::Zstd, Compression::Deflate];
+
+    /// The flag byte marking a frame compressed with this algorithm.
+    pub(crate) fn flag(self) -> u8 {
+        match self {
+            Compression::Zstd => 1,
+            Compression::Deflate => 2,
+        }
+    }
+
+    /// Returns the algorithm a frame flag stands for, `None` for
+    /// uncompressed frames.
+    ///
+    /// # Arguments
+    ///
+    /// * `flag` - The flag byte of a frame.
+    pub(crate) fn from_flag(flag: u8) -> Result<Option<Compression>, u8> {
+        match flag {
+            FLAG_UNCOMPRESSED => Ok(None),
+            1 => Ok(Some(Compression::Zstd)),
+            2 => Ok(Some(Compression::Deflate)),
+            _ => Err(flag),
+        }
+    }
+
+    /// Compresses a frame payload.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The payload to compress.
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decompresses a frame payload
+    ///
+    /// At most `limit + 1` bytes are decompressed, so a payload that
+    /// decompresses to more than `limit` bytes is detected without
+    /// inflating it completely.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The compressed payload.
+    /// * `limit` - The maximum size of the decompressed payload.
+    pub fn decompress(self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        let take = limit as u64 + 1;
+        match self {
+            Compression::Zstd => {
+                zstd::Decoder::new(data)?
+                    .take(take)
+                    .read_to_end(&mut decompressed)?;
+            }
+            Compression::Deflate => {
+                DeflateDecoder::new(data)
+                    .take(take)
+                    .read_to_end(&mut decompressed)?;
+            }
+        }
+        Ok(decompressed)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::Zstd => "zstd",
+            Compression::Deflate => "deflate",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "zstd" => Ok(Compression::Zstd),
+            "deflate" => Ok(Compression::Deflate),
+            _ => Err(format!(
+                "Unknown compression '{}', expected zstd or deflate",
+                s
+            )),
+        }
+    }
+}
+
+/// Chooses the compression of a connection
+///
+/// Returns the first algorithm offered by the peer that we support as
+/// well, or `None` to send frames uncompressed.
+///
+/// # Arguments
+///
+/// * `offered` - The algorithms announced by the peer, in its order of
+///   preference.
+/// * `supported` - The algorithms we support.
+pub fn choose_compression(
+    offered: &[Compression],
+    supported: &[Compression],
+) -> Option<Compression> {
+    offered
+        .iter()
+        .copied()
+        .find(|compression| supported.contains(compression))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let data = "timestamp,level,message\n".repeat(100);
+        for compression in Compression::ALL {
+            let compressed = compression.compress(data.as_bytes()).unwrap();
+            assert!(compressed.len() < data.len() / 10, "{}", compression);
+            let decompressed = compression.decompress(&compressed, data.len()).unwrap();
+            assert_eq!(decompressed, data.as_bytes(), "{}", compression);
+        }
+    }
+
+    #[test]
+    fn test_decompress_stops_after_limit() {
+        let data = vec![0u8; 1024 * 1024];
+        for compression in Compression::ALL {
+            let compressed = compression.compress(&data).unwrap();
+            let decompressed = compression.decompress(&compressed, 1000).unwrap();
+            assert_eq!(decompressed.len(), 1001, "{}", compression);
+        }
+    }
+
+    #[test]
+    fn test_choose_compression() {
+        let offered = [Compression::Deflate, Compression::Zstd];
+        assert_eq!(
+            choose_compression(&offered, Compression::ALL),
+            Some(Compression::Deflate)
+        );
+        assert_eq!(choose_compression(&offered, &[]), None);
+        assert_eq!(choose_compression(&[], Compression::ALL), None);
+    }
+}
@@ -0,0 +1,101 @@
+// shared/src/envelope.rs
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::MessageType;
+
+/// A message together with its metadata, as it travels over the wire
+///
+/// Clients fill in only `id`, `correlation_id` and `message`. The server
+/// overwrites `sender` and `timestamp` with authoritative values before it
+/// stores or relays the message, so receivers can trust them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    /// Unique ID of the message, chosen by whoever created it.
+    pub id: Uuid,
+    /// When the server received or created the message.
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Username of the user who sent the message, `None` for messages
+    /// originating from the server itself.
+    pub sender: Option<String>,
+    /// ID of the message this one answers, e.g. the request a server
+    /// `Error` refers to.
+    pub correlation_id: Option<Uuid>,
+    /// The message itself.
+    pub message: MessageType,
+}
+
+impl Envelope {
+    /// Wraps a message in a new envelope with a fresh ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to wrap.
+    pub fn new(message: MessageType) -> Self {
+        Envelope {
+            id: Uuid::new_v4(),
+            timestamp: None,
+            sender: None,
+            correlation_id: None,
+            message,
+        }
+    }
+
+    /// Wraps a message answering the message with the given ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `correlation_id` - The ID of the message being answered.
+    /// * `message` - The message to wrap.
+    pub fn reply_to(correlation_id: Uuid, message: MessageType) -> Self {
+        Envelope {
+            correlation_id: Some(correlation_id),
+            ..Envelope::new(message)
+        }
+    }
+
+    /// Stamps the envelope with the current time and the given sender,
+    /// replacing whatever the peer put there.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The username of the sender, `None` for the server.
+    pub fn stamp(mut self, sender: Option<&str>) -> Self {
+        self.timestamp = Some(Utc::now());
+        self.sender = sender.map(str::to_string);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_envelopes_have_unique_ids() {
+        let first = Envelope::new(MessageType::Quit);
+        let second = Envelope::new(MessageType::Quit);
+        assert_ne!(first.id, second.id);
+        assert!(first.timestamp.is_none());
+        assert!(first.sender.is_none());
+    }
+
+    #[test]
+    fn test_reply_to_sets_correlation_id() {
+        let request = Envelope::new(MessageType::Text("hi".to_string()));
+        let reply = Envelope::reply_to(request.id, MessageType::Error("nope".to_string()));
+        assert_eq!(reply.correlation_id, Some(request.id));
+        assert_ne!(reply.id, request.id);
+    }
+
+    #[test]
+    fn test_stamp_overwrites_sender_and_timestamp() {
+        let mut envelope = Envelope::new(MessageType::Text("hi".to_string()));
+        envelope.sender = Some("mallory".to_string());
+        let envelope = envelope.stamp(Some("alice"));
+        assert_eq!(envelope.sender.as_deref(), Some("alice"));
+        assert!(envelope.timestamp.is_some());
+    }
+}
@@ -0,0 +1,194 @@
+// shared/src/format.rs
+
+use crate::{DeserializationError, Envelope, SerializationError};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// A serialization format for envelopes on the wire
+pub trait WireFormat: Send + Sync {
+    /// Serializes an envelope into the payload of a frame.
+    fn serialize(&self, envelope: &Envelope) -> Result<Vec<u8>, SerializationError>;
+
+    /// Deserializes an envelope from the payload of a frame.
+    fn deserialize(&self, data: &[u8]) -> Result<Envelope, DeserializationError>;
+}
+
+/// CBOR via `serde_cbor`, the original format of the protocol.
+pub struct Cbor;
+
+impl WireFormat for Cbor {
+    fn serialize(&self, envelope: &Envelope) -> Result<Vec<u8>, SerializationError> {
+        Ok(serde_cbor::to_vec(envelope)?)
+    }
+
+    fn deserialize(&self, data: &[u8]) -> Result<Envelope, DeserializationError> {
+        Ok(serde_cbor::from_slice(data)?)
+    }
+}
+
+/// MessagePack via `rmp-serde`, with structs encoded as maps.
+pub struct MessagePack;
+
+impl WireFormat for MessagePack {
+    fn serialize(&self, envelope: &Envelope) -> Result<Vec<u8>, SerializationError> {
+        Ok(rmp_serde::to_vec_named(envelope)?)
+    }
+
+    fn deserialize(&self, data: &[u8]) -> Result<Envelope, DeserializationError> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+}
+
+/// JSON via `serde_json`, readable when inspecting traffic by hand.
+pub struct Json;
+
+impl WireFormat for Json {
+    fn serialize(&self, envelope: &Envelope) -> Result<Vec<u8>, SerializationError> {
+        Ok(serde_json::to_vec(envelope)?)
+    }
+
+    fn deserialize(&self, data: &[u8]) -> Result<Envelope, DeserializationError> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// Bincode with variable-length integers.
+///
+/// Deserialization never allocates more than the size of the frame, so a
+/// bogus length inside the payload cannot blow up memory either.
+pub struct Bincode;
+
+impl WireFormat for Bincode {
+    fn serialize(&self, envelope: &Envelope) -> Result<Vec<u8>, SerializationError> {
+        Ok(bincode::DefaultOptions::new().serialize(envelope)?)
+    }
+
+    fn deserialize(&self, data: &[u8]) -> Result<Envelope, DeserializationError> {
+        Ok(bincode::DefaultOptions::new()
+            .with_limit(data.len() as u64)
+            .deserialize(data)?)
+    }
+}
+
+/// The wire formats a peer can offer in its `Hello` message.
+///
+/// The handshake itself is always encoded as CBOR; both peers switch to
+/// the negotiated format right after `HelloAck`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Format {
+    #[default]
+    Cbor,
+    MessagePack,
+    Json,
+    Bincode,
+}
+
+impl Format {
+    /// All formats implemented by this crate, in order of preference.
+    pub const ALL: &'static [Format] = &[
+        Format::MessagePack,
+        Format::Cbor,
+        Format::Bincode,
+        Format::Json,
+    ];
+
+    /// Returns the implementation of the format.
+    pub fn wire_format(self) -> &'static dyn WireFormat {
+        match self {
+            Format::Cbor => &Cbor,
+            Format::MessagePack => &MessagePack,
+            Format::Json => &Json,
+            Format::Bincode => &Bincode,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Cbor => "cbor",
+            Format::MessagePack => "msgpack",
+            Format::Json => "json",
+            Format::Bincode => "bincode",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cbor" => Ok(Format::Cbor),
+            "msgpack" | "messagepack" => Ok(Format::MessagePack),
+            "json" => Ok(Format::Json),
+            "bincode" => Ok(Format::Bincode),
+            _ => Err(format!(
+                "Unknown wire format '{}', expected cbor, msgpack, json or bincode",
+                s
+            )),
+        }
+    }
+}
+
+/// Chooses the wire format of a connection
+///
+/// Returns the first format offered by the peer that we support as well,
+/// falling back to CBOR, which every peer speaks.
+///
+/// # Arguments
+///
+/// * `offered` - The formats announced by the peer, in its order of
+///   preference.
+/// * `supported` - The formats we support.
+pub fn choose_format(offered: &[Format], supported: &[Format]) -> Format {
+    offered
+        .iter()
+        .copied()
+        .find(|format| supported.contains(format))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageType;
+
+    #[test]
+    fn test_all_formats_round_trip() {
+        let file = MessageType::File("a.txt".to_string(), vec![1, 2, 3], "digest".to_string());
+        let envelope = Envelope::new(file).stamp(Some("alice"));
+        for format in Format::ALL {
+            let serialized = format.wire_format().serialize(&envelope).unwrap();
+            let deserialized = format.wire_format().deserialize(&serialized).unwrap();
+            assert_eq!(deserialized.id, envelope.id, "{}", format);
+            assert_eq!(deserialized.timestamp, envelope.timestamp, "{}", format);
+            assert_eq!(deserialized.sender.as_deref(), Some("alice"), "{}", format);
+            assert!(
+                matches!(deserialized.message, MessageType::File(ref name, ref data, ref sha256)
+                    if name == "a.txt" && data == &[1, 2, 3] && sha256 == "digest"),
+                "{}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn test_choose_format() {
+        let offered = [Format::Json, Format::Cbor];
+        assert_eq!(choose_format(&offered, Format::ALL), Format::Json);
+        assert_eq!(choose_format(&offered, &[Format::Cbor]), Format::Cbor);
+        assert_eq!(choose_format(&[], Format::ALL), Format::Cbor);
+    }
+
+    #[test]
+    fn test_parse_format() {
+        for format in Format::ALL {
+            assert_eq!(format.to_string().parse::<Format>(), Ok(*format));
+        }
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
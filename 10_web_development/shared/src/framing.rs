@@ -0,0 +1,492 @@
+// shared/src/framing.rs
+
+use crate::compression::FLAG_UNCOMPRESSED;
+use crate::{
+    Compression, DeserializationError, Envelope, Format, MessageType, SerializationError,
+    COMPRESSION_THRESHOLD, MAX_ROOM_NAME_LEN,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use std::borrow::Borrow;
+use std::io::{Read, Write};
+use thiserror::Error;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Limits enforced on incoming frames
+///
+/// Every frame is a 4-byte big-endian length followed by the serialized
+/// envelope. The length is checked against `max_frame_size` before any
+/// buffer is allocated, so a hostile length prefix cannot make the receiver
+/// allocate gigabytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLimits {
+    /// Maximum size of a serialized envelope in bytes.
+    pub max_frame_size: usize,
+    /// Maximum length of a file name in bytes.
+    pub max_filename_len: usize,
+    /// Maximum length of a text message, an error message or a username in
+    /// bytes.
+    pub max_text_len: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            max_frame_size: 16 * 1024 * 1024,
+            max_filename_len: 255,
+            max_text_len: 64 * 1024,
+        }
+    }
+}
+
+impl FrameLimits {
+    /// Checks the fields of a message against the per-kind limits.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to check.
+    pub fn check(&self, message: &MessageType) -> Result<(), FrameError> {
+        match message {
+            MessageType::Text(text) => check_len("text", text, self.max_text_len),
+            MessageType::Error(err) => check_len("error", err, self.max_text_len),
+            MessageType::Login { username, password }
+            | MessageType::Register { username, password } => {
+                check_len("username", username, self.max_text_len)?;
+                check_len("password", password.expose(), self.max_text_len)
+            }
+            MessageType::Hello { client_name, .. } => {
+                check_len("client name", client_name, self.max_text_len)
+            }
+            MessageType::File(name, ..) | MessageType::FileStart { name, .. } => {
+                check_len("file name", name, self.max_filename_len)
+            }
+            MessageType::Join(room) | MessageType::Leave(room) => {
+                check_len("room name", room, MAX_ROOM_NAME_LEN)
+            }
+            MessageType::RoomMessage { room, body } => {
+                check_len("room name", room, MAX_ROOM_NAME_LEN)?;
+                check_len("text", body, self.max_text_len)
+            }
+            MessageType::Resume(token) => check_len("session token", token, self.max_text_len),
+            MessageType::Direct { to, body } => {
+                check_len("username", to, self.max_text_len)?;
+                check_len("text", body, self.max_text_len)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn check_len(field: &'static str, value: &str, limit: usize) -> Result<(), FrameError> {
+    if value.len() > limit {
+        return Err(FrameError::FieldTooLong {
+            field,
+            len: value.len(),
+            limit,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("Frame of {size} bytes exceeds the limit of {limit} bytes")]
+    TooLarge { size: usize, limit: usize },
+    #[error("The {field} is {len} bytes long, the limit is {limit} bytes")]
+    FieldTooLong {
+        field: &'static str,
+        len: usize,
+        limit: usize,
+    },
+    #[error("Decompressed frame exceeds the limit of {limit} bytes")]
+    DecompressedTooLarge { limit: usize },
+    #[error("Unknown compression flag {0}")]
+    UnknownCompression(u8),
+    #[error("Compression failed: {0}")]
+    Compression(#[source] std::io::Error),
+    #[error("Connection error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Serialization(#[from] SerializationError),
+    #[error(transparent)]
+    Deserialization(#[from] DeserializationError),
+}
+
+/// Size of the big-endian length prefix of every frame.
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Codec for the length-prefixed framing
+///
+/// Use it with `tokio_util::codec::Framed` (or `FramedRead`/`FramedWrite`)
+/// to turn a byte stream into a stream and sink of envelopes. The payload of
+/// each frame is the envelope in the codec's wire format, CBOR until the
+/// handshake negotiates another one (see `set_format`). Incoming
+/// frames larger than `FrameLimits::max_frame_size` are rejected with
+/// `FrameError::TooLarge` as soon as their length prefix arrives; the
+/// message fields are left to `FrameLimits::check`, so callers can refuse a
+/// single message without dropping the connection.
+///
+/// Once the handshake negotiates compression (see `set_compression`), every
+/// payload is preceded by a flag byte telling whether and how it is
+/// compressed. Payloads under `COMPRESSION_THRESHOLD` bytes, images, which
+/// are compressed already, and payloads that do not shrink are sent as they
+/// are.
+#[derive(Debug, Clone, Default)]
+pub struct EnvelopeCodec {
+    limits: FrameLimits,
+    format: Format,
+    compression: Option<Compression>,
+}
+
+impl EnvelopeCodec {
+    pub fn new(limits: FrameLimits) -> Self {
+        EnvelopeCodec {
+            limits,
+            format: Format::default(),
+            compression: None,
+        }
+    }
+
+    /// The limits enforced by the codec.
+    pub fn limits(&self) -> &FrameLimits {
+        &self.limits
+    }
+
+    /// The wire format of the frames.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Switches to another wire format for all following frames.
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - The format negotiated in the handshake.
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// The compression of the frames, `None` when frames carry no flag byte.
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// Switches compression on or off for all following frames.
+    ///
+    /// # Arguments
+    ///
+    /// * `compression` - The compression negotiated in the handshake.
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    /// Serializes an envelope into the body of a frame, compressing it
+    /// when that is worthwhile.
+    fn encode_body(&self, envelope: &Envelope) -> Result<Vec<u8>, FrameError> {
+        let payload = self.format.wire_format().serialize(envelope)?;
+        let Some(compression) = self.compression else {
+            return Ok(payload);
+        };
+
+        if payload.len() >= COMPRESSION_THRESHOLD
+            && !matches!(envelope.message, MessageType::Image(..))
+        {
+            let compressed = compression
+                .compress(&payload)
+                .map_err(FrameError::Compression)?;
+            if compressed.len() < payload.len() {
+                let mut body = Vec::with_capacity(1 + compressed.len());
+                body.push(compression.flag());
+                body.extend(compressed);
+                return Ok(body);
+            }
+        }
+        let mut body = Vec::with_capacity(1 + payload.len());
+        body.push(FLAG_UNCOMPRESSED);
+        body.extend(payload);
+        Ok(body)
+    }
+
+    /// Deserializes an envelope from the body of a frame, decompressing it
+    /// first if its flag says so.
+    fn decode_body(&self, body: &[u8]) -> Result<Envelope, FrameError> {
+        let wire_format = self.format.wire_format();
+        if self.compression.is_none() {
+            return Ok(wire_format.deserialize(body)?);
+        }
+
+        let (&flag, payload) = body.split_first().unwrap_or((&FLAG_UNCOMPRESSED, &[]));
+        match Compression::from_flag(flag).map_err(FrameError::UnknownCompression)? {
+            None => Ok(wire_format.deserialize(payload)?),
+            Some(compression) => {
+                let limit = self.limits.max_frame_size;
+                let payload = compression
+                    .decompress(payload, limit)
+                    .map_err(FrameError::Compression)?;
+                if payload.len() > limit {
+                    return Err(FrameError::DecompressedTooLarge { limit });
+                }
+                Ok(wire_format.deserialize(&payload)?)
+            }
+        }
+    }
+}
+
+impl Decoder for EnvelopeCodec {
+    type Item = Envelope;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Envelope>, FrameError> {
+        if src.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+        let mut len_bytes = [0u8; LENGTH_PREFIX_SIZE];
+        len_bytes.copy_from_slice(&src[..LENGTH_PREFIX_SIZE]);
+        let len = frame_len(len_bytes, &self.limits)?;
+
+        if src.len() < LENGTH_PREFIX_SIZE + len {
+            // Only reserve what the frame needs; its size was checked above
+            src.reserve(LENGTH_PREFIX_SIZE + len - src.len());
+            return Ok(None);
+        }
+        src.advance(LENGTH_PREFIX_SIZE);
+        let frame = src.split_to(len);
+        Ok(Some(self.decode_body(&frame)?))
+    }
+}
+
+impl<T: Borrow<Envelope>> Encoder<T> for EnvelopeCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, envelope: T, dst: &mut BytesMut) -> Result<(), FrameError> {
+        let body = self.encode_body(envelope.borrow())?;
+        dst.reserve(LENGTH_PREFIX_SIZE + body.len());
+        dst.put_slice(&length_prefix(body.len())?);
+        dst.put_slice(&body);
+        Ok(())
+    }
+}
+
+/// Reads one length-prefixed envelope from a blocking reader
+///
+/// This is the `std::io` counterpart of `EnvelopeCodec`'s decoder.
+///
+/// # Arguments
+///
+/// * `reader` - The stream to read from.
+/// * `codec` - The codec holding the limits, format and compression of the
+///   connection.
+pub fn read_frame<R: Read>(reader: &mut R, codec: &EnvelopeCodec) -> Result<Envelope, FrameError> {
+    let mut len_bytes = [0u8; LENGTH_PREFIX_SIZE];
+    reader.read_exact(&mut len_bytes)?;
+    let len = frame_len(len_bytes, &codec.limits)?;
+
+    let mut buffer = vec![0u8; len];
+    reader.read_exact(&mut buffer)?;
+    codec.decode_body(&buffer)
+}
+
+/// Writes one envelope with its length prefix to a blocking writer
+///
+/// This is the `std::io` counterpart of `EnvelopeCodec`'s encoder.
+///
+/// # Arguments
+///
+/// * `writer` - The stream to write to.
+/// * `envelope` - The envelope to send.
+/// * `codec` - The codec holding the format and compression of the
+///   connection.
+pub fn write_frame<W: Write>(
+    writer: &mut W,
+    envelope: &Envelope,
+    codec: &EnvelopeCodec,
+) -> Result<(), FrameError> {
+    let body = codec.encode_body(envelope)?;
+    writer.write_all(&length_prefix(body.len())?)?;
+    writer.write_all(&body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Decodes a length prefix, checking it against the frame size limit.
+fn frame_len(
+    len_bytes: [u8; LENGTH_PREFIX_SIZE],
+    limits: &FrameLimits,
+) -> Result<usize, FrameError> {
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > limits.max_frame_size {
+        return Err(FrameError::TooLarge {
+            size: len,
+            limit: limits.max_frame_size,
+        });
+    }
+    Ok(len)
+}
+
+/// Encodes the length prefix of a frame of `len` bytes.
+fn length_prefix(len: usize) -> Result<[u8; LENGTH_PREFIX_SIZE], FrameError> {
+    let len = u32::try_from(len).map_err(|_| FrameError::TooLarge {
+        size: len,
+        limit: u32::MAX as usize,
+    })?;
+    Ok(len.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialize_message;
+
+    fn frame(envelope: &Envelope) -> Vec<u8> {
+        let serialized = serialize_message(envelope).unwrap();
+        let mut frame = (serialized.len() as u32).to_be_bytes().to_vec();
+        frame.extend(serialized);
+        frame
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_length_prefix() {
+        let mut src = BytesMut::from(&u32::MAX.to_be_bytes()[..]);
+        let err = EnvelopeCodec::default().decode(&mut src).unwrap_err();
+        assert!(matches!(err, FrameError::TooLarge { size, .. } if size == u32::MAX as usize));
+        assert!(src.capacity() < 1024);
+    }
+
+    #[test]
+    fn test_decode_waits_for_whole_frame() {
+        let envelope = Envelope::new(MessageType::Text("hi".to_string()));
+        let frame = frame(&envelope);
+        let mut codec = EnvelopeCodec::default();
+
+        let mut src = BytesMut::from(&frame[..frame.len() - 1]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.put_u8(frame[frame.len() - 1]);
+        let decoded = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(decoded.id, envelope.id);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_encode_matches_blocking_write() {
+        let envelope = Envelope::new(MessageType::Text("hi".to_string()));
+        let mut dst = BytesMut::new();
+        EnvelopeCodec::default()
+            .encode(&envelope, &mut dst)
+            .unwrap();
+        let mut written = Vec::new();
+        write_frame(&mut written, &envelope, &EnvelopeCodec::default()).unwrap();
+        assert_eq!(&dst[..], &frame(&envelope)[..]);
+        assert_eq!(written, frame(&envelope));
+
+        let read = read_frame(&mut written.as_slice(), &EnvelopeCodec::default()).unwrap();
+        assert_eq!(read.id, envelope.id);
+    }
+
+    #[test]
+    fn test_codec_switches_format() {
+        let envelope = Envelope::new(MessageType::Text("hi".to_string()));
+        let mut codec = EnvelopeCodec::default();
+        codec.set_format(Format::Json);
+        let mut dst = BytesMut::new();
+        codec.encode(&envelope, &mut dst).unwrap();
+        assert!(dst[LENGTH_PREFIX_SIZE..].starts_with(b"{"));
+
+        let decoded = codec.decode(&mut dst).unwrap().unwrap();
+        assert_eq!(decoded.id, envelope.id);
+    }
+
+    #[test]
+    fn test_codec_compresses_large_frames() {
+        let mut codec = EnvelopeCodec::default();
+        codec.set_compression(Some(Compression::Zstd));
+        let text = "2024-06-01,INFO,all good\n".repeat(100);
+        let envelope = Envelope::new(MessageType::Text(text.clone()));
+        let mut dst = BytesMut::new();
+        codec.encode(&envelope, &mut dst).unwrap();
+        assert_eq!(dst[LENGTH_PREFIX_SIZE], Compression::Zstd.flag());
+        assert!(dst.len() < text.len() / 5);
+
+        let small = Envelope::new(MessageType::Text("hi".to_string()));
+        codec.encode(&small, &mut dst).unwrap();
+        let image = Envelope::new(MessageType::Image(vec![0; 4096], String::new()));
+        codec.encode(&image, &mut dst).unwrap();
+
+        let decoded = codec.decode(&mut dst).unwrap().unwrap();
+        assert!(matches!(decoded.message, MessageType::Text(ref t) if *t == text));
+        assert_eq!(dst[LENGTH_PREFIX_SIZE], FLAG_UNCOMPRESSED);
+        assert_eq!(codec.decode(&mut dst).unwrap().unwrap().id, small.id);
+        assert_eq!(dst[LENGTH_PREFIX_SIZE], FLAG_UNCOMPRESSED);
+        assert_eq!(codec.decode(&mut dst).unwrap().unwrap().id, image.id);
+    }
+
+    #[test]
+    fn test_decode_rejects_decompression_bomb() {
+        let limits = FrameLimits {
+            max_frame_size: 64 * 1024,
+            ..FrameLimits::default()
+        };
+        let mut codec = EnvelopeCodec::new(limits);
+        codec.set_compression(Some(Compression::Deflate));
+        let bomb = Compression::Deflate
+            .compress(&vec![0; 1024 * 1024])
+            .unwrap();
+        let mut src = BytesMut::new();
+        src.put_slice(&length_prefix(1 + bomb.len()).unwrap());
+        src.put_u8(Compression::Deflate.flag());
+        src.put_slice(&bomb);
+
+        let err = codec.decode(&mut src).unwrap_err();
+        assert!(matches!(err, FrameError::DecompressedTooLarge { limit } if limit == 64 * 1024));
+    }
+
+    #[test]
+    fn test_read_frame_rejects_oversized_length_prefix() {
+        let limits = FrameLimits {
+            max_frame_size: 16,
+            ..FrameLimits::default()
+        };
+        let mut stream: &[u8] = &17u32.to_be_bytes();
+        let err = read_frame(&mut stream, &EnvelopeCodec::new(limits)).unwrap_err();
+        assert!(matches!(
+            err,
+            FrameError::TooLarge {
+                size: 17,
+                limit: 16
+            }
+        ));
+    }
+    #[test]
+    fn test_check_field_limits() {
+        let limits = FrameLimits {
+            max_frame_size: 1024,
+            max_filename_len: 8,
+            max_text_len: 4,
+        };
+        assert!(limits.check(&MessageType::Text("hi".to_string())).is_ok());
+        assert!(matches!(
+            limits.check(&MessageType::Text("hello".to_string())),
+            Err(FrameError::FieldTooLong {
+                field: "text",
+                len: 5,
+                limit: 4
+            })
+        ));
+        let file = MessageType::File("long_name.txt".to_string(), vec![], String::new());
+        assert!(matches!(
+            limits.check(&file),
+            Err(FrameError::FieldTooLong {
+                field: "file name",
+                ..
+            })
+        ));
+        let image = MessageType::Image(vec![0; 2048], String::new());
+        assert!(limits.check(&image).is_ok());
+        let room_message = MessageType::RoomMessage {
+            room: "general".to_string(),
+            body: "hello".to_string(),
+        };
+        assert!(matches!(
+            limits.check(&room_message),
+            Err(FrameError::FieldTooLong { field: "text", .. })
+        ));
+    }
+}
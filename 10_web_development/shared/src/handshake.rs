@@ -0,0 +1,105 @@
+// shared/src/handshake.rs
+
+use serde::{Deserialize, Serialize};
+
+/// Version of the chat protocol implemented by this crate.
+///
+/// Bump it whenever a change to `MessageType` or to the framing would make
+/// older peers misread the traffic.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Optional protocol features a peer can announce in its `Hello` message.
+///
+/// Only the capabilities both peers announce are enabled on a connection,
+/// and message kinds that need a capability which was not negotiated are
+/// refused.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Sending and receiving `MessageType::Image`.
+    Images,
+    /// Sending and receiving `MessageType::File`.
+    Files,
+    /// Chunked, resumable file transfers (`MessageType::FileStart` and
+    /// friends).
+    ChunkedFiles,
+    /// Named chat rooms (`MessageType::Join`, `MessageType::RoomMessage`
+    /// and friends).
+    Rooms,
+    /// Private messages between users (`MessageType::Direct`).
+    DirectMessages,
+    /// Session tokens and resuming a session after a reconnect
+    /// (`MessageType::SessionToken` and `MessageType::Resume`).
+    Sessions,
+    /// Heartbeats that detect dead connections (`MessageType::Ping` and
+    /// `MessageType::Pong`).
+    Heartbeats,
+    /// The online user list, user statuses and presence notifications
+    /// (`MessageType::Who`, `Users`, `SetStatus` and `Presence`).
+    Presence,
+    /// Message history sent after login and paged through on request
+    /// (`MessageType::FetchHistory` and `History`).
+    History,
+}
+
+impl Capability {
+    /// All capabilities implemented by this crate.
+    pub const ALL: &'static [Capability] = &[
+        Capability::Images,
+        Capability::Files,
+        Capability::ChunkedFiles,
+        Capability::Rooms,
+        Capability::DirectMessages,
+        Capability::Sessions,
+        Capability::Heartbeats,
+        Capability::Presence,
+        Capability::History,
+    ];
+}
+
+/// Checks whether a peer speaking `protocol_version` can talk to us.
+pub fn is_compatible(protocol_version: u32) -> bool {
+    protocol_version == PROTOCOL_VERSION
+}
+
+/// Negotiates the capabilities enabled on a connection
+///
+/// Returns the capabilities offered by the peer that we support as well,
+/// in the order the peer listed them and without duplicates.
+///
+/// # Arguments
+///
+/// * `offered` - The capabilities announced by the peer.
+/// * `supported` - The capabilities we support.
+pub fn negotiate(offered: &[Capability], supported: &[Capability]) -> Vec<Capability> {
+    let mut negotiated = Vec::new();
+    for capability in offered {
+        if supported.contains(capability) && !negotiated.contains(capability) {
+            negotiated.push(*capability);
+        }
+    }
+    negotiated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_keeps_common_capabilities() {
+        let offered = [Capability::Files, Capability::Images, Capability::Files];
+        let negotiated = negotiate(&offered, &[Capability::Images, Capability::Files]);
+        assert_eq!(negotiated, vec![Capability::Files, Capability::Images]);
+    }
+
+    #[test]
+    fn test_negotiate_drops_unsupported_capabilities() {
+        let negotiated = negotiate(Capability::ALL, &[Capability::Files]);
+        assert_eq!(negotiated, vec![Capability::Files]);
+    }
+
+    #[test]
+    fn test_is_compatible() {
+        assert!(is_compatible(PROTOCOL_VERSION));
+        assert!(!is_compatible(PROTOCOL_VERSION + 1));
+    }
+}
@@ -0,0 +1,54 @@
+// shared/src/heartbeat.rs
+
+use std::time::Duration;
+
+/// Default time between two heartbeats.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Default number of heartbeats a peer may miss before it is considered gone.
+pub const DEFAULT_MAX_MISSED_HEARTBEATS: u32 = 3;
+
+/// Heartbeat settings of one side of a connection
+///
+/// Each side pings the other every `interval` and answers pings with a
+/// pong. A peer that sends nothing at all, not even a pong, for `timeout()`
+/// is considered gone, even if its TCP connection looks open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// Time between two pings.
+    pub interval: Duration,
+    /// Heartbeats the peer may miss before it is considered gone.
+    pub max_missed: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: DEFAULT_HEARTBEAT_INTERVAL,
+            max_missed: DEFAULT_MAX_MISSED_HEARTBEATS,
+        }
+    }
+}
+
+impl Heartbeat {
+    /// Returns how long the peer may stay silent before it is considered
+    /// gone.
+    pub fn timeout(&self) -> Duration {
+        self.interval * self.max_missed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeout_covers_missed_heartbeats() {
+        assert_eq!(Heartbeat::default().timeout(), Duration::from_secs(45));
+        let heartbeat = Heartbeat {
+            interval: Duration::from_secs(2),
+            max_missed: 5,
+        };
+        assert_eq!(heartbeat.timeout(), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,52 @@
+// shared/src/history.rs
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+/// The most messages the server sends in one `MessageType::History` page.
+pub const MAX_HISTORY_PAGE: u32 = 100;
+
+/// Parses a point in time given by a user, in UTC
+///
+/// Accepts RFC 3339 timestamps, the `YYYY-MM-DD HH:MM[:SS]` format messages
+/// are displayed in, and bare dates, which mean midnight.
+///
+/// # Arguments
+///
+/// * `input` - The time as typed by the user.
+pub fn parse_history_time(input: &str) -> Option<DateTime<Utc>> {
+    let input = input.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(input) {
+        return Some(time.with_timezone(&Utc));
+    }
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(input, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(input, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .map(|time| time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_history_time() {
+        let expected = Utc.with_ymd_and_hms(2024, 5, 17, 14, 30, 0).unwrap();
+        assert_eq!(parse_history_time("2024-05-17 14:30"), Some(expected));
+        assert_eq!(parse_history_time("2024-05-17 14:30:00"), Some(expected));
+        assert_eq!(
+            parse_history_time("2024-05-17T16:30:00+02:00"),
+            Some(expected)
+        );
+        assert_eq!(
+            parse_history_time("2024-05-17"),
+            Some(Utc.with_ymd_and_hms(2024, 5, 17, 0, 0, 0).unwrap())
+        );
+        assert_eq!(parse_history_time("yesterday"), None);
+    }
+}
@@ -1,43 +1,269 @@
 // shared/src/lib.rs
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use serde_cbor;
 use thiserror::Error;
 use tracing::instrument;
+use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug)]
+mod compression;
+mod envelope;
+mod format;
+mod framing;
+mod handshake;
+mod heartbeat;
+mod history;
+mod password;
+mod presence;
+mod room;
+mod transfer;
+mod user;
+
+pub use compression::{choose_compression, Compression, COMPRESSION_THRESHOLD};
+pub use envelope::Envelope;
+pub use format::{choose_format, Bincode, Cbor, Format, Json, MessagePack, WireFormat};
+pub use framing::{read_frame, write_frame, EnvelopeCodec, FrameError, FrameLimits};
+pub use handshake::{is_compatible, negotiate, Capability, PROTOCOL_VERSION};
+pub use heartbeat::{Heartbeat, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_MAX_MISSED_HEARTBEATS};
+pub use history::{parse_history_time, MAX_HISTORY_PAGE};
+pub use password::Password;
+pub use presence::{OnlineUser, PresenceEvent, Status};
+pub use room::{is_valid_room_name, MAX_ROOM_NAME_LEN};
+pub use transfer::{
+    image_extension, is_sha256_hex, received_image_name, sanitize_file_name, sha256_file,
+    sha256_hex, FILE_CHUNK_SIZE,
+};
+pub use user::{is_valid_username, MAX_USERNAME_LEN};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MessageType {
     Text(String),
-    Image(Vec<u8>),
-    File(String, Vec<u8>),
+    /// An image and the hex-encoded SHA-256 digest of its bytes.
+    Image(Vec<u8>, String),
+    /// A file name, the file's content and the hex-encoded SHA-256 digest
+    /// of the content.
+    File(String, Vec<u8>, String),
     Quit,
     Error(String),
-    Login(String),
-    Register(String),
+    /// Logs in as an existing user.
+    Login {
+        username: String,
+        password: Password,
+    },
+    /// Creates a new user and logs in as it.
+    Register {
+        username: String,
+        password: Password,
+    },
+    /// The first message a client sends, announcing the protocol version
+    /// it speaks, the capabilities it supports, and the wire formats and
+    /// compression algorithms it speaks in order of preference.
+    Hello {
+        protocol_version: u32,
+        client_name: String,
+        capabilities: Vec<Capability>,
+        #[serde(default)]
+        formats: Vec<Format>,
+        #[serde(default)]
+        compression: Vec<Compression>,
+    },
+    /// The server's answer to a compatible `Hello`, listing the
+    /// capabilities enabled on the connection and the wire format and
+    /// compression both peers use from now on.
+    HelloAck {
+        protocol_version: u32,
+        capabilities: Vec<Capability>,
+        #[serde(default)]
+        format: Format,
+        #[serde(default)]
+        compression: Option<Compression>,
+    },
+    /// Starts a chunked file transfer of `size` bytes whose content has the
+    /// hex-encoded SHA-256 digest `sha256`.
+    ///
+    /// The server answers an upload with `FileOffset`, so an upload of the
+    /// same content that was interrupted earlier resumes where it stopped.
+    /// Files relayed by the server to other clients always start at 0.
+    FileStart {
+        transfer_id: Uuid,
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    /// The receiver's answer to `FileStart`: the sender continues with the
+    /// chunk starting at `offset`.
+    FileOffset {
+        transfer_id: Uuid,
+        offset: u64,
+    },
+    /// Up to `FILE_CHUNK_SIZE` bytes of the file starting at `offset`.
+    FileChunk {
+        transfer_id: Uuid,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Ends a chunked file transfer; the receiver verifies the size and
+    /// digest and commits the file.
+    FileFinish {
+        transfer_id: Uuid,
+    },
+    /// Joins the named room. The server answers with the room's recent
+    /// messages, and from then on relays the messages posted to it.
+    Join(String),
+    /// Leaves the named room.
+    Leave(String),
+    /// A text message posted to a room, relayed to its members only.
+    RoomMessage {
+        room: String,
+        body: String,
+    },
+    /// Asks the server for the names of all rooms.
+    ListRooms,
+    /// The server's answer to `ListRooms`.
+    Rooms(Vec<String>),
+    /// A private text message to the user `to`. The server relays it to
+    /// the recipient's sessions only, or keeps it until the recipient logs
+    /// in.
+    Direct {
+        to: String,
+        body: String,
+    },
+    /// A signed token the server issues after a successful login. A client
+    /// that lost its connection sends it back in `Resume`.
+    SessionToken(String),
+    /// Logs in again after a reconnect with a token from `SessionToken`.
+    /// The server then replays the messages the session missed.
+    Resume(String),
+    /// Asks the peer to show it is still there. Either side sends it
+    /// periodically, see `Heartbeat`.
+    Ping,
+    /// Answers a `Ping`.
+    Pong,
+    /// Asks the server which users are logged in.
+    Who,
+    /// The users who are logged in, sorted by username.
+    Users(Vec<OnlineUser>),
+    /// Sets the status the user is shown with.
+    SetStatus(Status),
+    /// Tells clients that a user's presence changed.
+    Presence {
+        username: String,
+        event: PresenceEvent,
+    },
+    /// Asks the server for up to `limit` public messages posted before
+    /// `before` and after `since`, the latest ones first if there are more.
+    FetchHistory {
+        before: Option<DateTime<Utc>>,
+        since: Option<DateTime<Utc>>,
+        limit: u32,
+    },
+    /// A page of public messages, oldest first, each in its original
+    /// envelope with ID, sender and timestamp. Sent in answer to
+    /// `FetchHistory` and after login.
+    History(Vec<Envelope>),
+}
+
+impl MessageType {
+    /// Returns the name of the message's variant, for logging a message
+    /// without its content.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MessageType::Text(_) => "Text",
+            MessageType::Image(..) => "Image",
+            MessageType::File(..) => "File",
+            MessageType::Quit => "Quit",
+            MessageType::Error(_) => "Error",
+            MessageType::Login { .. } => "Login",
+            MessageType::Register { .. } => "Register",
+            MessageType::Hello { .. } => "Hello",
+            MessageType::HelloAck { .. } => "HelloAck",
+            MessageType::FileStart { .. } => "FileStart",
+            MessageType::FileOffset { .. } => "FileOffset",
+            MessageType::FileChunk { .. } => "FileChunk",
+            MessageType::FileFinish { .. } => "FileFinish",
+            MessageType::Join(_) => "Join",
+            MessageType::Leave(_) => "Leave",
+            MessageType::RoomMessage { .. } => "RoomMessage",
+            MessageType::ListRooms => "ListRooms",
+            MessageType::Rooms(_) => "Rooms",
+            MessageType::Direct { .. } => "Direct",
+            MessageType::SessionToken(_) => "SessionToken",
+            MessageType::Resume(_) => "Resume",
+            MessageType::Ping => "Ping",
+            MessageType::Pong => "Pong",
+            MessageType::Who => "Who",
+            MessageType::Users(_) => "Users",
+            MessageType::SetStatus(_) => "SetStatus",
+            MessageType::Presence { .. } => "Presence",
+            MessageType::FetchHistory { .. } => "FetchHistory",
+            MessageType::History(_) => "History",
+        }
+    }
+
+    /// Returns the capability that must be negotiated before this message
+    /// may be sent over a connection, if any.
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self {
+            MessageType::Image(..) => Some(Capability::Images),
+            MessageType::File(..) => Some(Capability::Files),
+            MessageType::FileStart { .. }
+            | MessageType::FileOffset { .. }
+            | MessageType::FileChunk { .. }
+            | MessageType::FileFinish { .. } => Some(Capability::ChunkedFiles),
+            MessageType::Join(_)
+            | MessageType::Leave(_)
+            | MessageType::RoomMessage { .. }
+            | MessageType::ListRooms
+            | MessageType::Rooms(_) => Some(Capability::Rooms),
+            MessageType::Direct { .. } => Some(Capability::DirectMessages),
+            MessageType::SessionToken(_) | MessageType::Resume(_) => Some(Capability::Sessions),
+            MessageType::Ping | MessageType::Pong => Some(Capability::Heartbeats),
+            MessageType::Who
+            | MessageType::Users(_)
+            | MessageType::SetStatus(_)
+            | MessageType::Presence { .. } => Some(Capability::Presence),
+            MessageType::FetchHistory { .. } | MessageType::History(_) => Some(Capability::History),
+            _ => None,
+        }
+    }
 }
 
-// Function to serialize a message
+// Function to serialize a message together with its envelope in the default
+// CBOR format
 #[instrument]
-pub fn serialize_message(message: &MessageType) -> Result<Vec<u8>, SerializationError> {
-    serde_cbor::to_vec(&message).map_err(SerializationError::from)
+pub fn serialize_message(envelope: &Envelope) -> Result<Vec<u8>, SerializationError> {
+    Cbor.serialize(envelope)
 }
 
-// Function to deserialize a message
+// Function to deserialize a message together with its envelope from the
+// default CBOR format
 #[instrument]
-pub fn deserialize_message(data: &[u8]) -> Result<MessageType, DeserializationError> {
-    serde_cbor::from_slice(&data).map_err(DeserializationError::from)
+pub fn deserialize_message(data: &[u8]) -> Result<Envelope, DeserializationError> {
+    Cbor.deserialize(data)
 }
 
 #[derive(Error, Debug)]
 pub enum SerializationError {
     #[error("Serialization failed: {0}")]
     Cbor(#[from] serde_cbor::Error),
+    #[error("Serialization failed: {0}")]
+    MessagePack(#[from] rmp_serde::encode::Error),
+    #[error("Serialization failed: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Serialization failed: {0}")]
+    Bincode(#[from] bincode::Error),
 }
 
 #[derive(Error, Debug)]
 pub enum DeserializationError {
     #[error("Deserialization failed: {0}")]
     Cbor(#[from] serde_cbor::Error),
+    #[error("Deserialization failed: {0}")]
+    MessagePack(#[from] rmp_serde::decode::Error),
+    #[error("Deserialization failed: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Deserialization failed: {0}")]
+    Bincode(#[from] bincode::Error),
 }
 
 #[cfg(test)]
@@ -47,8 +273,8 @@ mod tests {
   #[test]
   fn test_serialize_deserialize_text_message() {
       let message = MessageType::Text("Příliš žluťoučký kůň úpěl ďábelské ódy!".to_string());
-      let serialized = serialize_message(&message).unwrap();
-      let deserialized: MessageType = deserialize_message(&serialized).unwrap();
+      let serialized = serialize_message(&Envelope::new(message)).unwrap();
+      let deserialized: MessageType = deserialize_message(&serialized).unwrap().message;
       
       if let MessageType::Text(text) = deserialized {
          assert_eq!(text, "Příliš žluťoučký kůň úpěl ďábelské ódy!")   
@@ -59,12 +285,13 @@ mod tests {
   
   #[test]
   fn test_serialize_deserialize_image_message() {
-     let message = MessageType::Image(vec![1, 2, 3, 4, 5]);
-     let serialized = serialize_message(&message).unwrap();
-     let deserialized: MessageType = deserialize_message(&serialized).unwrap();
+     let message = MessageType::Image(vec![1, 2, 3, 4, 5], sha256_hex(&[1, 2, 3, 4, 5]));
+     let serialized = serialize_message(&Envelope::new(message)).unwrap();
+     let deserialized: MessageType = deserialize_message(&serialized).unwrap().message;
      
-     if let MessageType::Image(data) = deserialized {
+     if let MessageType::Image(data, sha256) = deserialized {
         assert_eq!(data, vec![1, 2, 3, 4, 5]);
+        assert_eq!(sha256, sha256_hex(&data));
      } else {
          panic!("Deserialized message is not of type Image");   
      }
@@ -72,15 +299,89 @@ mod tests {
   
   #[test]
   fn test_serialize_deserialize_file_message() {
-      let message = MessageType::File("test.txt".to_string(), vec![1, 2, 3, 4, 5, 6]);
-      let serialized = serialize_message(&message).unwrap();
-      let deserialized: MessageType = deserialize_message(&serialized).unwrap();
+      let data = vec![1, 2, 3, 4, 5, 6];
+      let message = MessageType::File("test.txt".to_string(), data.clone(), sha256_hex(&data));
+      let serialized = serialize_message(&Envelope::new(message)).unwrap();
+      let deserialized: MessageType = deserialize_message(&serialized).unwrap().message;
       
-      if let MessageType::File(filename, data) = deserialized {
+      if let MessageType::File(filename, data, sha256) = deserialized {
          assert_eq!(filename, "test.txt");
          assert_eq!(data, vec![1, 2, 3, 4, 5, 6]);
+         assert_eq!(sha256, sha256_hex(&data));
       } else {
          panic!("Deserialized message is not of type File");   
       }
   }
+  
+  #[test]
+  fn test_serialize_deserialize_hello_message() {
+      let message = MessageType::Hello {
+          protocol_version: PROTOCOL_VERSION,
+          client_name: "client".to_string(),
+          capabilities: vec![Capability::Images],
+          formats: vec![Format::Json],
+          compression: vec![Compression::Zstd],
+      };
+      let serialized = serialize_message(&Envelope::new(message)).unwrap();
+      let deserialized: MessageType = deserialize_message(&serialized).unwrap().message;
+      
+      if let MessageType::Hello { protocol_version, client_name, capabilities, formats, compression } = deserialized {
+         assert_eq!(protocol_version, PROTOCOL_VERSION);
+         assert_eq!(client_name, "client");
+         assert_eq!(capabilities, vec![Capability::Images]);
+         assert_eq!(formats, vec![Format::Json]);
+         assert_eq!(compression, vec![Compression::Zstd]);
+      } else {
+         panic!("Deserialized message is not of type Hello");   
+      }
+  }
+  
+  #[test]
+  fn test_required_capability() {
+      assert_eq!(MessageType::Text("hi".to_string()).required_capability(), None);
+      let image = MessageType::Image(vec![], sha256_hex(&[]));
+      assert_eq!(image.required_capability(), Some(Capability::Images));
+      let file = MessageType::File("a.txt".to_string(), vec![], sha256_hex(&[]));
+      assert_eq!(file.required_capability(), Some(Capability::Files));
+      let room_message = MessageType::RoomMessage { room: "general".to_string(), body: "hi".to_string() };
+      assert_eq!(room_message.required_capability(), Some(Capability::Rooms));
+  }
+  
+  #[test]
+  fn test_kind_omits_content() {
+      let message = MessageType::Direct { to: "bob".to_string(), body: "secret".to_string() };
+      assert_eq!(message.kind(), "Direct");
+      assert_eq!(MessageType::Ping.kind(), "Ping");
+  }
+  
+  #[test]
+  fn test_serialize_deserialize_envelope_metadata() {
+      let request = Envelope::new(MessageType::Text("hi".to_string()));
+      let envelope = Envelope::reply_to(request.id, MessageType::Error("nope".to_string()))
+          .stamp(Some("alice"));
+      let serialized = serialize_message(&envelope).unwrap();
+      let deserialized = deserialize_message(&serialized).unwrap();
+      
+      assert_eq!(deserialized.id, envelope.id);
+      assert_eq!(deserialized.timestamp, envelope.timestamp);
+      assert_eq!(deserialized.sender.as_deref(), Some("alice"));
+      assert_eq!(deserialized.correlation_id, Some(request.id));
+      assert!(matches!(deserialized.message, MessageType::Error(ref err) if err == "nope"));
+  }
+  
+  #[test]
+  fn test_serialize_deserialize_file_chunk_message() {
+      let transfer_id = Uuid::new_v4();
+      let message = MessageType::FileChunk { transfer_id, offset: 65536, data: vec![7, 8, 9] };
+      let serialized = serialize_message(&Envelope::new(message)).unwrap();
+      let deserialized: MessageType = deserialize_message(&serialized).unwrap().message;
+      
+      if let MessageType::FileChunk { transfer_id: id, offset, data } = deserialized {
+         assert_eq!(id, transfer_id);
+         assert_eq!(offset, 65536);
+         assert_eq!(data, vec![7, 8, 9]);
+      } else {
+         panic!("Deserialized message is not of type FileChunk");   
+      }
+  }
 }
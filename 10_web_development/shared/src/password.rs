@@ -0,0 +1,58 @@
+// shared/src/password.rs
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A plaintext password on its way from the client to the server
+///
+/// The password travels as a plain string on the wire, but its `Debug`
+/// output is redacted, so logging a message that carries one never leaks
+/// it. Use `expose` where the password is actually needed.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Password(String);
+
+impl Password {
+    /// Wraps a plaintext password.
+    ///
+    /// # Arguments
+    ///
+    /// * `password` - The plaintext password.
+    pub fn new(password: impl Into<String>) -> Self {
+        Password(password.into())
+    }
+
+    /// Returns the plaintext password.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Password(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageType;
+
+    #[test]
+    fn test_debug_redacts_password() {
+        let message = MessageType::Login {
+            username: "alice".to_string(),
+            password: Password::new("hunter22"),
+        };
+        let debug = format!("{:?}", message);
+        assert!(debug.contains("alice"));
+        assert!(!debug.contains("hunter22"));
+    }
+
+    #[test]
+    fn test_password_serializes_as_string() {
+        let password = Password::new("hunter22");
+        assert_eq!(serde_json::to_string(&password).unwrap(), "\"hunter22\"");
+    }
+}
@@ -0,0 +1,81 @@
+// shared/src/presence.rs
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// How available a logged-in user is for chatting
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    #[default]
+    Online,
+    Away,
+    DoNotDisturb,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Online => "online",
+            Status::Away => "away",
+            Status::DoNotDisturb => "do-not-disturb",
+        })
+    }
+}
+
+impl FromStr for Status {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "online" => Ok(Status::Online),
+            "away" => Ok(Status::Away),
+            "dnd" | "do-not-disturb" => Ok(Status::DoNotDisturb),
+            _ => Err(format!(
+                "Unknown status '{}', expected online, away or dnd",
+                s
+            )),
+        }
+    }
+}
+
+/// A change in a user's presence, broadcast in `MessageType::Presence`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresenceEvent {
+    /// The user logged in and was not logged in elsewhere.
+    LoggedIn,
+    /// The user's last session logged out or closed its connection.
+    LoggedOut,
+    /// The user's last session stopped answering heartbeats.
+    TimedOut,
+    /// The user set a new status.
+    StatusChanged(Status),
+}
+
+/// A logged-in user, as listed in `MessageType::Users`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OnlineUser {
+    pub username: String,
+    pub status: Status,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_round_trip() {
+        for status in [Status::Online, Status::Away, Status::DoNotDisturb] {
+            assert_eq!(status.to_string().parse::<Status>(), Ok(status));
+        }
+        assert_eq!("DND".parse::<Status>(), Ok(Status::DoNotDisturb));
+        assert!("busy".parse::<Status>().is_err());
+    }
+
+    #[test]
+    fn test_status_serializes_like_display() {
+        let json = serde_json::to_string(&Status::DoNotDisturb).unwrap();
+        assert_eq!(json, "\"do-not-disturb\"");
+    }
+}
@@ -0,0 +1,36 @@
+// shared/src/room.rs
+
+/// Maximum length of a room name in bytes.
+pub const MAX_ROOM_NAME_LEN: usize = 64;
+
+/// Checks that a string is a valid room name
+///
+/// Room names are 1 to `MAX_ROOM_NAME_LEN` characters long and consist of
+/// ASCII letters, digits, `-` and `_`, so they can be shown and typed
+/// without quoting.
+///
+/// # Arguments
+///
+/// * `name` - The room name to check.
+pub fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ROOM_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_room_name() {
+        assert!(is_valid_room_name("general"));
+        assert!(is_valid_room_name("rust-beginners_2"));
+        assert!(!is_valid_room_name(""));
+        assert!(!is_valid_room_name("two words"));
+        assert!(!is_valid_room_name("../etc"));
+        assert!(!is_valid_room_name(&"a".repeat(MAX_ROOM_NAME_LEN + 1)));
+    }
+}
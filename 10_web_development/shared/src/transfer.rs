@@ -0,0 +1,186 @@
+// shared/src/transfer.rs
+
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use uuid::Uuid;
+
+/// Maximum number of payload bytes carried by one `MessageType::FileChunk`.
+pub const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Computes the hex-encoded SHA-256 digest of a file
+///
+/// The file is read in chunks, so this works for files of any size. It
+/// does blocking I/O; async callers should run it with `spawn_blocking`.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file to hash.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; FILE_CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Computes the hex-encoded SHA-256 digest of a buffer
+///
+/// # Arguments
+///
+/// * `data` - The bytes to hash.
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Checks that a string looks like a hex-encoded SHA-256 digest.
+///
+/// Receivers use digests to name partial files, so anything else must be
+/// rejected before it gets near the filesystem.
+pub fn is_sha256_hex(digest: &str) -> bool {
+    digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Turns a file name chosen by a peer into a plain file name
+///
+/// Directory parts are dropped, control characters removed, and leading
+/// dots and surrounding whitespace trimmed, so the result never refers to
+/// another directory or a hidden file. The name is cut to `max_len` bytes,
+/// and `file` is returned when nothing is left.
+///
+/// # Arguments
+///
+/// * `name` - The name as received.
+/// * `max_len` - The maximum length of the result in bytes.
+pub fn sanitize_file_name(name: &str, max_len: usize) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim().trim_start_matches('.').trim_start();
+    let mut end = name.len().min(max_len);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let name = &name[..end];
+    if name.is_empty() {
+        "file".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Names an image received from another user
+///
+/// The name is made of the sender and the ID of the message the image came
+/// in. The sender is a username chosen by a peer, so it is sanitized like a
+/// file name before it becomes part of a path.
+///
+/// # Arguments
+///
+/// * `sender` - The username of the sender.
+/// * `id` - The ID of the message carrying the image.
+/// * `data` - The encoded image.
+pub fn received_image_name(sender: &str, id: Uuid, data: &[u8]) -> String {
+    format!(
+        "{}_{}.{}",
+        sanitize_file_name(sender, 64),
+        id,
+        image_extension(data)
+    )
+}
+
+/// Returns the file extension matching the format of an encoded image
+///
+/// The server may store and relay images as PNG, JPEG, GIF or WebP;
+/// anything unrecognized is taken to be PNG.
+///
+/// # Arguments
+///
+/// * `data` - The encoded image.
+pub fn image_extension(data: &[u8]) -> &'static str {
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        "jpg"
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        "gif"
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        "webp"
+    } else {
+        "png"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_file() {
+        let path = std::env::temp_dir().join(format!("sha256_test_{}", std::process::id()));
+        std::fs::write(&path, b"abc").unwrap();
+        let digest = sha256_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_is_sha256_hex() {
+        assert!(is_sha256_hex(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        ));
+        assert!(!is_sha256_hex("../../etc/passwd"));
+        assert!(!is_sha256_hex("ba7816bf"));
+    }
+
+    #[test]
+    fn test_received_image_name() {
+        let id = Uuid::new_v4();
+        assert_eq!(
+            received_image_name("alice", id, b"GIF89a"),
+            format!("alice_{}.gif", id)
+        );
+        let name = received_image_name("../../.ssh/x", id, b"");
+        assert_eq!(name, format!("x_{}.png", id));
+        assert!(!name.contains('/'));
+        assert!(!received_image_name("..\\..\\evil", id, b"").contains('\\'));
+    }
+
+    #[test]
+    fn test_sanitize_file_name() {
+        assert_eq!(sanitize_file_name("report.pdf", 255), "report.pdf");
+        assert_eq!(sanitize_file_name("../../etc/passwd", 255), "passwd");
+        assert_eq!(
+            sanitize_file_name("C:\\Users\\me\\notes.txt", 255),
+            "notes.txt"
+        );
+        assert_eq!(sanitize_file_name(" .bashrc\n", 255), "bashrc");
+        assert_eq!(sanitize_file_name("..", 255), "file");
+        assert_eq!(sanitize_file_name("dir/", 255), "file");
+        assert_eq!(sanitize_file_name("žluťoučký", 5), "žlu");
+    }
+
+    #[test]
+    fn test_image_extension() {
+        assert_eq!(image_extension(b"\x89PNG\r\n\x1a\n"), "png");
+        assert_eq!(image_extension(&[0xff, 0xd8, 0xff, 0xe0]), "jpg");
+        assert_eq!(image_extension(b"GIF89a"), "gif");
+        assert_eq!(image_extension(b"RIFF\x10\x00\x00\x00WEBPVP8L"), "webp");
+        assert_eq!(image_extension(b"RIFF\x10\x00\x00\x00WAVE"), "png");
+    }
+}
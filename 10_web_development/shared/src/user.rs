@@ -0,0 +1,40 @@
+// shared/src/user.rs
+
+/// Maximum length of a username in bytes.
+pub const MAX_USERNAME_LEN: usize = 32;
+
+/// Checks that a string is a valid username for a new user
+///
+/// Usernames are 1 to `MAX_USERNAME_LEN` characters long and consist of
+/// ASCII letters, digits, `-` and `_`. They are shown to other users and
+/// end up in file names, so they cannot contain separators, dots or
+/// control characters.
+///
+/// # Arguments
+///
+/// * `username` - The username to check.
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= MAX_USERNAME_LEN
+        && username
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_username() {
+        assert!(is_valid_username("alice"));
+        assert!(is_valid_username("bob_the-builder2"));
+        assert!(!is_valid_username(""));
+        assert!(!is_valid_username("../../.ssh/x"));
+        assert!(!is_valid_username("a/b"));
+        assert!(!is_valid_username(".."));
+        assert!(!is_valid_username("new\nline"));
+        assert!(!is_valid_username("two words"));
+        assert!(!is_valid_username(&"a".repeat(MAX_USERNAME_LEN + 1)));
+    }
+}
@@ -2,6 +2,7 @@ mod client;
 mod message;
 mod server;
 
+use message::DEFAULT_MAX_FRAME_SIZE;
 use std::env;
 
 fn main() {
@@ -19,7 +20,17 @@ fn main() {
     };
 
     if mode == "server" {
-        server::listen_and_accept(address).unwrap();
+        let max_frame_size = match env::var("MAX_FRAME_SIZE") {
+            Ok(value) => match value.parse() {
+                Ok(size) => size,
+                Err(e) => {
+                    eprintln!("Invalid MAX_FRAME_SIZE '{}': {}", value, e);
+                    return;
+                }
+            },
+            Err(_) => DEFAULT_MAX_FRAME_SIZE,
+        };
+        server::listen_and_accept(address, max_frame_size).unwrap();
     } else if mode == "client" {
         client::start_client(address).unwrap();
     } else {
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum MessageType {
@@ -8,6 +9,34 @@ pub enum MessageType {
     Quit, // Command to quit the client
 }
 
+/// Largest message a server accepts unless `MAX_FRAME_SIZE` says otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Message of {} bytes exceeds the limit of {} bytes",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+pub fn check_frame_size(size: usize, limit: usize) -> Result<(), FrameTooLarge> {
+    if size > limit {
+        return Err(FrameTooLarge { size, limit });
+    }
+    Ok(())
+}
+
 pub fn serialize_message(message: &MessageType) -> Vec<u8> {
     serde_cbor::to_vec(&message).unwrap()
 }
@@ -1,4 +1,4 @@
-use crate::message::{deserialize_message, MessageType};
+use crate::message::{check_frame_size, deserialize_message, MessageType};
 use chrono::Utc;
 use std::collections::HashMap;
 use std::fs;
@@ -8,10 +8,14 @@ use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-pub fn handle_client(mut stream: TcpStream) -> Result<MessageType, Box<dyn std::error::Error>> {
+pub fn handle_client(
+    mut stream: TcpStream,
+    max_frame_size: usize,
+) -> Result<MessageType, Box<dyn std::error::Error>> {
     let mut len_bytes = [0u8; 4];
     stream.read_exact(&mut len_bytes)?;
     let len = u32::from_be_bytes(len_bytes) as usize;
+    check_frame_size(len, max_frame_size)?;
 
     let mut buffer = vec![0u8; len];
     stream.read_exact(&mut buffer)?;
@@ -51,7 +55,7 @@ pub fn handle_message(
     Ok(false)
 }
 
-pub fn listen_and_accept(address: &str) -> std::io::Result<()> {
+pub fn listen_and_accept(address: &str, max_frame_size: usize) -> std::io::Result<()> {
     let listener = TcpListener::bind(address)?;
     println!("Server running on {}", address);
 
@@ -68,7 +72,7 @@ pub fn listen_and_accept(address: &str) -> std::io::Result<()> {
         let clients = Arc::clone(&clients);
         thread::spawn(move || {
             loop {
-                match handle_client(stream.try_clone().unwrap()) {
+                match handle_client(stream.try_clone().unwrap(), max_frame_size) {
                     Ok(message) => {
                         match handle_message(addr, message) {
                             Ok(true) => {
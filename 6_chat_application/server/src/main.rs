@@ -1,5 +1,5 @@
 use chrono::Utc;
-use shared::{deserialize_message, MessageType};
+use shared::{check_frame_size, deserialize_message, MessageType, DEFAULT_MAX_FRAME_SIZE};
 use std::collections::HashMap;
 use std::env;
 use std::fs;
@@ -12,10 +12,14 @@ use tracing::{error, info};
 use tracing_subscriber;
 
 // Function to handle incoming client connections
-pub fn handle_client(mut stream: TcpStream) -> Result<MessageType, Box<dyn std::error::Error>> {
+pub fn handle_client(
+    mut stream: TcpStream,
+    max_frame_size: usize,
+) -> Result<MessageType, Box<dyn std::error::Error>> {
     let mut len_bytes = [0u8; 4];
     stream.read_exact(&mut len_bytes)?;
     let len = u32::from_be_bytes(len_bytes) as usize;
+    check_frame_size(len, max_frame_size)?;
 
     let mut buffer = vec![0u8; len];
     stream.read_exact(&mut buffer)?;
@@ -57,7 +61,7 @@ pub fn handle_message(
 }
 
 // Function to start the server and listen for incoming connections
-pub fn listen_and_accept(address: &str) -> std::io::Result<()> {
+pub fn listen_and_accept(address: &str, max_frame_size: usize) -> std::io::Result<()> {
     let listener = TcpListener::bind(address)?;
     info!("Server running on {}", address);
 
@@ -74,7 +78,7 @@ pub fn listen_and_accept(address: &str) -> std::io::Result<()> {
         let clients = Arc::clone(&clients);
         thread::spawn(move || {
             loop {
-                match handle_client(stream.try_clone().unwrap()) {
+                match handle_client(stream.try_clone().unwrap(), max_frame_size) {
                     Ok(message) => {
                         match handle_message(addr, message) {
                             Ok(true) => {
@@ -130,7 +134,18 @@ fn main() {
         &args[1]
     };
 
-    if let Err(e) = listen_and_accept(address) {
+    let max_frame_size = match env::var("MAX_FRAME_SIZE") {
+        Ok(value) => match value.parse() {
+            Ok(size) => size,
+            Err(e) => {
+                error!("Invalid MAX_FRAME_SIZE '{}': {}", value, e);
+                return;
+            }
+        },
+        Err(_) => DEFAULT_MAX_FRAME_SIZE,
+    };
+
+    if let Err(e) = listen_and_accept(address, max_frame_size) {
         error!("Error: {}", e);
     }
 }
@@ -2,6 +2,7 @@
 
 use serde::{Deserialize, Serialize};
 use serde_cbor;
+use std::fmt;
 use tracing::instrument;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -12,6 +13,36 @@ pub enum MessageType {
     Quit,
 }
 
+/// Largest message a server accepts unless `MAX_FRAME_SIZE` says otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+// Error for a length prefix announcing a message larger than the limit
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Message of {} bytes exceeds the limit of {} bytes",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+// Function to check the length prefix of a message before reading it
+pub fn check_frame_size(size: usize, limit: usize) -> Result<(), FrameTooLarge> {
+    if size > limit {
+        return Err(FrameTooLarge { size, limit });
+    }
+    Ok(())
+}
+
 // Function to serialize a message
 #[instrument]
 pub fn serialize_message(message: &MessageType) -> Vec<u8> {
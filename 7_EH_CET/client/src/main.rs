@@ -1,4 +1,6 @@
-use shared::{deserialize_message, serialize_message, MessageType};
+use shared::{
+    check_frame_size, deserialize_message, serialize_message, MessageType, DEFAULT_MAX_FRAME_SIZE,
+};
 use std::env;
 use std::fs::File;
 use std::io::{self, Read, Write};
@@ -29,6 +31,7 @@ fn handle_server_response(mut reader: TcpStream) -> Result<()> {
         let mut len_bytes = [0u8;4];
         reader.read_exact(&mut len_bytes).context("Failed to read response length")?;
         let len = u32::from_be_bytes(len_bytes) as usize;
+        check_frame_size(len, DEFAULT_MAX_FRAME_SIZE)?;
         
         let mut buffer = vec![0u8; len];
         reader.read_exact(&mut buffer).context("Failed to read response")?;
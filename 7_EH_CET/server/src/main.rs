@@ -1,5 +1,7 @@
 use chrono::Utc;
-use shared::{deserialize_message, serialize_message, MessageType};
+use shared::{
+    check_frame_size, deserialize_message, serialize_message, MessageType, DEFAULT_MAX_FRAME_SIZE,
+};
 use std::collections::HashMap;
 use std::env;
 use std::fs::{self, File};
@@ -14,10 +16,11 @@ use anyhow::{Context, Result};
 use image::ImageFormat;
 
 // Function to handle incoming client connections
-pub fn handle_client(mut stream: TcpStream) -> Result<MessageType> {
+pub fn handle_client(mut stream: TcpStream, max_frame_size: usize) -> Result<MessageType> {
     let mut len_bytes = [0u8; 4];
     stream.read_exact(&mut len_bytes).context("Failed to read message length")?;
     let len = u32::from_be_bytes(len_bytes) as usize;
+    check_frame_size(len, max_frame_size)?;
 
     let mut buffer = vec![0u8; len];
     stream.read_exact(&mut buffer).context("Failed to read message")?;
@@ -76,7 +79,7 @@ fn report_error(mut stream: TcpStream, error_message: &str) -> Result<()> {
 
 
 // Function to start the server and listen for incoming connections
-pub fn listen_and_accept(address: &str) -> std::io::Result<()> {
+pub fn listen_and_accept(address: &str, max_frame_size: usize) -> std::io::Result<()> {
     let listener = TcpListener::bind(address)?;
     info!("Server running on {}", address);
 
@@ -93,7 +96,7 @@ pub fn listen_and_accept(address: &str) -> std::io::Result<()> {
         let clients = Arc::clone(&clients);
         thread::spawn(move || {
             loop {
-                match handle_client(stream.try_clone().unwrap()) {
+                match handle_client(stream.try_clone().unwrap(), max_frame_size) {
                     Ok(message) => {
                         match handle_message(addr, message) {
                             Ok(true) => {
@@ -155,7 +158,18 @@ fn main() {
         &args[1]
     };
 
-    if let Err(e) = listen_and_accept(address) {
+    let max_frame_size = match env::var("MAX_FRAME_SIZE") {
+        Ok(value) => match value.parse() {
+            Ok(size) => size,
+            Err(e) => {
+                error!("Invalid MAX_FRAME_SIZE '{}': {}", value, e);
+                return;
+            }
+        },
+        Err(_) => DEFAULT_MAX_FRAME_SIZE,
+    };
+
+    if let Err(e) = listen_and_accept(address, max_frame_size) {
         error!("Error: {}", e);
     }
 }
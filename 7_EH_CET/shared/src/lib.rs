@@ -14,6 +14,17 @@ pub enum MessageType {
     Error(String),
 }
 
+/// Largest message a server accepts unless `MAX_FRAME_SIZE` says otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+// Function to check the length prefix of a message before reading it
+pub fn check_frame_size(size: usize, limit: usize) -> Result<(), FrameError> {
+    if size > limit {
+        return Err(FrameError::TooLarge { size, limit });
+    }
+    Ok(())
+}
+
 // Function to serialize a message
 #[instrument]
 pub fn serialize_message(message: &MessageType) -> Result<Vec<u8>, SerializationError> {
@@ -37,3 +48,9 @@ pub enum DeserializationError {
  #[error("Deserialization failed: {0}")]
  Cbor(#[from] serde_cbor::Error),
 }
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+ #[error("Message of {size} bytes exceeds the limit of {limit} bytes")]
+ TooLarge { size: usize, limit: usize },
+}